@@ -0,0 +1,104 @@
+use std::{
+    panic::{self, AssertUnwindSafe},
+    sync::{
+        Arc, Mutex,
+        mpsc::{self, Receiver, Sender},
+    },
+    thread,
+};
+
+use crate::engine::{Mesh, Skybox, material::Material};
+
+// Enough to decode a few textures at once without starving the render thread
+const WORKER_COUNT: usize = 4;
+
+/// Assets decoded by a job, by the id of the handle they were reserved for.
+#[derive(Default)]
+pub struct Decoded {
+    pub meshes: Vec<(usize, Mesh)>,
+    pub materials: Vec<(usize, Material)>,
+    pub skyboxes: Vec<(usize, Skybox)>,
+}
+
+/// Ids of the assets a job was spawned for, by type.
+#[derive(Default)]
+pub struct Requested {
+    pub meshes: Vec<usize>,
+    pub materials: Vec<usize>,
+    pub skyboxes: Vec<usize>,
+}
+
+/// A job's results, `None` if it panicked, along with what it was spawned for.
+pub struct Finished {
+    pub requested: Requested,
+    pub decoded: Option<Decoded>,
+}
+
+struct Job {
+    requested: Requested,
+    decode: Box<dyn FnOnce() -> Decoded + Send>,
+}
+
+/// A pool of worker threads reading and decoding assets from disk, so the
+/// render thread never waits on PNG, EXR or glTF decoding.
+pub struct AssetLoader {
+    jobs: Sender<Job>,
+    finished: Receiver<Finished>,
+}
+
+impl AssetLoader {
+    pub fn new() -> Self {
+        let (jobs, job_receiver) = mpsc::channel::<Job>();
+        let (finished_sender, finished) = mpsc::channel();
+        let job_receiver = Arc::new(Mutex::new(job_receiver));
+
+        for index in 0..WORKER_COUNT {
+            let job_receiver = job_receiver.clone();
+            let finished_sender = finished_sender.clone();
+            thread::Builder::new()
+                .name(format!("asset-loader-{}", index))
+                .spawn(move || {
+                    loop {
+                        // The lock is released before the job runs
+                        let Ok(job) = job_receiver.lock().unwrap().recv() else {
+                            break;
+                        };
+                        // A job panicking on a broken file only fails its own
+                        // assets, the worker keeps going
+                        let decoded = panic::catch_unwind(AssertUnwindSafe(job.decode)).ok();
+                        let finished = Finished {
+                            requested: job.requested,
+                            decoded,
+                        };
+                        if finished_sender.send(finished).is_err() {
+                            break;
+                        }
+                    }
+                })
+                .unwrap();
+        }
+
+        Self { jobs, finished }
+    }
+
+    /// Runs `job`, which decodes the `requested` assets, on the next free
+    /// worker thread.
+    pub fn spawn(&self, requested: Requested, job: impl FnOnce() -> Decoded + Send + 'static) {
+        self.jobs
+            .send(Job {
+                requested,
+                decode: Box::new(job),
+            })
+            .expect("Asset loader workers stopped");
+    }
+
+    /// Jobs finished since the last call, without blocking.
+    pub fn finished(&self) -> impl Iterator<Item = Finished> + '_ {
+        self.finished.try_iter()
+    }
+
+    /// Blocks until the next job finishes.
+    pub fn wait(&self) -> Finished {
+        self.finished.recv().unwrap()
+    }
+}
@@ -0,0 +1,581 @@
+use std::{
+    collections::HashMap,
+    marker::PhantomData,
+    sync::{Arc, RwLock, Weak},
+};
+
+use crate::engine::{
+    BlendMode, Mesh, OpacitySource, Skybox,
+    asset_loader::{AssetLoader, Decoded, Finished, Requested},
+    gltf_material,
+    gltf_scene::{self, SceneNode},
+    material::Material,
+};
+
+/// A reference to an asset of an `AssetServer`. The asset stays loaded for as
+/// long as any handle to it is alive and is freed, GPU resources included, by
+/// the next `AssetServer::free_unused` after the last one drops.
+pub struct Handle<T> {
+    id: usize,
+    // Its strong count is the number of live handles
+    count: Arc<()>,
+    marker: PhantomData<fn() -> T>,
+}
+
+impl<T> Handle<T> {
+    /// Identifies the asset in its `Assets` until it's freed. Ids aren't reused.
+    pub fn id(&self) -> usize {
+        self.id
+    }
+}
+
+impl<T> Clone for Handle<T> {
+    fn clone(&self) -> Self {
+        Self {
+            id: self.id,
+            count: self.count.clone(),
+            marker: PhantomData,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LoadState {
+    /// Being read and decoded on a worker thread
+    Loading,
+    /// Decoded, waiting for the renderer to upload it
+    Pending,
+    /// Uploaded to the GPU and ready to be drawn
+    Loaded,
+    /// Its job panicked or didn't decode it, placeholders are drawn instead
+    Failed,
+}
+
+struct AssetEntry<T> {
+    path: String,
+    // Only `None` while loading
+    asset: Option<Arc<RwLock<T>>>,
+    state: LoadState,
+    handles: Weak<()>,
+}
+
+/// Every asset of one type, by the id of their handles. Each path is only ever
+/// loaded once while it has handles.
+pub struct Assets<T> {
+    entries: HashMap<usize, AssetEntry<T>>,
+    ids: HashMap<String, usize>,
+    next_id: usize,
+}
+
+impl<T> Assets<T> {
+    fn new() -> Self {
+        Self {
+            entries: HashMap::new(),
+            ids: HashMap::new(),
+            next_id: 0,
+        }
+    }
+
+    /// A handle to the asset of `path`, and whether it's new, in which case it
+    /// stays loading until `finish` gets its data.
+    pub fn reserve(&mut self, path: &str) -> (Handle<T>, bool) {
+        if let Some(handle) = self.handle(path) {
+            return (handle, false);
+        }
+
+        let id = self.next_id;
+        self.next_id += 1;
+        let count = Arc::new(());
+        self.entries.insert(
+            id,
+            AssetEntry {
+                path: path.to_string(),
+                asset: None,
+                state: LoadState::Loading,
+                handles: Arc::downgrade(&count),
+            },
+        );
+        self.ids.insert(path.to_string(), id);
+
+        let handle = Handle {
+            id,
+            count,
+            marker: PhantomData,
+        };
+        (handle, true)
+    }
+
+    /// A handle to the asset of `path`, calling `load` right away on this
+    /// thread if it isn't loaded yet.
+    pub fn load_with(&mut self, path: &str, load: impl FnOnce() -> T) -> Handle<T> {
+        let (handle, is_new) = self.reserve(path);
+        if is_new {
+            self.finish(handle.id, load());
+        }
+        handle
+    }
+
+    /// A handle to the asset of `path`, if it's loaded or loading.
+    pub fn handle(&mut self, path: &str) -> Option<Handle<T>> {
+        let id = *self.ids.get(path)?;
+        let entry = self.entries.get_mut(&id)?;
+        // Its last handle may have dropped without it being freed yet
+        let count = entry.handles.upgrade().unwrap_or_else(|| {
+            let count = Arc::new(());
+            entry.handles = Arc::downgrade(&count);
+            count
+        });
+
+        Some(Handle {
+            id,
+            count,
+            marker: PhantomData,
+        })
+    }
+
+    /// Marks a loading asset as failed, for when its job finished without it.
+    /// Ignored if the asset was decoded or freed in the meantime.
+    pub fn fail(&mut self, id: usize) {
+        if let Some(entry) = self.entries.get_mut(&id) {
+            if entry.state == LoadState::Loading {
+                println!("[assets] Failed to load {}", entry.path);
+                entry.state = LoadState::Failed;
+            }
+        }
+    }
+
+    /// Stores the decoded data of a loading asset. Ignored if the asset was
+    /// freed in the meantime.
+    pub fn finish(&mut self, id: usize, asset: T) {
+        if let Some(entry) = self.entries.get_mut(&id) {
+            if entry.state == LoadState::Loading {
+                entry.asset = Some(Arc::new(RwLock::new(asset)));
+                entry.state = LoadState::Pending;
+            }
+        }
+    }
+
+    /// The asset of `id` if it's been uploaded, `None` while it's loading or
+    /// if it failed to.
+    pub fn get(&self, id: usize) -> Option<&Arc<RwLock<T>>> {
+        self.entries
+            .get(&id)
+            .filter(|entry| entry.state == LoadState::Loaded)
+            .and_then(|entry| entry.asset.as_ref())
+    }
+
+    /// Every uploaded asset.
+    pub fn iter(&self) -> impl Iterator<Item = (usize, &Arc<RwLock<T>>)> {
+        self.entries
+            .iter()
+            .filter(|(_, entry)| entry.state == LoadState::Loaded)
+            .filter_map(|(id, entry)| Some((*id, entry.asset.as_ref()?)))
+    }
+
+    #[allow(dead_code)]
+    pub fn load_state(&self, handle: &Handle<T>) -> LoadState {
+        self.entries[&handle.id].state
+    }
+
+    /// Whether any asset is still being decoded.
+    pub fn is_loading(&self) -> bool {
+        self.entries
+            .values()
+            .any(|entry| entry.state == LoadState::Loading)
+    }
+
+    /// Marks every pending asset as loaded and returns them, for the caller to
+    /// upload.
+    pub fn drain_pending(&mut self) -> Vec<Arc<RwLock<T>>> {
+        self.entries
+            .values_mut()
+            .filter(|entry| entry.state == LoadState::Pending)
+            .filter_map(|entry| {
+                entry.state = LoadState::Loaded;
+                entry.asset.clone()
+            })
+            .collect()
+    }
+
+    /// Drops every asset without handles and returns their ids.
+    fn free_unused(&mut self) -> Vec<usize> {
+        let unused: Vec<usize> = self
+            .entries
+            .iter()
+            .filter(|(_, entry)| entry.handles.strong_count() == 0)
+            .map(|(id, _)| *id)
+            .collect();
+
+        for id in unused.iter() {
+            let entry = self.entries.remove(id).unwrap();
+            self.ids.remove(&entry.path);
+        }
+        unused
+    }
+}
+
+/// Loads meshes, materials and skyboxes by path on worker threads, handing
+/// out a `Handle` to each right away and loading every path only once.
+/// Decoded assets are collected by `update` and then uploaded by the renderer.
+pub struct AssetServer {
+    pub meshes: Assets<Mesh>,
+    pub materials: Assets<Material>,
+    pub skyboxes: Assets<Skybox>,
+    loader: AssetLoader,
+}
+
+impl AssetServer {
+    pub fn new() -> Self {
+        Self {
+            meshes: Assets::new(),
+            materials: Assets::new(),
+            skyboxes: Assets::new(),
+            loader: AssetLoader::new(),
+        }
+    }
+
+    /// Every mesh of `assets/meshes/{path}.glb` merged into one, see `Mesh::new`.
+    pub fn load_mesh(&mut self, path: &str) -> Handle<Mesh> {
+        let file_path = path.to_string();
+        self.load_mesh_with(path, move || Mesh::new(&file_path))
+    }
+
+    /// Like `load_mesh`, along with the file's materials, each submesh
+    /// defaulting to the material it was exported with.
+    pub fn load_gltf_model(&mut self, path: &str) -> Handle<Mesh> {
+        let key = format!("{}#model", path);
+        if let Some(handle) = self.meshes.handle(&key) {
+            return handle;
+        }
+
+        let materials = self.load_gltf_materials(path);
+        let file_path = path.to_string();
+        self.load_mesh_with(&key, move || {
+            Mesh::new(&file_path).with_materials(&materials)
+        })
+    }
+
+    /// `assets/textures/{name}_*.png`, see `Material::new`.
+    pub fn load_material(&mut self, name: &str) -> Handle<Material> {
+        let file_name = name.to_string();
+        self.load_material_with(name, move || Material::new(&file_name))
+    }
+
+    /// A material drawn in the transparent pass with `blend_mode`.
+    pub fn load_transparent_material(
+        &mut self,
+        name: &str,
+        blend_mode: BlendMode,
+        opacity: f32,
+    ) -> Handle<Material> {
+        let key = format!("{}?blend={:?}&opacity={}", name, blend_mode, opacity);
+        let file_name = name.to_string();
+        self.load_material_with(&key, move || {
+            Material::new(&file_name).with_blend_mode(blend_mode, opacity)
+        })
+    }
+
+    /// A cutout material whose `{name}_mask.png` red channel is its opacity,
+    /// discarding pixels below `cutoff`.
+    #[allow(dead_code)]
+    pub fn load_cutout_material(&mut self, name: &str, cutoff: f32) -> Handle<Material> {
+        let key = format!("{}?cutoff={}", name, cutoff);
+        let file_name = name.to_string();
+        self.load_material_with(&key, move || {
+            Material::new(&file_name).with_cutout(OpacitySource::MaskTexture, cutoff)
+        })
+    }
+
+    /// A material that glows with `factor`, see `Material::with_emissive`.
+    pub fn load_emissive_material(&mut self, name: &str, factor: [f32; 3]) -> Handle<Material> {
+        let key = format!("{}?emissive={:?}", name, factor);
+        let file_name = name.to_string();
+        self.load_material_with(&key, move || {
+            Material::new(&file_name).with_emissive(factor)
+        })
+    }
+
+    /// Every material of a glTF file, in the file's order. Only the file's
+    /// JSON is read on this thread. None if the file can't be read, submeshes
+    /// then fall back to the entity's or the default material.
+    pub fn load_gltf_materials(&mut self, path: &str) -> Vec<Handle<Material>> {
+        let count = match gltf_material::material_count(path) {
+            Ok(count) => count,
+            Err(e) => {
+                println!("[assets] Failed to read {}: {}", path, e);
+                return Vec::new();
+            }
+        };
+        let (handles, new): (Vec<_>, Vec<_>) = (0..count)
+            .map(|index| self.materials.reserve(&format!("{}#{}", path, index)))
+            .unzip();
+
+        // Materials that are already loaded are decoded again but dropped
+        if new.contains(&true) {
+            let ids: Vec<usize> = handles.iter().map(Handle::id).collect();
+            let requested = Requested {
+                materials: ids.clone(),
+                ..Default::default()
+            };
+            let file_path = path.to_string();
+            self.loader.spawn(requested, move || Decoded {
+                materials: ids
+                    .into_iter()
+                    .zip(gltf_material::load_materials(&file_path))
+                    .collect(),
+                ..Default::default()
+            });
+        }
+
+        handles
+    }
+
+    /// The node tree of a glTF file with its meshes, which are indexed by the
+    /// nodes, and its materials. Only the file's JSON is read on this thread.
+    /// A file that can't be read gives a single failed mesh, see
+    /// `gltf_scene::failed_layout`.
+    pub fn load_gltf_scene(&mut self, path: &str) -> (Vec<SceneNode>, Vec<Handle<Mesh>>) {
+        let (layout, readable) = match gltf_scene::load_layout(path) {
+            Ok(layout) => (layout, true),
+            Err(e) => {
+                println!("[assets] Failed to read {}: {}", path, e);
+                (gltf_scene::failed_layout(), false)
+            }
+        };
+
+        let (materials, new_materials): (Vec<_>, Vec<_>) = (0..layout.material_count)
+            .map(|index| self.materials.reserve(&format!("{}#{}", path, index)))
+            .unzip();
+        let (meshes, new_meshes): (Vec<_>, Vec<_>) = (0..layout.mesh_count)
+            .map(|index| self.meshes.reserve(&format!("{}#mesh{}", path, index)))
+            .unzip();
+
+        if !readable {
+            for mesh in meshes.iter() {
+                self.meshes.fail(mesh.id());
+            }
+            return (layout.roots, meshes);
+        }
+
+        if new_materials.contains(&true) || new_meshes.contains(&true) {
+            let material_ids: Vec<usize> = materials.iter().map(Handle::id).collect();
+            let mesh_ids: Vec<usize> = meshes.iter().map(Handle::id).collect();
+            let requested = Requested {
+                meshes: mesh_ids.clone(),
+                materials: material_ids.clone(),
+                ..Default::default()
+            };
+            let file_path = path.to_string();
+            self.loader.spawn(requested, move || {
+                let (decoded_meshes, decoded_materials) = gltf_scene::load_assets(&file_path);
+                Decoded {
+                    meshes: mesh_ids
+                        .into_iter()
+                        .zip(decoded_meshes)
+                        .map(|(id, mesh)| (id, mesh.with_materials(&materials)))
+                        .collect(),
+                    materials: material_ids.into_iter().zip(decoded_materials).collect(),
+                    ..Default::default()
+                }
+            });
+        }
+
+        (layout.roots, meshes)
+    }
+
+    /// An equirectangular HDR environment, see `Skybox::new`.
+    pub fn load_skybox(&mut self, path: &str) -> Handle<Skybox> {
+        let (handle, is_new) = self.skyboxes.reserve(path);
+        if is_new {
+            let id = handle.id();
+            let requested = Requested {
+                skyboxes: vec![id],
+                ..Default::default()
+            };
+            let file_path = path.to_string();
+            self.loader.spawn(requested, move || Decoded {
+                skyboxes: vec![(id, Skybox::new(&file_path))],
+                ..Default::default()
+            });
+        }
+        handle
+    }
+
+    /// Collects the assets decoded since the last call, without blocking.
+    pub fn update(&mut self) {
+        let finished: Vec<Finished> = self.loader.finished().collect();
+        for finished in finished {
+            self.store(finished);
+        }
+    }
+
+    /// Blocks until every asset requested so far is decoded.
+    pub fn wait_until_decoded(&mut self) {
+        while self.meshes.is_loading() || self.materials.is_loading() || self.skyboxes.is_loading()
+        {
+            let finished = self.loader.wait();
+            self.store(finished);
+        }
+    }
+
+    /// Frees every asset whose last handle dropped. Meshes go first since
+    /// their submeshes hold handles to their default materials. Returns the
+    /// ids of the freed meshes, for the renderer to drop its copies of them.
+    pub fn free_unused(&mut self) -> Vec<usize> {
+        let meshes = self.meshes.free_unused();
+        self.materials.free_unused();
+        self.skyboxes.free_unused();
+        meshes
+    }
+
+    fn load_mesh_with(
+        &mut self,
+        key: &str,
+        load: impl FnOnce() -> Mesh + Send + 'static,
+    ) -> Handle<Mesh> {
+        let (handle, is_new) = self.meshes.reserve(key);
+        if is_new {
+            let id = handle.id();
+            let requested = Requested {
+                meshes: vec![id],
+                ..Default::default()
+            };
+            self.loader.spawn(requested, move || Decoded {
+                meshes: vec![(id, load())],
+                ..Default::default()
+            });
+        }
+        handle
+    }
+
+    fn load_material_with(
+        &mut self,
+        key: &str,
+        load: impl FnOnce() -> Material + Send + 'static,
+    ) -> Handle<Material> {
+        let (handle, is_new) = self.materials.reserve(key);
+        if is_new {
+            let id = handle.id();
+            let requested = Requested {
+                materials: vec![id],
+                ..Default::default()
+            };
+            self.loader.spawn(requested, move || Decoded {
+                materials: vec![(id, load())],
+                ..Default::default()
+            });
+        }
+        handle
+    }
+
+    fn store(&mut self, finished: Finished) {
+        if let Some(decoded) = finished.decoded {
+            for (id, mesh) in decoded.meshes {
+                self.meshes.finish(id, mesh);
+            }
+            for (id, material) in decoded.materials {
+                self.materials.finish(id, material);
+            }
+            for (id, skybox) in decoded.skyboxes {
+                self.skyboxes.finish(id, skybox);
+            }
+        }
+
+        // Whatever the job didn't decode won't ever be
+        let requested = finished.requested;
+        for id in requested.meshes {
+            self.meshes.fail(id);
+        }
+        for id in requested.materials {
+            self.materials.fail(id);
+        }
+        for id in requested.skyboxes {
+            self.skyboxes.fail(id);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reserve_loads_each_path_once() {
+        let mut assets = Assets::<u32>::new();
+        let (first, first_is_new) = assets.reserve("a");
+        let (second, second_is_new) = assets.reserve("a");
+        let (other, _) = assets.reserve("b");
+
+        assert!(first_is_new);
+        assert!(!second_is_new);
+        assert_eq!(first.id(), second.id());
+        assert_ne!(first.id(), other.id());
+    }
+
+    #[test]
+    fn handle_revives_an_asset_that_is_not_freed_yet() {
+        let mut assets = Assets::new();
+        // Its only handle drops right away
+        let id = assets.load_with("a", || 1).id();
+
+        let handle = assets.handle("a").unwrap();
+        assert_eq!(handle.id(), id);
+        assert!(assets.free_unused().is_empty());
+
+        drop(handle);
+        assert_eq!(assets.free_unused(), [id]);
+        assert!(assets.handle("a").is_none());
+        // Loading the path again makes a new asset
+        assert_ne!(assets.load_with("a", || 2).id(), id);
+    }
+
+    #[test]
+    fn materials_held_by_a_freed_mesh_are_freed_with_it() {
+        let mut server = AssetServer::new();
+        let materials: Vec<Handle<Material>> = (0..gltf_material::material_count("Cart").unwrap())
+            .map(|index| server.materials.reserve(&format!("Cart#{}", index)).0)
+            .collect();
+        let mesh = server.meshes.load_with("Cart#model", || {
+            Mesh::new("Cart").with_materials(&materials)
+        });
+        drop(materials);
+
+        // The mesh's submeshes still hold the materials they default to
+        assert!(server.free_unused().is_empty());
+        assert!(!server.materials.entries.is_empty());
+
+        let mesh_id = mesh.id();
+        drop(mesh);
+        assert_eq!(server.free_unused(), [mesh_id]);
+        assert!(server.materials.entries.is_empty());
+    }
+
+    #[test]
+    fn a_panicking_job_fails_its_assets() {
+        let mut server = AssetServer::new();
+        let missing = server.load_mesh("Missing");
+        let sphere = server.load_mesh("Sphere");
+
+        // Returns instead of waiting on the mesh that never decodes
+        server.wait_until_decoded();
+        assert_eq!(server.meshes.load_state(&missing), LoadState::Failed);
+        assert_eq!(server.meshes.load_state(&sphere), LoadState::Pending);
+
+        server.meshes.drain_pending();
+        assert!(server.meshes.get(missing.id()).is_none());
+        assert!(server.meshes.get(sphere.id()).is_some());
+    }
+
+    #[test]
+    fn an_unreadable_gltf_file_fails_instead_of_panicking() {
+        let mut server = AssetServer::new();
+        assert!(server.load_gltf_materials("Missing").is_empty());
+
+        // One node drawing the failed mesh, which shows as the placeholder
+        let (roots, meshes) = server.load_gltf_scene("Missing");
+        assert_eq!(roots.len(), 1);
+        assert_eq!(roots[0].mesh, Some(0));
+        assert_eq!(server.meshes.load_state(&meshes[0]), LoadState::Failed);
+        assert!(!server.meshes.is_loading());
+    }
+}
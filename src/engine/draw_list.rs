@@ -0,0 +1,221 @@
+use crate::engine::DrawInstance;
+
+/// A run of consecutive instances in the frame's instance buffer that share a
+/// submesh, level of detail and material, drawn with a single instanced call.
+#[derive(Debug, Clone, Copy)]
+pub struct DrawBatch {
+    pub mesh_id: usize,
+    pub submesh: usize,
+    pub lod: usize,
+    pub material_id: usize,
+    pub first_instance: u32,
+    pub instance_count: u32,
+    written: u32,
+}
+
+/// A single instance of a transparent material. These aren't batched since
+/// they have to be drawn back to front.
+#[derive(Debug, Clone, Copy)]
+pub struct TransparentDraw {
+    pub mesh_id: usize,
+    pub submesh: usize,
+    pub lod: usize,
+    pub material_id: usize,
+    /// Index of the instance in the frame's instance buffer
+    pub instance: u32,
+}
+
+/// Draw batches of a frame, sorted by `(mesh, submesh, lod, material)` so the order is stable
+/// between frames. Kept alive across frames so the batch storage is reused.
+///
+/// Filling it takes two passes: every instance is `count`ed, `assign_ranges`
+/// lays the batches out back to back, then every instance is `write`n into its
+/// batch's range.
+pub struct DrawList {
+    batches: Vec<DrawBatch>,
+}
+
+impl DrawList {
+    pub fn new() -> Self {
+        Self {
+            batches: Vec::new(),
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.batches.clear();
+    }
+
+    pub fn count(&mut self, mesh_id: usize, submesh: usize, lod: usize, material_id: usize) {
+        match self.find(mesh_id, submesh, lod, material_id) {
+            Ok(index) => self.batches[index].instance_count += 1,
+            Err(index) => self.batches.insert(
+                index,
+                DrawBatch {
+                    mesh_id,
+                    submesh,
+                    lod,
+                    material_id,
+                    first_instance: 0,
+                    instance_count: 1,
+                    written: 0,
+                },
+            ),
+        }
+    }
+
+    /// Gives every batch its range in the instance buffer, starting at
+    /// `first_instance`, and returns the index just past the last range.
+    pub fn assign_ranges(&mut self, first_instance: usize) -> usize {
+        let mut first_instance = first_instance as u32;
+        for batch in self.batches.iter_mut() {
+            batch.first_instance = first_instance;
+            batch.written = 0;
+            first_instance += batch.instance_count;
+        }
+
+        first_instance as usize
+    }
+
+    pub fn write(
+        &mut self,
+        mesh_id: usize,
+        submesh: usize,
+        lod: usize,
+        material_id: usize,
+        instance: DrawInstance,
+        instances: &mut [DrawInstance],
+    ) {
+        if let Ok(index) = self.find(mesh_id, submesh, lod, material_id) {
+            let batch = &mut self.batches[index];
+            instances[(batch.first_instance + batch.written) as usize] = instance;
+            batch.written += 1;
+        }
+    }
+
+    pub fn batches(&self) -> &[DrawBatch] {
+        &self.batches
+    }
+
+    fn find(
+        &self,
+        mesh_id: usize,
+        submesh: usize,
+        lod: usize,
+        material_id: usize,
+    ) -> Result<usize, usize> {
+        self.batches
+            .binary_search_by_key(&(mesh_id, submesh, lod, material_id), |batch| {
+                (batch.mesh_id, batch.submesh, batch.lod, batch.material_id)
+            })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn instance(marker: f32) -> DrawInstance {
+        let mut instance = DrawInstance::default();
+        instance.instance_model[3][0] = marker;
+        instance
+    }
+
+    #[test]
+    fn batches_are_sorted_by_key() {
+        let mut draw_list = DrawList::new();
+        draw_list.count(2, 0, 0, 1);
+        draw_list.count(1, 1, 0, 0);
+        draw_list.count(1, 0, 1, 0);
+        draw_list.count(1, 0, 0, 3);
+        draw_list.count(2, 0, 0, 1);
+
+        let keys: Vec<_> = draw_list
+            .batches()
+            .iter()
+            .map(|batch| {
+                (
+                    batch.mesh_id,
+                    batch.submesh,
+                    batch.lod,
+                    batch.material_id,
+                    batch.instance_count,
+                )
+            })
+            .collect();
+        assert_eq!(
+            keys,
+            [
+                (1, 0, 0, 3, 1),
+                (1, 0, 1, 0, 1),
+                (1, 1, 0, 0, 1),
+                (2, 0, 0, 1, 2)
+            ]
+        );
+    }
+
+    #[test]
+    fn ranges_are_laid_out_back_to_back() {
+        let mut draw_list = DrawList::new();
+        draw_list.count(1, 0, 0, 0);
+        draw_list.count(0, 0, 0, 0);
+        draw_list.count(1, 0, 0, 0);
+        draw_list.count(1, 0, 0, 0);
+
+        assert_eq!(draw_list.assign_ranges(0), 4);
+        let ranges: Vec<_> = draw_list
+            .batches()
+            .iter()
+            .map(|batch| (batch.first_instance, batch.instance_count))
+            .collect();
+        assert_eq!(ranges, [(0, 1), (1, 3)]);
+    }
+
+    #[test]
+    fn ranges_start_at_the_given_instance() {
+        let mut draw_list = DrawList::new();
+        draw_list.count(0, 0, 0, 0);
+        draw_list.count(1, 0, 0, 0);
+        draw_list.count(1, 0, 0, 0);
+
+        assert_eq!(draw_list.assign_ranges(5), 8);
+        let ranges: Vec<_> = draw_list
+            .batches()
+            .iter()
+            .map(|batch| (batch.first_instance, batch.instance_count))
+            .collect();
+        assert_eq!(ranges, [(5, 1), (6, 2)]);
+    }
+
+    #[test]
+    fn instances_are_written_into_their_batch() {
+        let mut draw_list = DrawList::new();
+        draw_list.count(1, 0, 0, 0);
+        draw_list.count(0, 0, 0, 0);
+        draw_list.count(1, 0, 0, 0);
+        let count = draw_list.assign_ranges(0);
+
+        let mut instances = vec![DrawInstance::default(); count];
+        draw_list.write(1, 0, 0, 0, instance(1.0), &mut instances);
+        draw_list.write(0, 0, 0, 0, instance(2.0), &mut instances);
+        draw_list.write(1, 0, 0, 0, instance(3.0), &mut instances);
+        // Not counted, so it has no range to go into
+        draw_list.write(5, 0, 0, 0, instance(4.0), &mut instances);
+
+        let markers: Vec<f32> = instances
+            .iter()
+            .map(|instance| instance.instance_model[3][0])
+            .collect();
+        assert_eq!(markers, [2.0, 1.0, 3.0]);
+    }
+
+    #[test]
+    fn clear_removes_every_batch() {
+        let mut draw_list = DrawList::new();
+        draw_list.count(0, 0, 0, 0);
+        draw_list.clear();
+
+        assert!(draw_list.batches().is_empty());
+        assert_eq!(draw_list.assign_ranges(0), 0);
+    }
+}
@@ -1,7 +1,9 @@
-use hecs::World;
+use std::collections::HashMap;
+
+use hecs::{Entity, World};
 use nalgebra_glm::{TMat4, Vec3, vec3};
 
-use crate::engine::InputManager;
+use crate::engine::{Handle, InputManager, Mesh, material::Material};
 
 pub struct Transform {
     pub position: Vec3,
@@ -9,9 +11,62 @@ pub struct Transform {
     pub scale: Vec3,
 }
 
-pub struct MeshID(pub usize);
+impl Transform {
+    /// The transform relative to the parent, or the world without one.
+    pub fn matrix(&self) -> TMat4<f32> {
+        nalgebra_glm::translation(&self.position)
+            * self.rotation
+            * nalgebra_glm::scaling(&self.scale)
+    }
+}
+
+/// Makes the entity's `Transform` relative to another entity's.
+pub struct Parent(pub Entity);
+
+/// Model matrix of an entity with `transform` and `parent`, applying the
+/// transforms of every ancestor on top of its own.
+pub fn world_matrix(world: &World, transform: &Transform, parent: Option<&Parent>) -> TMat4<f32> {
+    let mut matrix = transform.matrix();
+    let mut parent = parent.map(|parent| parent.0);
+    while let Some(entity) = parent {
+        let Ok(transform) = world.get::<&Transform>(entity) else {
+            break;
+        };
+        matrix = transform.matrix() * matrix;
+        parent = world.get::<&Parent>(entity).ok().map(|parent| parent.0);
+    }
+    matrix
+}
+
+pub struct MeshID(pub Handle<Mesh>);
+
+/// Material of every submesh not in the entity's `MaterialOverrides`, in
+/// place of the mesh's own.
+pub struct MaterialID(pub Handle<Material>);
+
+/// Materials replacing the mesh's own for individual submeshes, by submesh
+/// index.
+pub struct MaterialOverrides(pub HashMap<usize, Handle<Material>>);
 
-pub struct MaterialID(pub usize);
+/// Light shining in every direction from the entity's `Transform` position.
+pub struct PointLight {
+    pub color: Vec3,
+    pub intensity: f32,
+    /// Distance at which the light has faded out completely
+    pub range: f32,
+}
+
+/// Light shining in a cone along the entity's forward axis.
+pub struct SpotLight {
+    pub color: Vec3,
+    pub intensity: f32,
+    /// Distance at which the light has faded out completely
+    pub range: f32,
+    /// Half angle in radians inside which the light is at full strength
+    pub inner_angle: f32,
+    /// Half angle in radians at which the light has faded out completely
+    pub outer_angle: f32,
+}
 
 pub struct Car {
     pub velocity: Vec3,
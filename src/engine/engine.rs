@@ -3,16 +3,25 @@ use std::{
     sync::{Arc, RwLock},
 };
 
-use hecs::{Entity, World};
+use hecs::{Entity, With, Without, World};
 use nalgebra_glm::{TMat4, Vec3, identity, pi, rotate_normalized_axis, rotation, vec3};
 use once_cell::sync::Lazy;
 
 use crate::engine::{
-    DrawInstance, InputManager, Mesh, Skybox,
-    ecs::{Car, MaterialID, MeshID, Transform, car_system},
+    AssetServer, BlendMode, DrawInstance, DrawList, Frustum, Handle, InputManager, LightInstance,
+    Mesh, Skybox, TransparentDraw,
+    ecs::{
+        Car, MaterialID, MaterialOverrides, MeshID, Parent, PointLight, SpotLight, Transform,
+        car_system, world_matrix,
+    },
+    gltf_scene::SceneNode,
     material::Material,
 };
 
+// Drawn in place of meshes and skyboxes that are still loading
+const PLACEHOLDER_MESH: &str = "Sphere";
+const PLACEHOLDER_SKY_COLOR: [f32; 4] = [0.2, 0.2, 0.2, 1.0];
+
 static DEFAULT_ROTATION: Lazy<TMat4<f32>> = Lazy::new(|| {
     let default = identity();
     let default = rotate_normalized_axis(&default, pi(), &vec3(0.0, 0.0, 1.0));
@@ -27,12 +36,42 @@ pub struct Camera {
     pub requires_update: bool,
 }
 
+/// How many instances the last `prepare_draw_calls` kept and threw away.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CullStats {
+    pub drawn: usize,
+    pub culled: usize,
+}
+
+// Mesh, submesh, level of detail and material of an instance
+type DrawKey = (usize, usize, usize, usize);
+
 pub struct Engine {
     pub input_manager: InputManager,
-    pub meshes: HashMap<usize, Arc<Mesh>>,
-    pub materials: HashMap<usize, Arc<RwLock<Material>>>,
+    pub assets: AssetServer,
     pub world: World,
-    pub skybox: Skybox,
+    skybox: Handle<Skybox>,
+    // Used by submeshes without a material of their own, and in place of
+    // materials that are still loading
+    default_material: Handle<Material>,
+    placeholder_mesh: Handle<Mesh>,
+    placeholder_skybox: Handle<Skybox>,
+    pub draw_list: DrawList,
+    /// Instances with transparent materials, sorted back to front
+    pub transparent_draws: Vec<TransparentDraw>,
+    pub cull_stats: CullStats,
+    /// Opaque instances inside each shadow cascade, when culling on the CPU
+    pub shadow_draw_lists: Vec<DrawList>,
+    // Instances that passed culling this frame, with their batch key
+    visible_instances: Vec<(DrawKey, DrawInstance)>,
+    // Instances of `shadow_draw_lists`, with their cascade and batch key
+    shadow_instances: Vec<(usize, DrawKey, DrawInstance)>,
+    // Instance data of `transparent_draws`, in the same order
+    transparent_instances: Vec<DrawInstance>,
+    // World matrices of the last and the current `prepare_draw_calls`, for
+    // motion vectors of moving entities
+    previous_models: HashMap<Entity, TMat4<f32>>,
+    current_models: HashMap<Entity, TMat4<f32>>,
 
     pub camera: Camera,
     car_entity: Option<Entity>,
@@ -40,14 +79,43 @@ pub struct Engine {
 
 impl Engine {
     pub fn new() -> Self {
+        Self::with_skybox("assets/HDR/forest.exr")
+    }
+
+    pub fn with_skybox(skybox_path: &str) -> Self {
+        let mut assets = AssetServer::new();
+        let skybox = assets.load_skybox(skybox_path);
+
+        // Placeholders are read right away so there's always something to draw
+        let default_material = assets
+            .materials
+            .load_with("default", || Material::new("default"));
+        let placeholder_mesh = assets
+            .meshes
+            .load_with(PLACEHOLDER_MESH, || Mesh::new(PLACEHOLDER_MESH));
+        let placeholder_skybox = assets
+            .skyboxes
+            .load_with("#placeholder", || Skybox::solid(PLACEHOLDER_SKY_COLOR));
+
         Self {
             input_manager: InputManager::new(),
-            materials: HashMap::new(),
-            meshes: HashMap::new(),
+            assets,
 
             world: World::new(),
 
-            skybox: Skybox::new("assets/HDR/forest.exr"),
+            skybox,
+            default_material,
+            placeholder_mesh,
+            placeholder_skybox,
+            draw_list: DrawList::new(),
+            transparent_draws: Vec::new(),
+            cull_stats: CullStats::default(),
+            shadow_draw_lists: Vec::new(),
+            visible_instances: Vec::new(),
+            shadow_instances: Vec::new(),
+            transparent_instances: Vec::new(),
+            previous_models: HashMap::new(),
+            current_models: HashMap::new(),
             camera: Camera {
                 view: identity(),
                 camera_pos: vec3(0.0, 0.0, 0.0),
@@ -57,12 +125,21 @@ impl Engine {
         }
     }
 
+    /// The skybox, or a plain one while it's loading.
+    pub fn skybox(&self) -> Arc<RwLock<Skybox>> {
+        self.assets
+            .skyboxes
+            .get(self.skybox.id())
+            .or_else(|| self.assets.skyboxes.get(self.placeholder_skybox.id()))
+            .expect("placeholder skybox not uploaded")
+            .clone()
+    }
+
     pub fn init(&mut self) {
-        self.load_material(0, "default");
-        self.load_material(1, "material_cube");
-        self.load_mesh(0, "Material_Test");
+        let material = self.assets.load_material("material_cube");
+        let mesh = self.assets.load_mesh("Material_Test");
 
-        let entity = self.spawn_instance(0, 1, vec3(0.0, -1.5, -3.0));
+        let entity = self.spawn_instance(mesh, material, vec3(0.0, -1.5, -3.0));
         if let Ok(transform) = self.world.query_one_mut::<&mut Transform>(entity) {
             let rotation = rotate_normalized_axis(
                 &transform.rotation,
@@ -72,46 +149,141 @@ impl Engine {
             transform.rotation = rotation;
         }
 
+        self.spawn_point_light(
+            vec3(1.5, -2.5, -2.0),
+            PointLight {
+                color: vec3(1.0, 0.6, 0.3),
+                intensity: 4.0,
+                range: 6.0,
+            },
+        );
+
+        let cart = self.assets.load_gltf_model("Cart");
+        self.spawn_car(cart, vec3(0.0, 0.0, -6.0));
+        self.spawn_gltf_scene("Map", vec3(0.0, 0.0, 0.0));
+
         /*
-        let car_mesh_id = self.load_mesh("assets/meshes/Cart.glb");
-        self.spawn_car(car_mesh_id, vec3(0.0, 0.0, -6.0));
         let goal_mesh_id = self.load_mesh("assets/meshes/Goal.glb");
-        self.spawn_instance(goal_mesh_id, vec3(0.0, 0.0, -15.0));
-        let map_mesh_id = self.load_mesh("assets/meshes/Map.glb");
-        self.spawn_instance(map_mesh_id, vec3(0.0, 0.0, 0.0));*/
+        self.spawn_instance(goal_mesh_id, vec3(0.0, 0.0, -15.0));*/
     }
 
-    pub fn load_material(&mut self, material_id: usize, file_name: &str) {
-        let material = Arc::new(RwLock::new(Material::new(file_name)));
-        self.materials.insert(material_id.into(), material.clone());
+    /// Starts loading a glTF file's meshes and materials, then spawns its node
+    /// tree under a root entity at `pos`, which is returned. Every node becomes an
+    /// entity with a `Parent`, drawing its mesh, if any, with a submesh per
+    /// primitive.
+    ///
+    /// Primitives aren't split into entities with a `MeshID` each: as
+    /// submeshes they still get their own material, overridable per slot with
+    /// `set_material_override`, while sharing the node's transform, bounds and
+    /// level of detail, so a node is culled and switches level as a whole.
+    pub fn spawn_gltf_scene(&mut self, file_path: &str, pos: Vec3) -> Entity {
+        let (roots, meshes) = self.assets.load_gltf_scene(file_path);
+
+        let root = self.world.spawn((Transform {
+            position: pos,
+            rotation: DEFAULT_ROTATION.clone(),
+            scale: vec3(1.0, 1.0, 1.0),
+        },));
+        for node in roots {
+            self.spawn_scene_node(node, root, &meshes);
+        }
+
+        root
     }
 
-    pub fn load_mesh(&mut self, mesh_id: usize, file_path: &str) {
-        let mesh = Mesh::new(file_path);
-        self.meshes.insert(mesh_id, Arc::new(mesh));
+    fn spawn_scene_node(&mut self, node: SceneNode, parent: Entity, meshes: &[Handle<Mesh>]) {
+        let entity = self.world.spawn((node.transform, Parent(parent)));
+        if let Some(mesh) = node.mesh {
+            self.world
+                .insert_one(entity, MeshID(meshes[mesh].clone()))
+                .unwrap();
+        }
+
+        for child in node.children {
+            self.spawn_scene_node(child, entity, meshes);
+        }
     }
 
-    pub fn spawn_instance(&mut self, mesh_id: usize, material_id: usize, pos: Vec3) -> Entity {
+    pub fn spawn_instance(
+        &mut self,
+        mesh: Handle<Mesh>,
+        material: Handle<Material>,
+        pos: Vec3,
+    ) -> Entity {
         self.world.spawn((
             Transform {
                 position: pos,
                 rotation: DEFAULT_ROTATION.clone(),
                 scale: vec3(1.0, 1.0, 1.0),
             },
-            MaterialID(material_id),
-            MeshID(mesh_id),
+            MaterialID(material),
+            MeshID(mesh),
         ))
     }
 
+    /// Draws one submesh of `entity`'s mesh with `material`, in place of both
+    /// the entity's `MaterialID` and the submesh's own material.
     #[allow(dead_code)]
-    pub fn spawn_car(&mut self, mesh_id: usize, pos: Vec3) {
+    pub fn set_material_override(
+        &mut self,
+        entity: Entity,
+        submesh: usize,
+        material: Handle<Material>,
+    ) {
+        if let Ok(mut overrides) = self.world.get::<&mut MaterialOverrides>(entity) {
+            overrides.0.insert(submesh, material);
+            return;
+        }
+        self.world
+            .insert_one(
+                entity,
+                MaterialOverrides(HashMap::from([(submesh, material)])),
+            )
+            .unwrap();
+    }
+
+    pub fn spawn_point_light(&mut self, pos: Vec3, light: PointLight) -> Entity {
+        self.world.spawn((
+            Transform {
+                position: pos,
+                rotation: identity(),
+                scale: vec3(1.0, 1.0, 1.0),
+            },
+            light,
+        ))
+    }
+
+    /// Spawns a spot light at `pos` shining along `direction`.
+    #[allow(dead_code)]
+    pub fn spawn_spot_light(&mut self, pos: Vec3, direction: Vec3, light: SpotLight) -> Entity {
+        // The light shines along the transform's forward axis, -z
+        let up = if direction.normalize().y.abs() > 0.99 {
+            vec3(0.0, 0.0, 1.0)
+        } else {
+            vec3(0.0, 1.0, 0.0)
+        };
+        let rotation = nalgebra_glm::look_at(&vec3(0.0, 0.0, 0.0), &direction, &up)
+            .try_inverse()
+            .unwrap();
+
+        self.world.spawn((
+            Transform {
+                position: pos,
+                rotation,
+                scale: vec3(1.0, 1.0, 1.0),
+            },
+            light,
+        ))
+    }
+
+    pub fn spawn_car(&mut self, mesh: Handle<Mesh>, pos: Vec3) {
         let entity = self.world.spawn((
             Transform {
                 position: pos,
                 rotation: DEFAULT_ROTATION.clone(),
                 scale: vec3(1.0, 1.0, 1.0),
             },
-            MeshID(mesh_id),
+            MeshID(mesh),
             Car {
                 velocity: vec3(0.0, 0.0, 0.0),
                 turn_speed: 1.5,
@@ -121,33 +293,255 @@ impl Engine {
         self.car_entity = Some(entity);
     }
 
-    pub fn get_draw_calls(&self) -> HashMap<(usize, usize), Vec<DrawInstance>> {
-        let mut instanced_draw_calls: HashMap<(usize, usize), Vec<DrawInstance>> = HashMap::new();
+    /// Groups the submeshes of every drawable entity inside the camera frustum
+    /// into the draw list's batches, picking each entity's level of detail from
+    /// its size on screen, and returns how many instances `write_draw_instances` will
+    /// write. With `cull` off every opaque instance is kept, for when the GPU
+    /// culls them instead.
+    ///
+    /// With `cull` on, opaque instances are also culled against every shadow
+    /// cascade's `light_view_projections` into `shadow_draw_lists`, so casters
+    /// outside the camera's view still cast shadows. Their instance data comes
+    /// after the transparent instances'.
+    ///
+    /// A submesh is drawn with the entity's `MaterialOverrides` entry for it,
+    /// else its `MaterialID`, else the submesh's own material. Meshes and
+    /// materials that are still loading are replaced by placeholders.
+    ///
+    /// Instances with transparent materials go to `transparent_draws` instead,
+    /// always culled here and sorted back to front. Their instance data follows
+    /// the batches' and they don't cast shadows.
+    pub fn prepare_draw_calls(
+        &mut self,
+        view: &TMat4<f32>,
+        projection: &TMat4<f32>,
+        light_view_projections: &[TMat4<f32>],
+        cull: bool,
+    ) -> usize {
+        self.draw_list.clear();
+        self.visible_instances.clear();
+        self.shadow_instances.clear();
+        self.cull_stats = CullStats::default();
+        // Transparent instances with their view depth, for sorting
+        let mut transparent = Vec::new();
+
+        let frustum = Frustum::from_matrix(&(projection * view));
+        // The cascades' boxes already reach out towards the light
+        let shadow_frusta: Vec<Frustum> = if cull {
+            light_view_projections
+                .iter()
+                .map(Frustum::from_matrix)
+                .collect()
+        } else {
+            Vec::new()
+        };
+        self.shadow_draw_lists
+            .resize_with(shadow_frusta.len(), DrawList::new);
+        for draw_list in self.shadow_draw_lists.iter_mut() {
+            draw_list.clear();
+        }
+        // Converts a radius over a view distance into a fraction of the screen height
+        let screen_scale = projection[(1, 1)].abs();
 
-        for (_, (transform, mesh_id, material_id)) in self
+        for (entity, (transform, mesh_id, material_id, overrides, parent)) in self
             .world
-            .query::<(&Transform, &MeshID, &MaterialID)>()
+            .query::<(
+                &Transform,
+                &MeshID,
+                Option<&MaterialID>,
+                Option<&MaterialOverrides>,
+                Option<&Parent>,
+            )>()
             .iter()
         {
-            let translation = nalgebra_glm::translation(&transform.position);
-            let scale = nalgebra_glm::scaling(&transform.scale);
+            // Meshes that are still loading are drawn as the placeholder
+            let mesh_id = Some(mesh_id.0.id())
+                .filter(|mesh_id| self.assets.meshes.get(*mesh_id).is_some())
+                .unwrap_or(self.placeholder_mesh.id());
+            let Some(mesh) = self.assets.meshes.get(mesh_id) else {
+                continue;
+            };
+            let mesh = mesh.read().unwrap();
+            let model_matrix = world_matrix(&self.world, transform, parent);
+
+            let (center, radius) = mesh.bounds.world_sphere(&model_matrix);
+            let in_frustum = frustum.intersects_sphere(&center, radius);
+            let distance = -(view * center.push(1.0)).z;
+            let screen_size = radius * screen_scale / distance.max(0.01);
+            let lod = mesh.select_lod(screen_size);
 
-            let model_matrix = translation * transform.rotation * scale;
             let normal_matrix = nalgebra_glm::inverse_transpose(model_matrix);
+            // Entities spawned this frame haven't moved yet
+            let previous_matrix = self
+                .previous_models
+                .get(&entity)
+                .copied()
+                .unwrap_or(model_matrix);
+            self.current_models.insert(entity, model_matrix);
+            let instance = DrawInstance::new(model_matrix, normal_matrix, previous_matrix);
+            let mut drawn = false;
+
+            for (submesh, submesh_data) in mesh.submeshes.iter().enumerate() {
+                if submesh_data.lods[lod].index_count == 0 {
+                    continue;
+                }
+
+                let material_id = overrides
+                    .and_then(|overrides| overrides.0.get(&submesh))
+                    .or(material_id.map(|material_id| &material_id.0))
+                    .or(submesh_data.material.as_ref())
+                    .map(Handle::id)
+                    .filter(|material_id| self.assets.materials.get(*material_id).is_some())
+                    .unwrap_or(self.default_material.id());
+                let blend_mode = self
+                    .assets
+                    .materials
+                    .get(material_id)
+                    .map_or(BlendMode::Opaque, |material| {
+                        material.read().unwrap().blend_mode
+                    });
+                let is_transparent = blend_mode.is_transparent();
+
+                if !is_transparent {
+                    for (cascade, shadow_frustum) in shadow_frusta.iter().enumerate() {
+                        if shadow_frustum.intersects_sphere(&center, radius) {
+                            self.shadow_instances.push((
+                                cascade,
+                                (mesh_id, submesh, lod, material_id),
+                                instance,
+                            ));
+                            self.shadow_draw_lists[cascade].count(
+                                mesh_id,
+                                submesh,
+                                lod,
+                                material_id,
+                            );
+                        }
+                    }
+                }
+
+                if (cull || is_transparent) && !in_frustum {
+                    continue;
+                }
+                drawn = true;
+
+                if is_transparent {
+                    let depth = -(view * model_matrix.column(3)).z;
+                    transparent.push((
+                        depth,
+                        TransparentDraw {
+                            mesh_id,
+                            submesh,
+                            lod,
+                            material_id,
+                            instance: 0,
+                        },
+                        instance,
+                    ));
+                    continue;
+                }
+
+                self.visible_instances
+                    .push(((mesh_id, submesh, lod, material_id), instance));
+                self.draw_list.count(mesh_id, submesh, lod, material_id);
+            }
+
+            if drawn {
+                self.cull_stats.drawn += 1;
+            } else {
+                self.cull_stats.culled += 1;
+            }
+        }
+
+        std::mem::swap(&mut self.previous_models, &mut self.current_models);
+        self.current_models.clear();
+
+        let opaque_count = self.draw_list.assign_ranges(0);
+
+        transparent.sort_by(|a, b| b.0.total_cmp(&a.0));
+        self.transparent_draws.clear();
+        self.transparent_instances.clear();
+        for (index, (_, mut draw, instance)) in transparent.into_iter().enumerate() {
+            draw.instance = (opaque_count + index) as u32;
+            self.transparent_draws.push(draw);
+            self.transparent_instances.push(instance);
+        }
+
+        let mut instance_count = opaque_count + self.transparent_draws.len();
+        for draw_list in self.shadow_draw_lists.iter_mut() {
+            instance_count = draw_list.assign_ranges(instance_count);
+        }
+
+        instance_count
+    }
+
+    /// Writes the instance data of every entity kept by `prepare_draw_calls`
+    /// into its batch's range of `instances`, which is usually mapped GPU
+    /// memory.
+    pub fn write_draw_instances(&mut self, instances: &mut [DrawInstance]) {
+        for ((mesh_id, submesh, lod, material_id), instance) in self.visible_instances.iter() {
+            self.draw_list
+                .write(*mesh_id, *submesh, *lod, *material_id, *instance, instances);
+        }
 
-            let draw_instances = instanced_draw_calls
-                .entry((mesh_id.0, material_id.0))
-                .or_default();
-            draw_instances.push(DrawInstance::new(model_matrix, normal_matrix));
+        for (draw, instance) in self
+            .transparent_draws
+            .iter()
+            .zip(&self.transparent_instances)
+        {
+            instances[draw.instance as usize] = *instance;
         }
 
-        instanced_draw_calls
+        for (cascade, (mesh_id, submesh, lod, material_id), instance) in
+            self.shadow_instances.iter()
+        {
+            self.shadow_draw_lists[*cascade].write(
+                *mesh_id,
+                *submesh,
+                *lod,
+                *material_id,
+                *instance,
+                instances,
+            );
+        }
+    }
+
+    /// Collects every point and spot light in the world for the lighting pass.
+    pub fn gather_lights(&self) -> Vec<LightInstance> {
+        let mut lights = Vec::new();
+
+        for (_, (transform, light)) in self.world.query::<(&Transform, &PointLight)>().iter() {
+            lights.push(LightInstance {
+                position: transform.position,
+                range: light.range,
+                color: light.color * light.intensity,
+                direction: vec3(0.0, 0.0, 0.0),
+                cone: None,
+            });
+        }
+
+        for (_, (transform, light)) in self.world.query::<(&Transform, &SpotLight)>().iter() {
+            lights.push(LightInstance {
+                position: transform.position,
+                range: light.range,
+                color: light.color * light.intensity,
+                direction: -transform.rotation.column(2).xyz().normalize(),
+                cone: Some((light.inner_angle.cos(), light.outer_angle.cos())),
+            });
+        }
+
+        lights
     }
 
     pub fn tick(&mut self, delta: f32) {
         car_system(&mut self.world, &self.input_manager, delta);
 
-        for (_entity, transform) in self.world.query_mut::<&mut Transform>() {
+        // Only standalone instances spin, children turn along with their root
+        // and cars and scene roots, which have no `MaterialID`, stay put
+        for (_entity, transform) in self
+            .world
+            .query_mut::<Without<With<&mut Transform, &MaterialID>, &Parent>>()
+        {
             let mut test = rotation(delta, &vec3(0.0, 1.0, 0.0));
             test *= rotation(delta, &vec3(1.0, 0.0, 0.0));
             transform.rotation = test * transform.rotation;
@@ -0,0 +1,162 @@
+use nalgebra_glm::{TMat4, Vec3, Vec4, vec4};
+
+/// Axis aligned box around a mesh in its local space.
+#[derive(Debug, Clone, Copy)]
+pub struct Bounds {
+    pub min: Vec3,
+    pub max: Vec3,
+}
+
+impl Bounds {
+    /// Smallest box containing every point, or an empty box at the origin.
+    pub fn from_points(points: impl IntoIterator<Item = [f32; 3]>) -> Bounds {
+        let mut points = points.into_iter().map(Vec3::from);
+        let first = match points.next() {
+            Some(point) => point,
+            None => {
+                return Bounds {
+                    min: Vec3::zeros(),
+                    max: Vec3::zeros(),
+                };
+            }
+        };
+
+        points.fold(
+            Bounds {
+                min: first,
+                max: first,
+            },
+            |bounds, point| Bounds {
+                min: bounds.min.inf(&point),
+                max: bounds.max.sup(&point),
+            },
+        )
+    }
+
+    pub fn center(&self) -> Vec3 {
+        (self.min + self.max) * 0.5
+    }
+
+    /// Radius of the sphere around `center` that contains the box.
+    pub fn radius(&self) -> f32 {
+        (self.max - self.min).norm() * 0.5
+    }
+
+    /// World space bounding sphere of the box placed with `model`.
+    pub fn world_sphere(&self, model: &TMat4<f32>) -> (Vec3, f32) {
+        let center = model * self.center().push(1.0);
+        let max_scale = (0..3)
+            .map(|axis| model.column(axis).xyz().norm())
+            .fold(0.0, f32::max);
+
+        (center.xyz(), self.radius() * max_scale)
+    }
+}
+
+/// The six planes bounding what a camera can see, pointing inwards.
+pub struct Frustum {
+    planes: [Vec4; 6],
+}
+
+impl Frustum {
+    /// Extracts the planes from a view projection matrix with an OpenGL style
+    /// `[-1, 1]` depth range, which also covers the Vulkan range.
+    pub fn from_matrix(view_projection: &TMat4<f32>) -> Frustum {
+        let row = |i: usize| {
+            let r = view_projection.row(i);
+            vec4(r[0], r[1], r[2], r[3])
+        };
+        let (x, y, z, w) = (row(0), row(1), row(2), row(3));
+
+        let planes = [w + x, w - x, w + y, w - y, w + z, w - z].map(|plane| {
+            let length = plane.xyz().norm();
+            plane / length
+        });
+
+        Frustum { planes }
+    }
+
+    /// The planes as `(normal, distance)` vectors.
+    pub fn planes(&self) -> [Vec4; 6] {
+        self.planes
+    }
+
+    /// Whether any part of the sphere is inside the frustum.
+    pub fn intersects_sphere(&self, center: &Vec3, radius: f32) -> bool {
+        self.planes
+            .iter()
+            .all(|plane| plane.xyz().dot(center) + plane.w >= -radius)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use nalgebra_glm::{half_pi, perspective, scaling, translation, vec3};
+
+    // Looks down -z with a 90 degree field of view, from 0.1 to 100
+    fn camera_frustum() -> Frustum {
+        let projection = perspective(1.0, half_pi(), 0.1, 100.0);
+        Frustum::from_matrix(&projection)
+    }
+
+    #[test]
+    fn planes_are_normalized() {
+        for plane in camera_frustum().planes() {
+            assert!((plane.xyz().norm() - 1.0).abs() < 1e-5);
+        }
+    }
+
+    #[test]
+    fn spheres_inside_the_view_intersect() {
+        let frustum = camera_frustum();
+        assert!(frustum.intersects_sphere(&vec3(0.0, 0.0, -5.0), 0.1));
+        assert!(frustum.intersects_sphere(&vec3(4.0, -4.0, -5.0), 0.1));
+    }
+
+    #[test]
+    fn spheres_outside_the_view_do_not_intersect() {
+        let frustum = camera_frustum();
+        // Behind, to the side, above and past the far plane
+        assert!(!frustum.intersects_sphere(&vec3(0.0, 0.0, 5.0), 1.0));
+        assert!(!frustum.intersects_sphere(&vec3(20.0, 0.0, -5.0), 1.0));
+        assert!(!frustum.intersects_sphere(&vec3(0.0, 20.0, -5.0), 1.0));
+        assert!(!frustum.intersects_sphere(&vec3(0.0, 0.0, -150.0), 1.0));
+    }
+
+    #[test]
+    fn spheres_straddling_a_plane_intersect() {
+        let frustum = camera_frustum();
+        // The left plane passes through x = -5 at this depth, about 0.71
+        // away from the center along its normal
+        let center = vec3(-6.0, 0.0, -5.0);
+        assert!(frustum.intersects_sphere(&center, 1.0));
+        assert!(!frustum.intersects_sphere(&center, 0.5));
+    }
+
+    #[test]
+    fn bounds_contain_every_point() {
+        let bounds = Bounds::from_points([[1.0, -2.0, 3.0], [-1.0, 4.0, 0.0], [0.5, 0.0, -3.0]]);
+        assert_eq!(bounds.min, vec3(-1.0, -2.0, -3.0));
+        assert_eq!(bounds.max, vec3(1.0, 4.0, 3.0));
+        assert_eq!(bounds.center(), vec3(0.0, 1.0, 0.0));
+    }
+
+    #[test]
+    fn bounds_of_no_points_are_empty() {
+        let bounds = Bounds::from_points([]);
+        assert_eq!(bounds.min, Vec3::zeros());
+        assert_eq!(bounds.max, Vec3::zeros());
+        assert_eq!(bounds.radius(), 0.0);
+    }
+
+    #[test]
+    fn world_sphere_follows_translation_and_largest_scale() {
+        let bounds = Bounds::from_points([[-1.0, -1.0, -1.0], [1.0, 1.0, 1.0]]);
+        let model = translation(&vec3(1.0, 2.0, 3.0)) * scaling(&vec3(1.0, 2.0, 3.0));
+
+        let (center, radius) = bounds.world_sphere(&model);
+        assert!((center - vec3(1.0, 2.0, 3.0)).norm() < 1e-5);
+        assert!((radius - 3.0f32.sqrt() * 3.0).abs() < 1e-5);
+    }
+}
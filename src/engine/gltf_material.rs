@@ -0,0 +1,270 @@
+use gltf::image::{Data, Format};
+use gltf::material::AlphaMode;
+use vulkano::image::ImageDimensions;
+
+use crate::engine::material::{BlendMode, Material, OpacitySource, Texture};
+
+/// Imports every material of `assets/meshes/{file_path}.glb`, in the file's
+/// order, with their textures packed into the engine's layout. Embedded and
+/// external images are both supported.
+///
+/// Alpha blended materials use the base color factor's alpha as a constant
+/// opacity, masked ones become cutouts of the base color alpha.
+pub fn load_materials(file_path: &str) -> Vec<Material> {
+    let mesh_path = format!("assets/meshes/{}.glb", file_path);
+    let (gltf, _, images) = gltf::import(mesh_path).expect("Failed to open glTF");
+
+    pack_materials(file_path, &gltf, &images)
+}
+
+/// Number of materials in `assets/meshes/{file_path}.glb`, without decoding
+/// any image.
+pub fn material_count(file_path: &str) -> Result<usize, gltf::Error> {
+    let mesh_path = format!("assets/meshes/{}.glb", file_path);
+    Ok(gltf::Gltf::open(mesh_path)?.materials().len())
+}
+
+/// Materials of a glTF file already imported by the caller, see `load_materials`.
+pub fn pack_materials(file_path: &str, gltf: &gltf::Document, images: &[Data]) -> Vec<Material> {
+    gltf.materials()
+        .enumerate()
+        .map(|(index, material)| {
+            let name = format!(
+                "{}#{}",
+                file_path,
+                material
+                    .name()
+                    .map_or_else(|| index.to_string(), str::to_string)
+            );
+            pack_material(&name, &material, images)
+        })
+        .collect()
+}
+
+fn pack_material(name: &str, material: &gltf::Material, images: &[Data]) -> Material {
+    let pbr = material.pbr_metallic_roughness();
+    let image = |texture: gltf::Texture| &images[texture.source().index()];
+
+    let base_color = pbr.base_color_texture().map(|info| image(info.texture()));
+    let metallic_roughness = pbr
+        .metallic_roughness_texture()
+        .map(|info| image(info.texture()));
+    let normal = material.normal_texture().map(|info| image(info.texture()));
+    let occlusion = material
+        .occlusion_texture()
+        .map(|info| image(info.texture()));
+    let emissive = material
+        .emissive_texture()
+        .map(|info| image(info.texture()));
+
+    let normal_scale = material.normal_texture().map_or(1.0, |info| info.scale());
+    let occlusion_strength = material
+        .occlusion_texture()
+        .map_or(1.0, |info| info.strength());
+    let base_color_factor = pbr.base_color_factor();
+    let roughness_factor = pbr.roughness_factor();
+    let metallic_factor = pbr.metallic_factor();
+
+    // Everything is resampled to the largest texture the material uses
+    let used = [base_color, metallic_roughness, normal, occlusion];
+    let width = used
+        .iter()
+        .flatten()
+        .map(|data| data.width)
+        .max()
+        .unwrap_or(1);
+    let height = used
+        .iter()
+        .flatten()
+        .map(|data| data.height)
+        .max()
+        .unwrap_or(1);
+
+    let texel_count = (width * height) as usize;
+    let mut base_color_rgba = Vec::with_capacity(texel_count * 4);
+    let mut albedo_ao = Vec::with_capacity(texel_count * 4);
+    let mut surface = Vec::with_capacity(texel_count * 4);
+
+    for y in 0..height {
+        for x in 0..width {
+            let u = (x as f32 + 0.5) / width as f32;
+            let v = (y as f32 + 0.5) / height as f32;
+
+            // The base color texture is sRGB encoded but its factor is linear
+            let color = sample(base_color, u, v);
+            let color: [f32; 4] = std::array::from_fn(|c| {
+                let value = if c < 3 {
+                    srgb_to_linear(color[c])
+                } else {
+                    color[c]
+                };
+                value * base_color_factor[c]
+            });
+            let ao = 1.0 + occlusion_strength * (sample(occlusion, u, v)[0] - 1.0);
+            albedo_ao.extend(
+                [
+                    linear_to_srgb(color[0]),
+                    linear_to_srgb(color[1]),
+                    linear_to_srgb(color[2]),
+                    ao,
+                ]
+                .map(to_unorm),
+            );
+            base_color_rgba.extend(color.map(to_unorm));
+
+            // Tangent space normal from [0, 1] to the octahedral encoding
+            let n = sample(normal, u, v);
+            let n = nalgebra_glm::vec3(
+                (n[0] * 2.0 - 1.0) * normal_scale,
+                (n[1] * 2.0 - 1.0) * normal_scale,
+                n[2] * 2.0 - 1.0,
+            )
+            .normalize();
+            let encoded = encode_octahedral([n.x, n.y, n.z]);
+
+            // glTF keeps roughness in green and metalness in blue
+            let mr = sample(metallic_roughness, u, v);
+            surface.extend(
+                [
+                    encoded[0],
+                    encoded[1],
+                    mr[1] * roughness_factor,
+                    mr[2] * metallic_factor,
+                ]
+                .map(to_unorm),
+            );
+        }
+    }
+
+    let dimensions = ImageDimensions::Dim2d {
+        width,
+        height,
+        array_layers: 1,
+    };
+    let mut packed = Material::from_textures(
+        name,
+        Texture {
+            data: albedo_ao,
+            dimensions,
+            srgb: true,
+        },
+        Texture {
+            data: surface,
+            dimensions,
+            srgb: false,
+        },
+    );
+
+    let emissive_factor = material.emissive_factor();
+    if let Some(emissive) = emissive {
+        packed = packed.with_emissive_texture(to_srgb_texture(emissive), emissive_factor);
+    } else if emissive_factor != [0.0; 3] {
+        packed = packed.with_emissive_texture(Texture::solid([255; 4]), emissive_factor);
+    }
+
+    match material.alpha_mode() {
+        AlphaMode::Opaque => packed,
+        AlphaMode::Mask => packed.with_cutout(
+            OpacitySource::BaseColorAlpha(Texture {
+                data: base_color_rgba,
+                dimensions,
+                srgb: false,
+            }),
+            material.alpha_cutoff().unwrap_or(0.5),
+        ),
+        AlphaMode::Blend => packed.with_blend_mode(BlendMode::Alpha, base_color_factor[3]),
+    }
+}
+
+/// Nearest texel of `image` at `u`, `v`, or white without an image.
+fn sample(image: Option<&Data>, u: f32, v: f32) -> [f32; 4] {
+    let Some(image) = image else {
+        return [1.0; 4];
+    };
+    let x = ((u * image.width as f32) as u32).min(image.width - 1);
+    let y = ((v * image.height as f32) as u32).min(image.height - 1);
+    texel(image, x, y)
+}
+
+fn texel(image: &Data, x: u32, y: u32) -> [f32; 4] {
+    let (channels, size) = match image.format {
+        Format::R8 => (1, 1),
+        Format::R8G8 => (2, 1),
+        Format::R8G8B8 => (3, 1),
+        Format::R8G8B8A8 => (4, 1),
+        Format::R16 => (1, 2),
+        Format::R16G16 => (2, 2),
+        Format::R16G16B16 => (3, 2),
+        Format::R16G16B16A16 => (4, 2),
+        // Float images aren't used for materials
+        _ => return [1.0; 4],
+    };
+
+    let start = (y * image.width + x) as usize * channels * size;
+    let mut value = [0.0, 0.0, 0.0, 1.0];
+    for (channel, out) in value.iter_mut().enumerate().take(channels) {
+        let offset = start + channel * size;
+        *out = if size == 1 {
+            image.pixels[offset] as f32 / 255.0
+        } else {
+            u16::from_ne_bytes([image.pixels[offset], image.pixels[offset + 1]]) as f32 / 65535.0
+        };
+    }
+
+    // Grayscale images spread their one channel
+    if channels == 1 {
+        value[1] = value[0];
+        value[2] = value[0];
+    }
+    value
+}
+
+/// `image` as is, for color textures which glTF stores sRGB encoded.
+fn to_srgb_texture(image: &Data) -> Texture {
+    let mut data = Vec::with_capacity((image.width * image.height * 4) as usize);
+    for y in 0..image.height {
+        for x in 0..image.width {
+            data.extend(texel(image, x, y).map(to_unorm));
+        }
+    }
+
+    Texture {
+        data,
+        dimensions: ImageDimensions::Dim2d {
+            width: image.width,
+            height: image.height,
+            array_layers: 1,
+        },
+        srgb: true,
+    }
+}
+
+fn srgb_to_linear(value: f32) -> f32 {
+    if value <= 0.04045 {
+        value / 12.92
+    } else {
+        ((value + 0.055) / 1.055).powf(2.4)
+    }
+}
+
+fn linear_to_srgb(value: f32) -> f32 {
+    if value <= 0.0031308 {
+        value * 12.92
+    } else {
+        1.055 * value.powf(1.0 / 2.4) - 0.055
+    }
+}
+
+fn to_unorm(value: f32) -> u8 {
+    (value.clamp(0.0, 1.0) * 255.0).round() as u8
+}
+
+/// Same encoding as `encode_octahedral` in `deferred.frag`.
+fn encode_octahedral(n: [f32; 3]) -> [f32; 2] {
+    let length = n[0].abs() + n[1].abs() + n[2].abs() + 1e-8;
+    let (mut x, mut y, z) = (n[0] / length, n[1] / length, n[2] / length);
+    if z < 0.0 {
+        (x, y) = ((1.0 - y.abs()) * x.signum(), (1.0 - x.abs()) * y.signum());
+    }
+    [x * 0.5 + 0.5, y * 0.5 + 0.5]
+}
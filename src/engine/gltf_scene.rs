@@ -0,0 +1,146 @@
+use nalgebra_glm::{identity, quat, quat_to_mat4, vec3};
+
+use crate::engine::{Mesh, ecs::Transform, gltf_material, material::Material};
+
+/// The node tree of a glTF file, read without decoding its buffers or images.
+pub struct SceneLayout {
+    /// Root nodes of the file's default scene
+    pub roots: Vec<SceneNode>,
+    pub mesh_count: usize,
+    pub material_count: usize,
+}
+
+pub struct SceneNode {
+    /// Relative to the parent node, with the Z axis flipped like the meshes
+    pub transform: Transform,
+    /// Index of the node's mesh in the file
+    pub mesh: Option<usize>,
+    pub children: Vec<SceneNode>,
+}
+
+/// Reads the node hierarchy of `assets/meshes/{file_path}.glb`, unlike
+/// `Mesh::new` which merges every primitive into a single mesh. Meshes used by
+/// several nodes are only loaded once.
+pub fn load_layout(file_path: &str) -> Result<SceneLayout, gltf::Error> {
+    let mesh_path = format!("assets/meshes/{}.glb", file_path);
+    let gltf = gltf::Gltf::open(mesh_path)?;
+
+    let roots = gltf
+        .default_scene()
+        .or_else(|| gltf.scenes().next())
+        .map_or_else(Vec::new, |scene| {
+            scene.nodes().map(|node| read_node(&node)).collect()
+        });
+
+    Ok(SceneLayout {
+        roots,
+        mesh_count: gltf.meshes().len(),
+        material_count: gltf.materials().len(),
+    })
+}
+
+/// Stands in for the layout of a file that can't be read: a single node
+/// drawing the first mesh, which never loads, so a placeholder shows where
+/// the scene would be.
+pub fn failed_layout() -> SceneLayout {
+    SceneLayout {
+        roots: vec![SceneNode {
+            transform: Transform {
+                position: vec3(0.0, 0.0, 0.0),
+                rotation: identity(),
+                scale: vec3(1.0, 1.0, 1.0),
+            },
+            mesh: Some(0),
+            children: Vec::new(),
+        }],
+        mesh_count: 1,
+        material_count: 0,
+    }
+}
+
+/// Decodes every mesh, with a submesh per primitive, and every material of
+/// the file, both in the file's order.
+pub fn load_assets(file_path: &str) -> (Vec<Mesh>, Vec<Material>) {
+    let mesh_path = format!("assets/meshes/{}.glb", file_path);
+    let (gltf, buffers, images) = gltf::import(mesh_path).expect("Failed to open glTF");
+
+    let meshes = gltf
+        .meshes()
+        .map(|mesh| Mesh::from_gltf_mesh(&mesh, &buffers))
+        .collect();
+
+    (
+        meshes,
+        gltf_material::pack_materials(file_path, &gltf, &images),
+    )
+}
+
+fn read_node(node: &gltf::Node) -> SceneNode {
+    let ([x, y, z], [rx, ry, rz, rw], [sx, sy, sz]) = node.transform().decomposed();
+
+    SceneNode {
+        // Mirroring Z negates the rotation around the X and Y axes
+        transform: Transform {
+            position: vec3(x, y, -z),
+            rotation: quat_to_mat4(&quat(-rx, -ry, rz, rw)),
+            scale: vec3(sx, sy, sz),
+        },
+        mesh: node.mesh().map(|mesh| mesh.index()),
+        children: node.children().map(|child| read_node(&child)).collect(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use nalgebra_glm::{quat_angle_axis, scaling};
+
+    use super::*;
+
+    // The root node of a file with one root node and a child, neither with a mesh
+    fn root(translation: [f32; 3], rotation: [f32; 4]) -> SceneNode {
+        let json = format!(
+            r#"{{
+                "asset": {{"version": "2.0"}},
+                "scenes": [{{"nodes": [0]}}],
+                "nodes": [
+                    {{
+                        "translation": {:?},
+                        "rotation": {:?},
+                        "scale": [2.0, 3.0, 4.0],
+                        "children": [1]
+                    }},
+                    {{}}
+                ]
+            }}"#,
+            translation, rotation
+        );
+        let gltf = gltf::Gltf::from_slice(json.as_bytes()).unwrap();
+        read_node(&gltf.nodes().next().unwrap())
+    }
+
+    #[test]
+    fn translation_is_mirrored_along_z() {
+        let node = root([1.0, 2.0, 3.0], [0.0, 0.0, 0.0, 1.0]);
+        assert_eq!(node.transform.position, vec3(1.0, 2.0, -3.0));
+    }
+
+    #[test]
+    fn rotation_is_mirrored_along_z() {
+        let rotation = quat_angle_axis(0.7, &vec3(1.0, 2.0, 3.0).normalize());
+        let node = root([0.0; 3], rotation.coords.into());
+
+        // Mirroring, rotating and mirroring back
+        let mirror = scaling(&vec3(1.0, 1.0, -1.0));
+        let expected = mirror * quat_to_mat4(&rotation) * mirror;
+        assert!((node.transform.rotation - expected).amax() < 1e-5);
+    }
+
+    #[test]
+    fn scale_and_children_are_kept() {
+        let node = root([0.0; 3], [0.0, 0.0, 0.0, 1.0]);
+        assert_eq!(node.transform.scale, vec3(2.0, 3.0, 4.0));
+        assert_eq!(node.mesh, None);
+        assert_eq!(node.children.len(), 1);
+        assert!(node.children[0].children.is_empty());
+    }
+}
@@ -22,17 +22,36 @@ pub struct DrawInstance {
     // This is struct used to pass in instance transformation for instancing
     pub instance_model: [[f32; 4]; 4],
     pub instance_normal: [[f32; 4]; 4],
+    // Model matrix of the previous frame, for motion vectors
+    pub instance_previous_model: [[f32; 4]; 4],
 }
 
 impl DrawInstance {
-    pub fn new(instance_model: TMat4<f32>, instance_normal: TMat4<f32>) -> Self {
+    pub fn new(
+        instance_model: TMat4<f32>,
+        instance_normal: TMat4<f32>,
+        instance_previous_model: TMat4<f32>,
+    ) -> Self {
         Self {
             instance_model: instance_model.into(),
             instance_normal: instance_normal.into(),
+            instance_previous_model: instance_previous_model.into(),
         }
     }
 }
 
+/// A point or spot light gathered from the world for the current frame.
+#[derive(Clone, Copy, Debug)]
+pub struct LightInstance {
+    pub position: Vec3,
+    pub range: f32,
+    // Color already multiplied by intensity
+    pub color: Vec3,
+    pub direction: Vec3,
+    // Cosines of the spot cone angles, None for point lights
+    pub cone: Option<(f32, f32)>,
+}
+
 static DEFAULT_ROTATION: Lazy<TMat4<f32>> = Lazy::new(|| {
     let default = identity();
     let default = rotate_normalized_axis(&default, pi(), &vec3(0.0, 0.0, 1.0));
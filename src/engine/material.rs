@@ -1,11 +1,13 @@
 use std::{
     fs::{self},
     io::Cursor,
+    path::Path,
     sync::Arc,
 };
 
 use vulkano::{
     command_buffer::{AutoCommandBufferBuilder, PrimaryAutoCommandBuffer},
+    descriptor_set::PersistentDescriptorSet,
     format::Format,
     image::{ImageDimensions, ImageViewAbstract, ImmutableImage, MipmapsCount, view::ImageView},
     memory::allocator::StandardMemoryAllocator,
@@ -15,14 +17,76 @@ use vulkano::{
 pub struct Texture {
     pub data: Vec<u8>,
     pub dimensions: ImageDimensions,
+    /// Whether RGB is sRGB encoded and decoded when sampled, alpha is always linear
+    pub srgb: bool,
+}
+
+impl Texture {
+    /// A single RGBA texel.
+    pub fn solid(color: [u8; 4]) -> Texture {
+        Texture {
+            data: color.to_vec(),
+            dimensions: ImageDimensions::Dim2d {
+                width: 1,
+                height: 1,
+                array_layers: 1,
+            },
+            srgb: false,
+        }
+    }
+}
+
+/// Where a cutout material reads its opacity from.
+pub enum OpacitySource {
+    /// Red channel of `assets/textures/{name}_mask.png`
+    MaskTexture,
+    /// Alpha channel of an RGBA texture, such as a glTF base color texture
+    BaseColorAlpha(Texture),
+}
+
+/// How a material's surface combines with what is behind it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BlendMode {
+    /// Written to the G-buffer and lit by the deferred passes
+    Opaque,
+    /// Lit forward and blended over the scene by `opacity`, for glass and the like
+    Alpha,
+    /// Lit forward and added on top of the scene, for glows and particles
+    Additive,
+}
+
+impl BlendMode {
+    pub fn is_transparent(self) -> bool {
+        self != BlendMode::Opaque
+    }
 }
 
 pub struct Material {
+    name: String,
     albedo_ao_texture: Texture,
     surface_texture: Texture,
+    // Opacity in R, white unless the material is a cutout
+    mask_texture: Texture,
+    // Scaled by `emissive_factor`, white unless the material has its own
+    emissive_texture: Texture,
 
     pub albedo_ao: Option<Arc<ImageView<ImmutableImage>>>, // RGB = albedo, A = AO
     pub surface: Option<Arc<ImageView<ImmutableImage>>>, // RG = normal, B = roughness, A = metallic
+    pub mask: Option<Arc<ImageView<ImmutableImage>>>,    // R = opacity
+    pub emissive: Option<Arc<ImageView<ImmutableImage>>>,
+
+    pub blend_mode: BlendMode,
+    /// Coverage of transparent materials, ignored when opaque
+    pub opacity: f32,
+    /// Pixels whose mask is below this are discarded, `None` unless a cutout
+    pub alpha_cutoff: Option<f32>,
+    /// Light given off regardless of lighting, black for most materials
+    pub emissive_factor: [f32; 3],
+
+    // Built by the renderer once the textures are loaded
+    pub descriptor_set: Option<Arc<PersistentDescriptorSet>>,
+    // Set for the shadow pass, only built for cutouts
+    pub shadow_set: Option<Arc<PersistentDescriptorSet>>,
 }
 
 fn create_texture(path: &str) -> Texture {
@@ -50,6 +114,7 @@ fn create_texture(path: &str) -> Texture {
     Texture {
         data: image_data,
         dimensions: image_dimensions,
+        srgb: false,
     }
 }
 
@@ -58,12 +123,17 @@ fn load_texture(
     command_buffer: &mut AutoCommandBufferBuilder<PrimaryAutoCommandBuffer>,
     texture: &Texture,
 ) -> Arc<ImageView<ImmutableImage>> {
+    let format = if texture.srgb {
+        Format::R8G8B8A8_SRGB
+    } else {
+        Format::R8G8B8A8_UNORM
+    };
     let image = ImmutableImage::from_iter(
         allocator,
         texture.data.iter().cloned(),
         texture.dimensions,
         MipmapsCount::One,
-        Format::R8G8B8A8_UNORM,
+        format,
         command_buffer,
     )
     .unwrap();
@@ -76,15 +146,84 @@ impl Material {
         let albedo_ao_path = format!("assets/textures/{}_albedo_ao.png", name);
         let surface_path = format!("assets/textures/{}_material.png", name);
 
+        Self::from_textures(
+            name,
+            create_texture(&albedo_ao_path),
+            create_texture(&surface_path),
+        )
+    }
+
+    /// A material from textures already packed into the engine's layout,
+    /// see `albedo_ao` and `surface`.
+    pub fn from_textures(name: &str, albedo_ao_texture: Texture, surface_texture: Texture) -> Self {
         Self {
-            albedo_ao_texture: create_texture(&albedo_ao_path),
-            surface_texture: create_texture(&surface_path),
+            name: name.to_string(),
+            albedo_ao_texture,
+            surface_texture,
+            mask_texture: Texture::solid([255; 4]),
+            emissive_texture: Texture::solid([255; 4]),
 
             albedo_ao: None,
             surface: None,
+            mask: None,
+            emissive: None,
+
+            blend_mode: BlendMode::Opaque,
+            opacity: 1.0,
+            alpha_cutoff: None,
+            emissive_factor: [0.0; 3],
+
+            descriptor_set: None,
+            shadow_set: None,
         }
     }
 
+    /// Makes the material glow with `factor`, which can go above 1 to feed
+    /// bloom. Multiplied with `assets/textures/{name}_emissive.png` if it exists.
+    pub fn with_emissive(mut self, factor: [f32; 3]) -> Self {
+        let emissive_path = format!("assets/textures/{}_emissive.png", self.name);
+        if Path::new(&emissive_path).exists() {
+            self.emissive_texture = create_texture(&emissive_path);
+        }
+        self.emissive_factor = factor;
+        self
+    }
+
+    /// Makes the material glow with `factor` times `texture`.
+    pub fn with_emissive_texture(mut self, texture: Texture, factor: [f32; 3]) -> Self {
+        self.emissive_texture = texture;
+        self.emissive_factor = factor;
+        self
+    }
+
+    /// Makes the material a cutout: pixels whose opacity from `source` is
+    /// below `cutoff` are discarded, in the shadow pass as well.
+    pub fn with_cutout(mut self, source: OpacitySource, cutoff: f32) -> Self {
+        self.mask_texture = match source {
+            OpacitySource::MaskTexture => {
+                create_texture(&format!("assets/textures/{}_mask.png", self.name))
+            }
+            OpacitySource::BaseColorAlpha(texture) => Texture {
+                data: texture
+                    .data
+                    .chunks_exact(4)
+                    .flat_map(|texel| [texel[3], texel[3], texel[3], 255])
+                    .collect(),
+                dimensions: texture.dimensions,
+                srgb: false,
+            },
+        };
+        self.alpha_cutoff = Some(cutoff);
+        self
+    }
+
+    /// Makes the material transparent, drawn in the forward pass after lighting.
+    pub fn with_blend_mode(mut self, blend_mode: BlendMode, opacity: f32) -> Self {
+        self.blend_mode = blend_mode;
+        self.opacity = opacity;
+        self
+    }
+
     pub fn load(
         &mut self,
         allocator: &StandardMemoryAllocator,
@@ -100,6 +239,20 @@ impl Material {
             command_buffer,
             &self.surface_texture,
         ));
+        self.mask = Some(load_texture(allocator, command_buffer, &self.mask_texture));
+        self.emissive = Some(load_texture(
+            allocator,
+            command_buffer,
+            &self.emissive_texture,
+        ));
+    }
+
+    pub fn unpack_mask(&self) -> Arc<dyn ImageViewAbstract> {
+        self.mask.as_ref().expect("mask not loaded").clone() as Arc<dyn ImageViewAbstract>
+    }
+
+    pub fn unpack_emissive(&self) -> Arc<dyn ImageViewAbstract> {
+        self.emissive.as_ref().expect("emissive not loaded").clone() as Arc<dyn ImageViewAbstract>
     }
 
     pub fn unpack(&self) -> (Arc<dyn ImageViewAbstract>, Arc<dyn ImageViewAbstract>) {
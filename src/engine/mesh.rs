@@ -1,9 +1,19 @@
 #![allow(dead_code)]
 
+use std::collections::BTreeMap;
+use std::sync::Arc;
+
 use bytemuck::{Pod, Zeroable};
 use nalgebra_glm::{TMat4, identity, pi, rotate_normalized_axis, vec3};
 use once_cell::sync::Lazy;
-use vulkano::image::ImageDimensions;
+
+use crate::engine::{Bounds, Handle, material::Material};
+use vulkano::{
+    buffer::{BufferUsage, DeviceLocalBuffer},
+    command_buffer::{AutoCommandBufferBuilder, PrimaryAutoCommandBuffer},
+    image::ImageDimensions,
+    memory::allocator::StandardMemoryAllocator,
+};
 
 #[repr(C)]
 #[derive(Clone, Copy, Debug, Default, Zeroable, Pod)]
@@ -69,10 +79,45 @@ pub struct Build {
     pub indices: Vec<u32>,
 }
 
+/// One level of detail of a mesh, a range of its index buffer.
+#[derive(Clone, Copy, Debug)]
+pub struct MeshLod {
+    pub first_index: u32,
+    pub index_count: u32,
+    /// Screen size, as a fraction of the screen height, below which the next
+    /// level is used instead
+    pub min_screen_size: f32,
+}
+
+/// Part of a mesh drawn with its own material, such as the glass of a car.
+#[derive(Clone, Debug)]
+pub struct Submesh {
+    /// The submesh's part of every level in `Mesh::lods`, possibly empty
+    pub lods: Vec<MeshLod>,
+    /// Material used unless the entity has its own, `None` to leave it to the
+    /// entity
+    pub material: Option<Handle<Material>>,
+    // Index of the glTF material the submesh was loaded with
+    gltf_material: Option<usize>,
+}
+
 pub struct Mesh {
     pub build: Build,
+    /// Box around every vertex, used to cull instances outside the camera
+    pub bounds: Bounds,
+    /// Levels of detail from the most detailed, always at least one, each
+    /// covering every submesh
+    pub lods: Vec<MeshLod>,
+    /// Material slots of the mesh, drawn in separate batches
+    pub submeshes: Vec<Submesh>,
+
+    pub vertex_buffer: Option<Arc<DeviceLocalBuffer<[NormalVertex]>>>,
+    pub index_buffer: Option<Arc<DeviceLocalBuffer<[u32]>>>,
 }
 
+// Screen size at which LOD0 hands over to LOD1, halved for every further level
+const LOD0_MIN_SCREEN_SIZE: f32 = 0.25;
+
 static DEFAULT_COLOR: [f32; 3] = [1.0, 0.35, 0.137];
 static DEFAULT_ROTATION: Lazy<TMat4<f32>> = Lazy::new(|| {
     let default = identity();
@@ -83,61 +128,324 @@ static DEFAULT_ROTATION: Lazy<TMat4<f32>> = Lazy::new(|| {
 });
 
 impl Mesh {
+    /// Loads every mesh in the file. Meshes named with a `_LOD<n>` suffix, such
+    /// as `Cart_LOD1`, become level `n`; meshes without a suffix are level 0.
+    /// Primitives sharing a glTF material become one submesh on every level.
     pub fn new(file_path: &str) -> Mesh {
         let mesh_path = format!("assets/meshes/{}.glb", file_path);
         let (gltf, buffers, _) = gltf::import(mesh_path).expect("Failed to open glTF");
 
         let mut vertices: Vec<NormalVertex> = Vec::new();
-        let mut indices = Vec::new();
+        let mut materials: Vec<Option<usize>> = Vec::new();
+        // Indices of every submesh, per level
+        let mut lod_indices: BTreeMap<usize, Vec<Vec<u32>>> = BTreeMap::new();
 
         for mesh in gltf.meshes() {
+            let level = lod_indices
+                .entry(mesh.name().map_or(0, lod_level))
+                .or_default();
+
             for primitive in mesh.primitives() {
-                let reader = primitive.reader(|buffer| Some(&buffers[buffer.index()]));
-
-                // Keep in mind because glTF uses diff coord system than Vulkan, we need to flip Z axis
-                let positions: Vec<[f32; 3]> = reader
-                    .read_positions()
-                    .expect("Mesh has no POSITION attribute")
-                    .map(|[x, y, z]| [x, y, -z])
-                    .collect();
-                let normals: Vec<[f32; 3]> = if let Some(iter) = reader.read_normals() {
-                    iter.map(|[x, y, z]| [x, y, -z]).collect()
-                } else {
-                    vec![[0.0, 1.0, 0.0]; positions.len()]
+                let material = primitive.material().index();
+                let submesh = match materials.iter().position(|other| *other == material) {
+                    Some(submesh) => submesh,
+                    None => {
+                        materials.push(material);
+                        materials.len() - 1
+                    }
                 };
+                if level.len() <= submesh {
+                    level.resize_with(submesh + 1, Vec::new);
+                }
+                read_primitive(&primitive, &buffers, &mut vertices, &mut level[submesh]);
+            }
+        }
 
-                let tangents: Vec<[f32; 4]> = if let Some(iter) = reader.read_tangents() {
-                    iter.map(|[x, y, z, w]| [x, y, -z, w]).collect()
-                } else {
-                    vec![[1.0, 0.0, 0.0, 1.0]; positions.len()]
-                };
+        Self::from_levels(vertices, lod_indices, materials)
+    }
 
-                let uvs: Vec<[f32; 2]> = reader
-                    .read_tex_coords(0)
-                    .map(|t| t.into_f32().collect())
-                    .unwrap_or_else(|| vec![[0.0, 0.0]; positions.len()]);
-
-                let start_index = vertices.len() as u32;
-                for i in 0..positions.len() {
-                    vertices.push(NormalVertex {
-                        position: positions[i],
-                        normal: normals[i],
-                        tangent: tangents[i],
-                        uv: uvs[i],
-                    });
-                }
+    /// Loads a mesh of a glTF file already imported by the caller as one level
+    /// of detail, with a submesh per primitive, without transforming it.
+    pub fn from_gltf_mesh(mesh: &gltf::Mesh, buffers: &[gltf::buffer::Data]) -> Mesh {
+        let mut vertices = Vec::new();
+        let mut indices = Vec::new();
+        let mut materials = Vec::new();
+        for primitive in mesh.primitives() {
+            let mut primitive_indices = Vec::new();
+            read_primitive(&primitive, buffers, &mut vertices, &mut primitive_indices);
+            indices.push(primitive_indices);
+            materials.push(primitive.material().index());
+        }
 
-                // Add indices
-                if let Some(read_indices) = reader.read_indices() {
-                    indices.extend(read_indices.into_u32().map(|i| i + start_index));
-                } else {
-                    indices.extend((0..positions.len() as u32).map(|i| i + start_index));
-                }
+        Self::from_levels(vertices, BTreeMap::from([(0, indices)]), materials)
+    }
+
+    fn from_levels(
+        vertices: Vec<NormalVertex>,
+        lod_indices: BTreeMap<usize, Vec<Vec<u32>>>,
+        materials: Vec<Option<usize>>,
+    ) -> Mesh {
+        let bounds = Bounds::from_points(vertices.iter().map(|vertex| vertex.position));
+
+        let mut submeshes: Vec<Submesh> = materials
+            .into_iter()
+            .map(|gltf_material| Submesh {
+                lods: Vec::new(),
+                material: None,
+                gltf_material,
+            })
+            .collect();
+
+        // Levels are laid out back to back in one index buffer, each one's
+        // submeshes back to back inside it
+        let mut indices = Vec::new();
+        let mut lods = Vec::new();
+        for (level, mut level_indices) in lod_indices.into_values().enumerate() {
+            let first_index = indices.len() as u32;
+            let min_screen_size = LOD0_MIN_SCREEN_SIZE * 0.5f32.powi(level as i32);
+
+            level_indices.resize_with(submeshes.len(), Vec::new);
+            for (submesh, submesh_indices) in submeshes.iter_mut().zip(level_indices) {
+                submesh.lods.push(MeshLod {
+                    first_index: indices.len() as u32,
+                    index_count: submesh_indices.len() as u32,
+                    min_screen_size,
+                });
+                indices.extend(submesh_indices);
             }
+
+            lods.push(MeshLod {
+                first_index,
+                index_count: indices.len() as u32 - first_index,
+                min_screen_size,
+            });
+        }
+        if lods.is_empty() {
+            lods.push(MeshLod {
+                first_index: 0,
+                index_count: 0,
+                min_screen_size: 0.0,
+            });
         }
 
         Mesh {
             build: Build { vertices, indices },
+            bounds,
+            lods,
+            submeshes,
+
+            vertex_buffer: None,
+            index_buffer: None,
+        }
+    }
+
+    /// Makes every submesh loaded with a glTF material default to it, given
+    /// the file's `materials` in order.
+    pub fn with_materials(mut self, materials: &[Handle<Material>]) -> Self {
+        for submesh in self.submeshes.iter_mut() {
+            submesh.material = submesh
+                .gltf_material
+                .and_then(|index| materials.get(index).cloned());
+        }
+        self
+    }
+
+    /// Part of level `lod` drawn by `submesh`.
+    pub fn lod(&self, submesh: usize, lod: usize) -> &MeshLod {
+        &self.submeshes[submesh].lods[lod]
+    }
+
+    pub fn load(
+        &mut self,
+        allocator: &StandardMemoryAllocator,
+        command_buffer: &mut AutoCommandBufferBuilder<PrimaryAutoCommandBuffer>,
+    ) {
+        self.vertex_buffer = Some(
+            DeviceLocalBuffer::from_iter(
+                allocator,
+                self.build.vertices.iter().cloned(),
+                BufferUsage {
+                    vertex_buffer: true,
+                    ..BufferUsage::empty()
+                },
+                command_buffer,
+            )
+            .unwrap(),
+        );
+        self.index_buffer = Some(
+            DeviceLocalBuffer::from_iter(
+                allocator,
+                self.build.indices.iter().cloned(),
+                BufferUsage {
+                    index_buffer: true,
+                    ..BufferUsage::empty()
+                },
+                command_buffer,
+            )
+            .unwrap(),
+        );
+    }
+
+    /// Drops the GPU buffers made by `load`.
+    pub fn unload(&mut self) {
+        self.vertex_buffer = None;
+        self.index_buffer = None;
+    }
+
+    /// The level to draw an instance with that covers `screen_size` of the
+    /// screen height.
+    pub fn select_lod(&self, screen_size: f32) -> usize {
+        self.lods
+            .iter()
+            .position(|lod| screen_size >= lod.min_screen_size)
+            .unwrap_or(self.lods.len() - 1)
+    }
+
+    pub fn unpack(
+        &self,
+    ) -> (
+        Arc<DeviceLocalBuffer<[NormalVertex]>>,
+        Arc<DeviceLocalBuffer<[u32]>>,
+    ) {
+        (
+            self.vertex_buffer
+                .as_ref()
+                .expect("vertex buffer not loaded")
+                .clone(),
+            self.index_buffer
+                .as_ref()
+                .expect("index buffer not loaded")
+                .clone(),
+        )
+    }
+}
+
+/// Appends the vertices and indices of one glTF primitive, with its Z axis
+/// flipped, its indices offset past the vertices already in `vertices`.
+fn read_primitive(
+    primitive: &gltf::Primitive,
+    buffers: &[gltf::buffer::Data],
+    vertices: &mut Vec<NormalVertex>,
+    indices: &mut Vec<u32>,
+) {
+    let reader = primitive.reader(|buffer| Some(&buffers[buffer.index()]));
+
+    // Keep in mind because glTF uses diff coord system than Vulkan, we need to flip Z axis
+    let positions: Vec<[f32; 3]> = reader
+        .read_positions()
+        .expect("Mesh has no POSITION attribute")
+        .map(|[x, y, z]| [x, y, -z])
+        .collect();
+    let normals: Vec<[f32; 3]> = if let Some(iter) = reader.read_normals() {
+        iter.map(|[x, y, z]| [x, y, -z]).collect()
+    } else {
+        vec![[0.0, 1.0, 0.0]; positions.len()]
+    };
+
+    let tangents: Vec<[f32; 4]> = if let Some(iter) = reader.read_tangents() {
+        iter.map(|[x, y, z, w]| [x, y, -z, w]).collect()
+    } else {
+        vec![[1.0, 0.0, 0.0, 1.0]; positions.len()]
+    };
+
+    let uvs: Vec<[f32; 2]> = reader
+        .read_tex_coords(0)
+        .map(|t| t.into_f32().collect())
+        .unwrap_or_else(|| vec![[0.0, 0.0]; positions.len()]);
+
+    let start_index = vertices.len() as u32;
+    for i in 0..positions.len() {
+        vertices.push(NormalVertex {
+            position: positions[i],
+            normal: normals[i],
+            tangent: tangents[i],
+            uv: uvs[i],
+        });
+    }
+
+    // Add indices
+    if let Some(read_indices) = reader.read_indices() {
+        indices.extend(read_indices.into_u32().map(|i| i + start_index));
+    } else {
+        indices.extend((0..positions.len() as u32).map(|i| i + start_index));
+    }
+}
+
+/// Level of detail encoded in a glTF mesh name, e.g. 1 for `Cart_LOD1`.
+fn lod_level(name: &str) -> usize {
+    name.rsplit_once("_LOD")
+        .and_then(|(_, level)| level.parse().ok())
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A mesh with one triangle per submesh on each of `levels`
+    fn mesh(levels: &[usize], submeshes: usize) -> Mesh {
+        let vertices = vec![NormalVertex::default(); 3];
+        let lod_indices = levels
+            .iter()
+            .map(|level| (*level, vec![vec![0, 1, 2]; submeshes]))
+            .collect();
+        Mesh::from_levels(vertices, lod_indices, vec![None; submeshes])
+    }
+
+    #[test]
+    fn lod_level_reads_the_name_suffix() {
+        assert_eq!(lod_level("Cart"), 0);
+        assert_eq!(lod_level("Cart_LOD0"), 0);
+        assert_eq!(lod_level("Cart_LOD2"), 2);
+        assert_eq!(lod_level("Cart_LOD1_LOD3"), 3);
+        assert_eq!(lod_level("Cart_LOD"), 0);
+        assert_eq!(lod_level("Cart_LODx"), 0);
+    }
+
+    #[test]
+    fn levels_halve_their_min_screen_size() {
+        let mesh = mesh(&[0, 1, 2], 1);
+        let sizes: Vec<f32> = mesh.lods.iter().map(|lod| lod.min_screen_size).collect();
+        assert_eq!(sizes, [0.25, 0.125, 0.0625]);
+    }
+
+    #[test]
+    fn select_lod_picks_the_first_level_covering_the_size() {
+        let mesh = mesh(&[0, 1, 2], 1);
+        assert_eq!(mesh.select_lod(1.0), 0);
+        assert_eq!(mesh.select_lod(0.25), 0);
+        assert_eq!(mesh.select_lod(0.2), 1);
+        assert_eq!(mesh.select_lod(0.1), 2);
+        // Smaller than every level still draws the last one
+        assert_eq!(mesh.select_lod(0.001), 2);
+    }
+
+    #[test]
+    fn missing_levels_are_skipped() {
+        let mesh = mesh(&[0, 3], 1);
+        assert_eq!(mesh.lods.len(), 2);
+        assert_eq!(mesh.select_lod(0.2), 1);
+    }
+
+    #[test]
+    fn levels_cover_every_submesh_back_to_back() {
+        let mesh = mesh(&[0, 1], 2);
+        let mut ranges = Vec::new();
+        for lod in 0..2 {
+            for submesh in 0..2 {
+                let lod = mesh.lod(submesh, lod);
+                ranges.push((lod.first_index, lod.index_count));
+            }
         }
+        assert_eq!(ranges, [(0, 3), (3, 3), (6, 3), (9, 3)]);
+        assert_eq!((mesh.lods[1].first_index, mesh.lods[1].index_count), (6, 6));
+    }
+
+    #[test]
+    fn meshes_without_levels_have_one_empty_level() {
+        let mesh = mesh(&[], 0);
+        assert_eq!(mesh.lods.len(), 1);
+        assert_eq!(mesh.lods[0].index_count, 0);
+        assert_eq!(mesh.select_lod(0.0), 0);
     }
 }
@@ -1,17 +1,27 @@
+mod asset_loader;
+mod asset_server;
+mod draw_list;
 mod ecs;
 mod engine;
+mod frustum;
+mod gltf_material;
+mod gltf_scene;
 mod input_manager;
 mod instance;
 mod material;
 mod mesh;
 mod skybox;
 
-pub use engine::Engine;
+pub use asset_server::{AssetServer, Assets, Handle};
+pub use engine::{CullStats, Engine};
 pub use input_manager::InputManager;
-pub use mesh::Mesh;
+pub use mesh::{Mesh, MeshLod};
 
-pub use instance::DrawInstance;
-pub use material::Material;
+pub use draw_list::{DrawBatch, DrawList, TransparentDraw};
+pub use ecs::{PointLight, SpotLight};
+pub use frustum::{Bounds, Frustum};
+pub use instance::{DrawInstance, LightInstance};
+pub use material::{BlendMode, Material, OpacitySource, Texture};
 pub use mesh::DummyVertex;
 pub use mesh::NormalVertex;
 pub use skybox::Skybox;
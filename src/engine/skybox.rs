@@ -4,7 +4,7 @@ use image::ImageReader;
 use vulkano::{
     command_buffer::{AutoCommandBufferBuilder, PrimaryAutoCommandBuffer},
     format::Format,
-    image::{ImageDimensions, ImmutableImage, MipmapsCount, view::ImageView},
+    image::{AttachmentImage, ImageDimensions, ImmutableImage, MipmapsCount, view::ImageView},
     memory::allocator::StandardMemoryAllocator,
 };
 
@@ -13,6 +13,12 @@ pub struct Skybox {
     width: u32,
     height: u32,
     pub image_view: Option<Arc<ImageView<ImmutableImage>>>,
+
+    // Image-based lighting inputs, baked by the renderer after loading
+    pub irradiance_view: Option<Arc<ImageView<AttachmentImage>>>,
+    /// Mip per roughness level, from mirror-like to fully rough
+    pub prefiltered_view: Option<Arc<ImageView<ImmutableImage>>>,
+    pub brdf_lut_view: Option<Arc<ImageView<AttachmentImage>>>,
 }
 
 impl Skybox {
@@ -34,6 +40,22 @@ impl Skybox {
             width,
             height,
             image_view: None,
+            irradiance_view: None,
+            prefiltered_view: None,
+            brdf_lut_view: None,
+        }
+    }
+
+    /// A skybox of a single color, lighting everything evenly.
+    pub fn solid(color: [f32; 4]) -> Self {
+        Self {
+            pixels_data: vec![color],
+            width: 1,
+            height: 1,
+            image_view: None,
+            irradiance_view: None,
+            prefiltered_view: None,
+            brdf_lut_view: None,
         }
     }
 
@@ -48,11 +70,12 @@ impl Skybox {
             array_layers: 1,
         };
 
+        // Mips let the lighting precomputation read a filtered environment
         let image = ImmutableImage::from_iter(
             allocator,
             self.pixels_data.iter().cloned(),
             dimensions,
-            MipmapsCount::One,
+            MipmapsCount::Log2,
             Format::R32G32B32A32_SFLOAT,
             command_buffer,
         )
@@ -0,0 +1,342 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use image::{Rgba, RgbaImage};
+use nalgebra_glm::{look_at, vec3};
+use vulkano::image::ImageDimensions;
+use vulkano::sync::{self, GpuFuture};
+
+use crate::engine::{BlendMode, Engine, Material, OpacitySource, Texture};
+use crate::render_frame;
+use crate::system::{Capture, CaptureTarget, System};
+
+const REFERENCE_DIRECTORY: &str = "assets/golden";
+const OUTPUT_DIRECTORY: &str = "target/golden";
+const GOLDEN_EXTENT: [u32; 2] = [512, 512];
+const CHECKER_SIZE: u32 = 64;
+const CHECKER_CELL: u32 = 8;
+
+/// A few entities in front of a fixed camera.
+struct GoldenScene {
+    name: &'static str,
+    skybox: &'static str,
+    eye: [f32; 3],
+    target: [f32; 3],
+    // Largest per-channel difference that still counts as matching
+    tolerance: u8,
+    // Fraction of pixels allowed to exceed the tolerance
+    max_mismatch: f32,
+    // Loads the scene's assets and spawns its entities
+    spawn: fn(&mut Engine),
+}
+
+const SCENES: &[GoldenScene] = &[
+    GoldenScene {
+        name: "material_cube",
+        skybox: "assets/HDR/forest.exr",
+        eye: [0.0, -1.5, 0.1],
+        target: [0.0, -1.5, 0.0],
+        tolerance: 4,
+        max_mismatch: 0.001,
+        spawn: |engine| {
+            let material = engine.assets.load_material("material_cube");
+            let mesh = engine.assets.load_mesh("Material_Test");
+            engine.spawn_instance(mesh, material, vec3(0.0, -1.5, -3.0));
+        },
+    },
+    GoldenScene {
+        name: "default_sphere",
+        skybox: "assets/HDR/forest.exr",
+        eye: [0.0, 0.0, 0.1],
+        target: [0.0, 0.0, 0.0],
+        tolerance: 4,
+        max_mismatch: 0.001,
+        spawn: |engine| {
+            let material = engine.assets.load_material("default");
+            let mesh = engine.assets.load_mesh("Sphere");
+            engine.spawn_instance(mesh, material, vec3(0.0, 0.0, -3.0));
+        },
+    },
+    GoldenScene {
+        name: "transparent_plane",
+        skybox: "assets/HDR/forest.exr",
+        eye: [0.0, 0.0, 0.1],
+        target: [0.0, 0.0, 0.0],
+        tolerance: 4,
+        max_mismatch: 0.001,
+        spawn: |engine| {
+            // An opaque sphere seen through a half transparent plane
+            let sphere = engine.assets.load_mesh("Sphere");
+            let material = engine.assets.load_material("default");
+            engine.spawn_instance(sphere, material, vec3(0.0, 0.0, -5.0));
+
+            let plane = engine.assets.load_mesh("Plane");
+            let glass = engine
+                .assets
+                .load_transparent_material("default", BlendMode::Alpha, 0.5);
+            engine.spawn_instance(plane, glass, vec3(0.0, 0.0, -3.0));
+        },
+    },
+    GoldenScene {
+        name: "masked_quad",
+        skybox: "assets/HDR/forest.exr",
+        eye: [0.0, 0.0, 0.1],
+        target: [0.0, 0.0, 0.0],
+        tolerance: 4,
+        max_mismatch: 0.001,
+        spawn: |engine| {
+            // A sphere seen through the holes of a checkered cutout
+            let sphere = engine.assets.load_mesh("Sphere");
+            let material = engine.assets.load_material("default");
+            engine.spawn_instance(sphere, material, vec3(0.0, 0.0, -5.0));
+
+            let plane = engine.assets.load_mesh("Plane");
+            let checker = engine.assets.materials.load_with("#golden_checker", || {
+                Material::new("default")
+                    .with_cutout(OpacitySource::BaseColorAlpha(checker_texture()), 0.5)
+            });
+            engine.spawn_instance(plane, checker, vec3(0.0, 0.0, -3.0));
+        },
+    },
+    GoldenScene {
+        name: "emissive_sphere",
+        skybox: "assets/HDR/forest.exr",
+        eye: [0.0, 0.0, 0.1],
+        target: [0.0, 0.0, 0.0],
+        tolerance: 4,
+        max_mismatch: 0.001,
+        spawn: |engine| {
+            // Bright enough to bloom over the skybox around it
+            let material = engine
+                .assets
+                .load_emissive_material("default", [4.0, 1.5, 0.5]);
+            let mesh = engine.assets.load_mesh("Sphere");
+            engine.spawn_instance(mesh, material, vec3(0.0, 0.0, -3.0));
+        },
+    },
+    GoldenScene {
+        name: "gltf_cart",
+        skybox: "assets/HDR/forest.exr",
+        eye: [4.0, -3.0, 0.0],
+        target: [0.0, -1.0, -4.0],
+        tolerance: 4,
+        max_mismatch: 0.001,
+        spawn: |engine| {
+            // Drawn with the materials and textures embedded in the file
+            let mesh = engine.assets.load_gltf_model("Cart");
+            engine.spawn_car(mesh, vec3(0.0, 0.0, -4.0));
+        },
+    },
+];
+
+struct Comparison {
+    mismatched: usize,
+    max_difference: u8,
+    diff: RgbaImage,
+}
+
+/// Renders every scene headlessly and compares it against its reference image.
+/// With `update` set the references are overwritten instead. Returns whether
+/// all scenes passed, scenes without a reference are skipped.
+pub fn run(update: bool) -> bool {
+    let mut system = System::new_headless(GOLDEN_EXTENT);
+    let mut passed = true;
+
+    for scene in SCENES {
+        let reference_path = Path::new(REFERENCE_DIRECTORY).join(format!("{}.png", scene.name));
+        // New scenes only get compared once their reference is committed
+        if !update && !reference_path.exists() {
+            println!(
+                "[golden] {}: skipped, missing reference {}, run --golden-update to create it",
+                scene.name,
+                reference_path.display()
+            );
+            continue;
+        }
+
+        let capture = render_scene(&mut system, scene);
+        let rendered = capture
+            .frame(CaptureTarget::FinalColor)
+            .expect("Headless capture is missing the final color");
+
+        if update {
+            fs::create_dir_all(REFERENCE_DIRECTORY).unwrap();
+            match rendered.save(&reference_path) {
+                Ok(()) => println!(
+                    "[golden] {}: updated {}",
+                    scene.name,
+                    reference_path.display()
+                ),
+                Err(e) => {
+                    println!(
+                        "[golden] {}: FAILED, cannot write {} ({})",
+                        scene.name,
+                        reference_path.display(),
+                        e
+                    );
+                    passed = false;
+                }
+            }
+            continue;
+        }
+
+        let reference = match image::open(&reference_path) {
+            Ok(reference) => reference.to_rgba8(),
+            Err(e) => {
+                println!(
+                    "[golden] {}: FAILED, cannot open {} ({})",
+                    scene.name,
+                    reference_path.display(),
+                    e
+                );
+                passed = false;
+                continue;
+            }
+        };
+
+        let Some(texels) = rendered.to_rgba8() else {
+            println!(
+                "[golden] {}: FAILED, cannot read back frames of format {:?}",
+                scene.name, rendered.format
+            );
+            passed = false;
+            continue;
+        };
+        let actual = RgbaImage::from_raw(rendered.width, rendered.height, texels)
+            .expect("Frame data does not match its dimensions");
+
+        if actual.dimensions() != reference.dimensions() {
+            println!(
+                "[golden] {}: FAILED, rendered {:?} but reference is {:?}",
+                scene.name,
+                actual.dimensions(),
+                reference.dimensions()
+            );
+            passed = false;
+            continue;
+        }
+
+        let comparison = compare(&actual, &reference, scene.tolerance);
+        let pixel_count = (actual.width() * actual.height()) as f32;
+        let mismatch = comparison.mismatched as f32 / pixel_count;
+
+        if mismatch <= scene.max_mismatch {
+            println!(
+                "[golden] {}: ok ({} pixels differ, max difference {})",
+                scene.name, comparison.mismatched, comparison.max_difference
+            );
+        } else {
+            let (actual_path, diff_path) = output_paths(scene.name);
+            fs::create_dir_all(OUTPUT_DIRECTORY).unwrap();
+            actual.save(&actual_path).unwrap();
+            comparison.diff.save(&diff_path).unwrap();
+
+            println!(
+                "[golden] {}: FAILED, {} pixels ({:.3}%) differ by more than {}, max difference {}",
+                scene.name,
+                comparison.mismatched,
+                mismatch * 100.0,
+                scene.tolerance,
+                comparison.max_difference
+            );
+            println!(
+                "[golden] {}: wrote {} and {}",
+                scene.name,
+                actual_path.display(),
+                diff_path.display()
+            );
+            passed = false;
+        }
+    }
+
+    passed
+}
+
+fn render_scene(system: &mut System, scene: &GoldenScene) -> Capture {
+    let mut engine = Engine::with_skybox(scene.skybox);
+    (scene.spawn)(&mut engine);
+
+    system.preload_assets(&mut engine);
+    system.set_view(&look_at(
+        &scene.eye.into(),
+        &scene.target.into(),
+        &vec3(0.0, 1.0, 0.0),
+    ));
+
+    let mut previous_frame_end =
+        Some(Box::new(sync::now(system.device.clone())) as Box<dyn GpuFuture>);
+
+    let capture = render_frame(system, &mut engine, &mut previous_frame_end)
+        .expect("Headless frame did not produce a capture");
+    println!(
+        "[golden] {}: {} drawn, {} culled",
+        scene.name, engine.cull_stats.drawn, engine.cull_stats.culled
+    );
+
+    capture
+}
+
+/// Compares two images channel by channel. The diff image shows the rendered
+/// image darkened, with pixels outside the tolerance marked in red.
+fn compare(actual: &RgbaImage, reference: &RgbaImage, tolerance: u8) -> Comparison {
+    let mut mismatched = 0;
+    let mut max_difference = 0;
+    let mut diff = RgbaImage::new(actual.width(), actual.height());
+
+    for (x, y, actual_pixel) in actual.enumerate_pixels() {
+        let reference_pixel = reference.get_pixel(x, y);
+        let difference = actual_pixel
+            .0
+            .iter()
+            .zip(reference_pixel.0.iter())
+            .map(|(a, b)| a.abs_diff(*b))
+            .max()
+            .unwrap_or(0);
+
+        max_difference = max_difference.max(difference);
+
+        let diff_pixel = if difference > tolerance {
+            mismatched += 1;
+            Rgba([255, 0, 0, 255])
+        } else {
+            let [r, g, b, _] = actual_pixel.0;
+            Rgba([r / 4, g / 4, b / 4, 255])
+        };
+        diff.put_pixel(x, y, diff_pixel);
+    }
+
+    Comparison {
+        mismatched,
+        max_difference,
+        diff,
+    }
+}
+
+/// White texels whose alpha alternates between opaque and clear cells.
+fn checker_texture() -> Texture {
+    let data = (0..CHECKER_SIZE * CHECKER_SIZE)
+        .flat_map(|texel| {
+            let (x, y) = (texel % CHECKER_SIZE, texel / CHECKER_SIZE);
+            let opaque = (x / CHECKER_CELL + y / CHECKER_CELL) % 2 == 0;
+            [255, 255, 255, if opaque { 255 } else { 0 }]
+        })
+        .collect();
+
+    Texture {
+        data,
+        dimensions: ImageDimensions::Dim2d {
+            width: CHECKER_SIZE,
+            height: CHECKER_SIZE,
+            array_layers: 1,
+        },
+        srgb: false,
+    }
+}
+
+fn output_paths(name: &str) -> (PathBuf, PathBuf) {
+    let directory = Path::new(OUTPUT_DIRECTORY);
+    (
+        directory.join(format!("{}_actual.png", name)),
+        directory.join(format!("{}_diff.png", name)),
+    )
+}
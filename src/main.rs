@@ -1,25 +1,30 @@
 mod engine;
+mod golden;
 mod system;
 
-use system::System;
+use system::{Capture, CaptureTarget, DirectionalLight, System};
 
 use vulkano::sync;
 use vulkano::sync::GpuFuture;
 
 use winit::event::ElementState;
 use winit::event::KeyboardInput;
+use winit::event::VirtualKeyCode;
 use winit::event::{Event, WindowEvent};
 use winit::event_loop::{ControlFlow, EventLoop};
 
 use nalgebra_glm::{look_at, vec3};
 
+use std::path::Path;
 use std::sync::Arc;
 use std::sync::Mutex;
 use std::thread;
+use std::time::{SystemTime, UNIX_EPOCH};
 
 use crate::engine::Engine;
 
 const ENGINE_TICK_RATE: f32 = 60.0;
+const CAPTURE_DIRECTORY: &str = "captures";
 
 fn main() {
     // Just to make debug and release files work with debugger
@@ -32,6 +37,18 @@ fn main() {
         }
     }
 
+    // --golden renders the reference scenes headlessly and compares them,
+    // --golden-update overwrites the references with the new renders
+    let args: Vec<String> = std::env::args().collect();
+    if args
+        .iter()
+        .any(|a| a == "--golden" || a == "--golden-update")
+    {
+        let update = args.iter().any(|a| a == "--golden-update");
+        let passed = golden::run(update);
+        std::process::exit(if passed { 0 } else { 1 });
+    }
+
     let event_loop = EventLoop::new();
     let mut system = System::new(&event_loop);
 
@@ -44,12 +61,8 @@ fn main() {
         &vec3(0.0, 1.0, 0.0),
     ));
 
-    {
-        let mut e = engine.lock().unwrap();
-        e.init();
-
-        system.preload_textures(&mut e);
-    }
+    // Assets load in the background, drawn as placeholders until they're ready
+    engine.lock().unwrap().init();
 
     let mut previous_frame_end =
         Some(Box::new(sync::now(system.device.clone())) as Box<dyn GpuFuture>);
@@ -67,9 +80,8 @@ fn main() {
         }
     });
 
-    //let sun_light = DirectionalLight::new([100.0, -100.0, 100.0, 1.0], [1.0, 1.0, 1.0]);
-
     let engine_for_render = engine.clone();
+    let mut shown_title = String::new();
     event_loop.run(move |event, _, control_flow| match event {
         Event::WindowEvent { event, .. } => match event {
             WindowEvent::KeyboardInput {
@@ -81,6 +93,50 @@ fn main() {
                     },
                 ..
             } => {
+                // F12 captures the final image, F11 the G-buffer along with it,
+                // F10 colors the sunlight by shadow cascade, F9 cycles the
+                // tonemapping operator, F8 toggles auto exposure, F7 bloom, F6
+                // SSAO, F5 cycles the anti-aliasing mode and F4 toggles GPU
+                // driven drawing
+                if state == ElementState::Pressed {
+                    match keycode {
+                        VirtualKeyCode::F12 => system.request_capture(&[CaptureTarget::FinalColor]),
+                        VirtualKeyCode::F11 => system.request_capture(&CaptureTarget::ALL),
+                        VirtualKeyCode::F10 => {
+                            let mut settings = system.shadow_settings().clone();
+                            settings.debug_cascades = !settings.debug_cascades;
+                            system.set_shadow_settings(settings);
+                        }
+                        VirtualKeyCode::F9 => {
+                            let mut settings = system.tonemap_settings().clone();
+                            settings.operator = settings.operator.next();
+                            system.set_tonemap_settings(settings);
+                        }
+                        VirtualKeyCode::F8 => {
+                            let mut settings = system.tonemap_settings().clone();
+                            settings.auto_exposure = !settings.auto_exposure;
+                            system.set_tonemap_settings(settings);
+                        }
+                        VirtualKeyCode::F7 => {
+                            let mut settings = system.bloom_settings().clone();
+                            settings.enabled = !settings.enabled;
+                            system.set_bloom_settings(settings);
+                        }
+                        VirtualKeyCode::F6 => {
+                            let mut settings = system.ssao_settings().clone();
+                            settings.enabled = !settings.enabled;
+                            system.set_ssao_settings(settings);
+                        }
+                        VirtualKeyCode::F5 => {
+                            let mut settings = system.anti_aliasing_settings().clone();
+                            settings.mode = settings.mode.next();
+                            system.set_anti_aliasing_settings(settings);
+                        }
+                        VirtualKeyCode::F4 => system.set_gpu_driven(!system.gpu_driven()),
+                        _ => {}
+                    }
+                }
+
                 let mut e = engine_for_render.lock().unwrap();
                 match state {
                     ElementState::Pressed => e.input_manager.press_key(keycode),
@@ -109,25 +165,99 @@ fn main() {
                 system.set_view(&e.camera.view);
             }
 
-            system.start();
+            let capture = render_frame(&mut system, &mut e, &mut previous_frame_end);
 
-            let draw_calls = e.get_draw_calls();
-            for ((mesh_id, material_id), instances) in draw_calls {
-                if let Some(mesh_data) = e.meshes.get(&mesh_id) {
-                    if let Some(material_data) = e.materials.get(&material_id) {
-                        let material = material_data.read().unwrap();
-                        let mesh = Arc::clone(&mesh_data);
-                        system.geometry(instances, material, mesh);
+            // The title shows how many entities culling kept this frame. The
+            // counts only exist when the CPU culls, the GPU's stay on the GPU
+            let title = if system.gpu_driven() {
+                "rust-game (GPU culling)".to_string()
+            } else {
+                format!(
+                    "rust-game ({} drawn, {} culled)",
+                    e.cull_stats.drawn, e.cull_stats.culled
+                )
+            };
+            if title != shown_title {
+                system.set_window_title(&title);
+                shown_title = title;
+            }
+
+            if let Some(capture) = capture {
+                let timestamp = SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .unwrap()
+                    .as_millis();
+                match capture.save(
+                    Path::new(CAPTURE_DIRECTORY),
+                    &format!("capture_{}", timestamp),
+                ) {
+                    Ok(paths) => {
+                        for path in paths {
+                            println!("Saved capture to {}", path.display());
+                        }
                     }
+                    Err(e) => println!("Failed to save capture: {:?}", e),
                 }
             }
-
-            system.start_lighting();
-            system.skybox(&e.skybox);
-            system.ambient(&e.skybox);
-            //system.directional(&sun_light);
-            system.finish(&mut previous_frame_end);
         }
         _ => (),
     });
 }
+
+/// Records and submits one frame of the deferred pipeline for everything in
+/// `engine`. Shared by the window loop and the golden image harness.
+pub fn render_frame(
+    system: &mut System,
+    engine: &mut Engine,
+    previous_frame_end: &mut Option<Box<dyn GpuFuture>>,
+) -> Option<Capture> {
+    let sun_light = DirectionalLight::new([100.0, -100.0, 100.0, 1.0], [1.0, 1.0, 1.0]);
+
+    let freed_meshes = engine.assets.free_unused();
+    system.free_meshes(&freed_meshes);
+    engine.assets.update();
+    system.upload_assets(engine, previous_frame_end);
+    system.start();
+
+    // GPU driven frames leave culling to the compute pass
+    let gpu_driven = system.gpu_driven();
+    let light_view_projections = system.update_cascades(&sun_light);
+    let instance_count = engine.prepare_draw_calls(
+        system.view(),
+        system.projection(),
+        &light_view_projections,
+        !gpu_driven,
+    );
+    system.write_instances(instance_count, |instances| {
+        engine.write_draw_instances(instances)
+    });
+
+    if gpu_driven {
+        system.cull_instances(engine);
+    }
+    system.shadow(engine);
+    system.start_geometry();
+
+    if gpu_driven {
+        system.geometry_indirect(engine);
+    } else {
+        for batch in engine.draw_list.batches() {
+            if let Some(mesh_data) = engine.assets.meshes.get(batch.mesh_id) {
+                if let Some(material_data) = engine.assets.materials.get(batch.material_id) {
+                    let material = material_data.read().unwrap();
+                    let mesh = mesh_data.read().unwrap();
+                    system.geometry(batch, material, mesh);
+                }
+            }
+        }
+    }
+
+    system.start_lighting();
+    let skybox = engine.skybox();
+    system.skybox(&skybox.read().unwrap());
+    system.ambient(&skybox.read().unwrap());
+    system.directional(&sun_light);
+    system.local_lights(&engine.gather_lights());
+    system.transparent(engine, &sun_light);
+    system.finish(previous_frame_end)
+}
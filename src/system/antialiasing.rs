@@ -0,0 +1,333 @@
+use std::sync::Arc;
+
+use vulkano::buffer::{BufferUsage, CpuAccessibleBuffer};
+use vulkano::command_buffer::{AutoCommandBufferBuilder, PrimaryAutoCommandBuffer};
+use vulkano::descriptor_set::allocator::StandardDescriptorSetAllocator;
+use vulkano::descriptor_set::{PersistentDescriptorSet, WriteDescriptorSet};
+use vulkano::device::Device;
+use vulkano::image::view::ImageView;
+use vulkano::image::{AttachmentImage, ImageUsage};
+use vulkano::memory::allocator::StandardMemoryAllocator;
+use vulkano::pipeline::{GraphicsPipeline, Pipeline};
+use vulkano::render_pass::{Framebuffer, FramebufferCreateInfo, RenderPass, Subpass};
+use vulkano::sampler::{Filter, Sampler, SamplerAddressMode, SamplerCreateInfo};
+
+use crate::system::fullscreen::FullscreenPass;
+use crate::system::tonemap::HDR_FORMAT;
+
+mod fxaa_frag {
+    vulkano_shaders::shader! {
+        ty: "fragment",
+        path: "src/system/shaders/fxaa.frag",
+    }
+}
+
+mod taa_frag {
+    vulkano_shaders::shader! {
+        ty: "fragment",
+        path: "src/system/shaders/taa.frag",
+        types_meta: {
+            use bytemuck::{Pod, Zeroable};
+
+            #[derive(Clone, Copy, Zeroable, Pod)]
+        },
+    }
+}
+
+// Length of the Halton sequence the projection is jittered along
+const JITTER_SAMPLES: u32 = 8;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AntiAliasingMode {
+    None,
+    /// Single frame edge blur, cheap but doesn't help with shimmering
+    Fxaa,
+    /// Jitters the projection and accumulates frames along motion vectors
+    Taa,
+}
+
+impl AntiAliasingMode {
+    /// The mode after this one, wrapping around.
+    pub fn next(self) -> AntiAliasingMode {
+        match self {
+            AntiAliasingMode::None => AntiAliasingMode::Fxaa,
+            AntiAliasingMode::Fxaa => AntiAliasingMode::Taa,
+            AntiAliasingMode::Taa => AntiAliasingMode::None,
+        }
+    }
+}
+
+/// Tunables of the anti-aliasing stage.
+#[derive(Debug, Clone)]
+pub struct AntiAliasingSettings {
+    pub mode: AntiAliasingMode,
+    /// How much of the accumulated history TAA keeps each frame
+    pub history_weight: f32,
+}
+
+impl Default for AntiAliasingSettings {
+    fn default() -> Self {
+        Self {
+            mode: AntiAliasingMode::Fxaa,
+            history_weight: 0.9,
+        }
+    }
+}
+
+/// An HDR image with the framebuffer that renders into it.
+struct ResolveTarget {
+    image_view: Arc<ImageView<AttachmentImage>>,
+    framebuffer: Arc<Framebuffer>,
+}
+
+/// Resolves aliasing in the lit HDR image before bloom and tonemapping. TAA
+/// alternates between two targets, the previous one being its history.
+pub struct AntiAliasing {
+    pub settings: AntiAliasingSettings,
+    render_pass: Arc<RenderPass>,
+    fxaa_pipeline: Arc<GraphicsPipeline>,
+    taa_pipeline: Arc<GraphicsPipeline>,
+    targets: [ResolveTarget; 2],
+    current: usize,
+    history_valid: bool,
+    frame: u32,
+    sampler: Arc<Sampler>,
+    fullscreen: FullscreenPass,
+}
+
+impl AntiAliasing {
+    pub fn new(
+        device: Arc<Device>,
+        allocator: &StandardMemoryAllocator,
+        extent: [u32; 2],
+        settings: AntiAliasingSettings,
+    ) -> AntiAliasing {
+        let fullscreen = FullscreenPass::new(device.clone(), allocator);
+        let fxaa_frag = fxaa_frag::load(device.clone()).unwrap();
+        let taa_frag = taa_frag::load(device.clone()).unwrap();
+
+        let render_pass = vulkano::single_pass_renderpass!(device.clone(),
+            attachments: {
+                color: {
+                    load: DontCare,
+                    store: Store,
+                    format: HDR_FORMAT,
+                    samples: 1,
+                }
+            },
+            pass: {
+                color: [color],
+                depth_stencil: {}
+            }
+        )
+        .unwrap();
+
+        let fxaa_pipeline = fullscreen.pipeline(
+            device.clone(),
+            &fxaa_frag,
+            Subpass::from(render_pass.clone(), 0).unwrap(),
+            None,
+        );
+
+        let taa_pipeline = fullscreen.pipeline(
+            device.clone(),
+            &taa_frag,
+            Subpass::from(render_pass.clone(), 0).unwrap(),
+            None,
+        );
+
+        let sampler = Sampler::new(
+            device.clone(),
+            SamplerCreateInfo {
+                mag_filter: Filter::Linear,
+                min_filter: Filter::Linear,
+                address_mode: [SamplerAddressMode::ClampToEdge; 3],
+                ..Default::default()
+            },
+        )
+        .unwrap();
+
+        AntiAliasing {
+            settings,
+            targets: AntiAliasing::create_targets(allocator, render_pass.clone(), extent),
+            render_pass,
+            fxaa_pipeline,
+            taa_pipeline,
+            current: 0,
+            history_valid: false,
+            frame: 0,
+            sampler,
+            fullscreen,
+        }
+    }
+
+    /// Recreates the resolve targets for a new target size, dropping the history.
+    pub fn resize(&mut self, allocator: &StandardMemoryAllocator, extent: [u32; 2]) {
+        self.targets = AntiAliasing::create_targets(allocator, self.render_pass.clone(), extent);
+        self.history_valid = false;
+    }
+
+    /// Sub-pixel offset in NDC to shift this frame's projection by. Zero unless
+    /// TAA is on, otherwise steps through a Halton (2, 3) sequence.
+    pub fn next_jitter(&mut self, extent: [u32; 2]) -> [f32; 2] {
+        if self.settings.mode != AntiAliasingMode::Taa {
+            return [0.0, 0.0];
+        }
+
+        self.frame = (self.frame + 1) % JITTER_SAMPLES;
+        let index = self.frame + 1;
+        [
+            (halton(index, 2) - 0.5) * 2.0 / extent[0] as f32,
+            (halton(index, 3) - 0.5) * 2.0 / extent[1] as f32,
+        ]
+    }
+
+    /// Records the resolve of `hdr_color` and returns the image to continue
+    /// post processing with. Must be recorded outside a render pass.
+    pub fn record(
+        &mut self,
+        commands: &mut AutoCommandBufferBuilder<PrimaryAutoCommandBuffer>,
+        allocator: &StandardMemoryAllocator,
+        descriptor_set_allocator: &StandardDescriptorSetAllocator,
+        hdr_color: Arc<ImageView<AttachmentImage>>,
+        velocity: Arc<ImageView<AttachmentImage>>,
+    ) -> Arc<ImageView<AttachmentImage>> {
+        if self.settings.mode != AntiAliasingMode::Taa {
+            self.history_valid = false;
+        }
+
+        match self.settings.mode {
+            AntiAliasingMode::None => hdr_color,
+            AntiAliasingMode::Fxaa => {
+                let fxaa_set = PersistentDescriptorSet::new(
+                    descriptor_set_allocator,
+                    self.fxaa_pipeline
+                        .layout()
+                        .set_layouts()
+                        .get(0)
+                        .unwrap()
+                        .clone(),
+                    [WriteDescriptorSet::image_view_sampler(
+                        0,
+                        hdr_color,
+                        self.sampler.clone(),
+                    )],
+                )
+                .unwrap();
+
+                let target = &self.targets[self.current];
+                self.fullscreen.draw(
+                    commands,
+                    target.framebuffer.clone(),
+                    &self.fxaa_pipeline,
+                    Some(fxaa_set),
+                );
+                target.image_view.clone()
+            }
+            AntiAliasingMode::Taa => {
+                let history = &self.targets[1 - self.current];
+                let history_weight = if self.history_valid {
+                    self.settings.history_weight
+                } else {
+                    0.0
+                };
+
+                let taa_buffer = CpuAccessibleBuffer::from_data(
+                    allocator,
+                    BufferUsage {
+                        uniform_buffer: true,
+                        ..BufferUsage::empty()
+                    },
+                    false,
+                    taa_frag::ty::TAA_Data {
+                        params: [history_weight, 0.0, 0.0, 0.0],
+                    },
+                )
+                .unwrap();
+
+                let taa_set = PersistentDescriptorSet::new(
+                    descriptor_set_allocator,
+                    self.taa_pipeline
+                        .layout()
+                        .set_layouts()
+                        .get(0)
+                        .unwrap()
+                        .clone(),
+                    [
+                        WriteDescriptorSet::image_view_sampler(0, hdr_color, self.sampler.clone()),
+                        WriteDescriptorSet::image_view_sampler(
+                            1,
+                            history.image_view.clone(),
+                            self.sampler.clone(),
+                        ),
+                        WriteDescriptorSet::image_view_sampler(2, velocity, self.sampler.clone()),
+                        WriteDescriptorSet::buffer(3, taa_buffer),
+                    ],
+                )
+                .unwrap();
+
+                let target = &self.targets[self.current];
+                self.fullscreen.draw(
+                    commands,
+                    target.framebuffer.clone(),
+                    &self.taa_pipeline,
+                    Some(taa_set),
+                );
+                let resolved = target.image_view.clone();
+
+                self.current = 1 - self.current;
+                self.history_valid = true;
+                resolved
+            }
+        }
+    }
+
+    fn create_targets(
+        allocator: &StandardMemoryAllocator,
+        render_pass: Arc<RenderPass>,
+        extent: [u32; 2],
+    ) -> [ResolveTarget; 2] {
+        [(); 2].map(|_| {
+            let image_view = ImageView::new_default(
+                AttachmentImage::with_usage(
+                    allocator,
+                    extent,
+                    HDR_FORMAT,
+                    ImageUsage {
+                        color_attachment: true,
+                        sampled: true,
+                        ..ImageUsage::empty()
+                    },
+                )
+                .unwrap(),
+            )
+            .unwrap();
+
+            let framebuffer = Framebuffer::new(
+                render_pass.clone(),
+                FramebufferCreateInfo {
+                    attachments: vec![image_view.clone()],
+                    ..Default::default()
+                },
+            )
+            .unwrap();
+
+            ResolveTarget {
+                image_view,
+                framebuffer,
+            }
+        })
+    }
+}
+
+/// Element `index` of the Halton low discrepancy sequence in `base`.
+fn halton(mut index: u32, base: u32) -> f32 {
+    let mut result = 0.0;
+    let mut fraction = 1.0;
+    while index > 0 {
+        fraction /= base as f32;
+        result += fraction * (index % base) as f32;
+        index /= base;
+    }
+    result
+}
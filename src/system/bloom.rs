@@ -0,0 +1,347 @@
+use std::sync::Arc;
+
+use vulkano::buffer::{BufferUsage, CpuAccessibleBuffer};
+use vulkano::command_buffer::{AutoCommandBufferBuilder, PrimaryAutoCommandBuffer};
+use vulkano::descriptor_set::allocator::StandardDescriptorSetAllocator;
+use vulkano::descriptor_set::{PersistentDescriptorSet, WriteDescriptorSet};
+use vulkano::device::Device;
+use vulkano::image::view::ImageView;
+use vulkano::image::{AttachmentImage, ImageUsage};
+use vulkano::memory::allocator::StandardMemoryAllocator;
+use vulkano::pipeline::graphics::color_blend::{AttachmentBlend, BlendFactor, BlendOp};
+use vulkano::pipeline::{GraphicsPipeline, Pipeline};
+use vulkano::render_pass::{Framebuffer, FramebufferCreateInfo, RenderPass, Subpass};
+use vulkano::sampler::{Filter, Sampler, SamplerAddressMode, SamplerCreateInfo};
+
+use crate::system::fullscreen::FullscreenPass;
+use crate::system::tonemap::HDR_FORMAT;
+
+mod downsample_frag {
+    vulkano_shaders::shader! {
+        ty: "fragment",
+        path: "src/system/shaders/bloom_downsample.frag",
+        types_meta: {
+            use bytemuck::{Pod, Zeroable};
+
+            #[derive(Clone, Copy, Zeroable, Pod)]
+        },
+    }
+}
+
+mod upsample_frag {
+    vulkano_shaders::shader! {
+        ty: "fragment",
+        path: "src/system/shaders/bloom_upsample.frag",
+        types_meta: {
+            use bytemuck::{Pod, Zeroable};
+
+            #[derive(Clone, Copy, Zeroable, Pod)]
+        },
+    }
+}
+
+// Number of times the image is halved, fewer when the target is small
+const MAX_LEVELS: usize = 6;
+
+/// Tunables of the bloom pass.
+#[derive(Debug, Clone)]
+pub struct BloomSettings {
+    pub enabled: bool,
+    /// HDR brightness above which pixels start to bloom
+    pub threshold: f32,
+    /// Fraction of the threshold over which bloom fades in
+    pub knee: f32,
+    /// How strongly the blurred image is added before tonemapping
+    pub intensity: f32,
+    /// Radius of the upsampling filter in texels of the smaller level
+    pub radius: f32,
+}
+
+impl Default for BloomSettings {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            threshold: 1.0,
+            knee: 0.5,
+            intensity: 0.05,
+            radius: 1.0,
+        }
+    }
+}
+
+/// One level of the blur chain, with a framebuffer for each of the two passes
+/// that render into it.
+struct BloomLevel {
+    image_view: Arc<ImageView<AttachmentImage>>,
+    downsample_framebuffer: Arc<Framebuffer>,
+    upsample_framebuffer: Arc<Framebuffer>,
+}
+
+/// Extracts the bright parts of the HDR image and blurs them by halving the
+/// image repeatedly and adding the levels back up.
+pub struct Bloom {
+    pub settings: BloomSettings,
+    downsample_pass: Arc<RenderPass>,
+    upsample_pass: Arc<RenderPass>,
+    downsample_pipeline: Arc<GraphicsPipeline>,
+    upsample_pipeline: Arc<GraphicsPipeline>,
+    levels: Vec<BloomLevel>,
+    sampler: Arc<Sampler>,
+    fullscreen: FullscreenPass,
+}
+
+impl Bloom {
+    pub fn new(
+        device: Arc<Device>,
+        allocator: &StandardMemoryAllocator,
+        extent: [u32; 2],
+        settings: BloomSettings,
+    ) -> Bloom {
+        let fullscreen = FullscreenPass::new(device.clone(), allocator);
+        let downsample_frag = downsample_frag::load(device.clone()).unwrap();
+        let upsample_frag = upsample_frag::load(device.clone()).unwrap();
+
+        let downsample_pass = vulkano::single_pass_renderpass!(device.clone(),
+            attachments: {
+                color: {
+                    load: DontCare,
+                    store: Store,
+                    format: HDR_FORMAT,
+                    samples: 1,
+                }
+            },
+            pass: {
+                color: [color],
+                depth_stencil: {}
+            }
+        )
+        .unwrap();
+
+        // Upsampling adds onto what the downsample left in the level
+        let upsample_pass = vulkano::single_pass_renderpass!(device.clone(),
+            attachments: {
+                color: {
+                    load: Load,
+                    store: Store,
+                    format: HDR_FORMAT,
+                    samples: 1,
+                }
+            },
+            pass: {
+                color: [color],
+                depth_stencil: {}
+            }
+        )
+        .unwrap();
+
+        let downsample_pipeline = fullscreen.pipeline(
+            device.clone(),
+            &downsample_frag,
+            Subpass::from(downsample_pass.clone(), 0).unwrap(),
+            None,
+        );
+
+        let upsample_pipeline = fullscreen.pipeline(
+            device.clone(),
+            &upsample_frag,
+            Subpass::from(upsample_pass.clone(), 0).unwrap(),
+            Some(AttachmentBlend {
+                color_op: BlendOp::Add,
+                color_source: BlendFactor::One,
+                color_destination: BlendFactor::One,
+                alpha_op: BlendOp::Max,
+                alpha_source: BlendFactor::One,
+                alpha_destination: BlendFactor::One,
+            }),
+        );
+
+        let sampler = Sampler::new(
+            device.clone(),
+            SamplerCreateInfo {
+                mag_filter: Filter::Linear,
+                min_filter: Filter::Linear,
+                address_mode: [SamplerAddressMode::ClampToEdge; 3],
+                ..Default::default()
+            },
+        )
+        .unwrap();
+
+        let levels = Bloom::create_levels(
+            allocator,
+            downsample_pass.clone(),
+            upsample_pass.clone(),
+            extent,
+        );
+
+        Bloom {
+            settings,
+            downsample_pass,
+            upsample_pass,
+            downsample_pipeline,
+            upsample_pipeline,
+            levels,
+            sampler,
+            fullscreen,
+        }
+    }
+
+    /// Recreates the blur chain for a new target size.
+    pub fn resize(&mut self, allocator: &StandardMemoryAllocator, extent: [u32; 2]) {
+        self.levels = Bloom::create_levels(
+            allocator,
+            self.downsample_pass.clone(),
+            self.upsample_pass.clone(),
+            extent,
+        );
+    }
+
+    /// Records the bloom chain for `hdr_color` and returns the half resolution
+    /// result to composite. Must be recorded outside a render pass.
+    pub fn record(
+        &self,
+        commands: &mut AutoCommandBufferBuilder<PrimaryAutoCommandBuffer>,
+        allocator: &StandardMemoryAllocator,
+        descriptor_set_allocator: &StandardDescriptorSetAllocator,
+        hdr_color: Arc<ImageView<AttachmentImage>>,
+    ) -> Arc<ImageView<AttachmentImage>> {
+        let uniform_usage = BufferUsage {
+            uniform_buffer: true,
+            ..BufferUsage::empty()
+        };
+
+        let mut source = hdr_color;
+        for (index, level) in self.levels.iter().enumerate() {
+            let downsample_buffer = CpuAccessibleBuffer::from_data(
+                allocator,
+                uniform_usage,
+                false,
+                downsample_frag::ty::Downsample_Data {
+                    params: [
+                        self.settings.threshold,
+                        self.settings.knee,
+                        (index == 0) as u32 as f32,
+                        0.0,
+                    ],
+                },
+            )
+            .unwrap();
+
+            let downsample_set = PersistentDescriptorSet::new(
+                descriptor_set_allocator,
+                self.downsample_pipeline
+                    .layout()
+                    .set_layouts()
+                    .get(0)
+                    .unwrap()
+                    .clone(),
+                [
+                    WriteDescriptorSet::image_view_sampler(0, source, self.sampler.clone()),
+                    WriteDescriptorSet::buffer(1, downsample_buffer),
+                ],
+            )
+            .unwrap();
+
+            self.fullscreen.draw(
+                commands,
+                level.downsample_framebuffer.clone(),
+                &self.downsample_pipeline,
+                Some(downsample_set),
+            );
+
+            source = level.image_view.clone();
+        }
+
+        let upsample_buffer = CpuAccessibleBuffer::from_data(
+            allocator,
+            uniform_usage,
+            false,
+            upsample_frag::ty::Upsample_Data {
+                params: [self.settings.radius, 0.0, 0.0, 0.0],
+            },
+        )
+        .unwrap();
+
+        for pair in self.levels.windows(2).rev() {
+            let (target, smaller) = (&pair[0], &pair[1]);
+
+            let upsample_set = PersistentDescriptorSet::new(
+                descriptor_set_allocator,
+                self.upsample_pipeline
+                    .layout()
+                    .set_layouts()
+                    .get(0)
+                    .unwrap()
+                    .clone(),
+                [
+                    WriteDescriptorSet::image_view_sampler(
+                        0,
+                        smaller.image_view.clone(),
+                        self.sampler.clone(),
+                    ),
+                    WriteDescriptorSet::buffer(1, upsample_buffer.clone()),
+                ],
+            )
+            .unwrap();
+
+            self.fullscreen.draw(
+                commands,
+                target.upsample_framebuffer.clone(),
+                &self.upsample_pipeline,
+                Some(upsample_set),
+            );
+        }
+
+        self.levels[0].image_view.clone()
+    }
+
+    fn create_levels(
+        allocator: &StandardMemoryAllocator,
+        downsample_pass: Arc<RenderPass>,
+        upsample_pass: Arc<RenderPass>,
+        extent: [u32; 2],
+    ) -> Vec<BloomLevel> {
+        let mut levels = Vec::new();
+        let mut size = [(extent[0] / 2).max(1), (extent[1] / 2).max(1)];
+
+        while levels.len() < MAX_LEVELS {
+            let image_view = ImageView::new_default(
+                AttachmentImage::with_usage(
+                    allocator,
+                    size,
+                    HDR_FORMAT,
+                    ImageUsage {
+                        color_attachment: true,
+                        sampled: true,
+                        ..ImageUsage::empty()
+                    },
+                )
+                .unwrap(),
+            )
+            .unwrap();
+
+            let create_framebuffer = |render_pass: Arc<RenderPass>| {
+                Framebuffer::new(
+                    render_pass,
+                    FramebufferCreateInfo {
+                        attachments: vec![image_view.clone()],
+                        ..Default::default()
+                    },
+                )
+                .unwrap()
+            };
+
+            levels.push(BloomLevel {
+                downsample_framebuffer: create_framebuffer(downsample_pass.clone()),
+                upsample_framebuffer: create_framebuffer(upsample_pass.clone()),
+                image_view,
+            });
+
+            if size[0] == 1 || size[1] == 1 {
+                break;
+            }
+            size = [size[0] / 2, size[1] / 2];
+        }
+
+        levels
+    }
+}
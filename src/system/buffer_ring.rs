@@ -0,0 +1,67 @@
+use std::sync::Arc;
+
+use vulkano::buffer::{BufferContents, BufferUsage, CpuAccessibleBuffer};
+use vulkano::memory::allocator::StandardMemoryAllocator;
+
+// Number of frames whose per-frame data can be in use by the GPU at once
+const FRAMES_IN_FLIGHT: usize = 3;
+const MIN_CAPACITY: usize = 256;
+
+/// Host-visible buffers that are reused frame after frame, one per frame in
+/// flight, so a frame never writes into data the GPU still reads.
+pub struct BufferRing<T>
+where
+    [T]: BufferContents,
+{
+    buffers: [Option<Arc<CpuAccessibleBuffer<[T]>>>; FRAMES_IN_FLIGHT],
+    usage: BufferUsage,
+    capacity: usize,
+    index: usize,
+}
+
+impl<T> BufferRing<T>
+where
+    T: Default,
+    [T]: BufferContents,
+{
+    pub fn new(usage: BufferUsage) -> Self {
+        Self {
+            buffers: Default::default(),
+            usage,
+            capacity: MIN_CAPACITY,
+            index: 0,
+        }
+    }
+
+    /// Advances to the next buffer and returns it, with room for at least
+    /// `count` elements.
+    pub fn next(
+        &mut self,
+        allocator: &StandardMemoryAllocator,
+        count: usize,
+    ) -> Arc<CpuAccessibleBuffer<[T]>> {
+        if count > self.capacity {
+            self.capacity = count.next_power_of_two();
+            self.buffers = Default::default();
+        }
+
+        self.index = (self.index + 1) % FRAMES_IN_FLIGHT;
+        let slot = &mut self.buffers[self.index];
+
+        // Only happens if the GPU falls more than FRAMES_IN_FLIGHT frames behind
+        let in_use = slot.as_ref().map_or(true, |buffer| buffer.write().is_err());
+        if in_use {
+            *slot = Some(
+                CpuAccessibleBuffer::from_iter(
+                    allocator,
+                    self.usage,
+                    false,
+                    (0..self.capacity).map(|_| T::default()),
+                )
+                .unwrap(),
+            );
+        }
+
+        slot.clone().unwrap()
+    }
+}
@@ -0,0 +1,68 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::system::Frame;
+
+/// An attachment of the deferred render pass, or the tonemapped final image,
+/// that can be read back.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CaptureTarget {
+    FinalColor,
+    HdrColor,
+    AlbedoAo,
+    Surface,
+    Depth,
+    Emissive,
+}
+
+impl CaptureTarget {
+    pub const ALL: [CaptureTarget; 6] = [
+        CaptureTarget::FinalColor,
+        CaptureTarget::HdrColor,
+        CaptureTarget::AlbedoAo,
+        CaptureTarget::Surface,
+        CaptureTarget::Depth,
+        CaptureTarget::Emissive,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            CaptureTarget::FinalColor => "final_color",
+            CaptureTarget::HdrColor => "hdr_color",
+            CaptureTarget::AlbedoAo => "albedo_ao",
+            CaptureTarget::Surface => "surface",
+            CaptureTarget::Depth => "depth",
+            CaptureTarget::Emissive => "emissive",
+        }
+    }
+}
+
+/// The attachments read back at the end of a single frame.
+pub struct Capture {
+    pub frames: Vec<(CaptureTarget, Frame)>,
+}
+
+impl Capture {
+    pub fn frame(&self, target: CaptureTarget) -> Option<&Frame> {
+        self.frames
+            .iter()
+            .find(|(frame_target, _)| *frame_target == target)
+            .map(|(_, frame)| frame)
+    }
+
+    /// Writes every frame into `directory` as `{stem}_{target}.png`, or `.exr`
+    /// for float attachments, and returns the written paths.
+    pub fn save(&self, directory: &Path, stem: &str) -> image::ImageResult<Vec<PathBuf>> {
+        fs::create_dir_all(directory)?;
+
+        let mut paths = Vec::new();
+        for (target, frame) in self.frames.iter() {
+            let extension = if frame.is_hdr() { "exr" } else { "png" };
+            let path = directory.join(format!("{}_{}.{}", stem, target.name(), extension));
+            frame.save(&path)?;
+            paths.push(path);
+        }
+
+        Ok(paths)
+    }
+}
@@ -0,0 +1,207 @@
+use std::path::Path;
+
+use image::error::{ImageError, ImageFormatHint, UnsupportedError, UnsupportedErrorKind};
+use vulkano::format::Format;
+
+/// A rendered image read back from the GPU. `data` holds the texels tightly
+/// packed row by row, in the layout of `format`.
+pub struct Frame {
+    pub width: u32,
+    pub height: u32,
+    pub format: Format,
+    pub data: Vec<u8>,
+}
+
+impl Frame {
+    pub fn new(dimensions: [u32; 2], format: Format, data: Vec<u8>) -> Frame {
+        Frame {
+            width: dimensions[0],
+            height: dimensions[1],
+            format,
+            data,
+        }
+    }
+
+    /// Whether the texels hold more than 8 bits per channel and should be
+    /// written as EXR to keep their range.
+    pub fn is_hdr(&self) -> bool {
+        matches!(
+            self.format,
+            Format::R16G16B16A16_SFLOAT | Format::R32G32B32A32_SFLOAT | Format::D32_SFLOAT
+        )
+    }
+
+    /// Texels as 8-bit RGBA, `None` for formats `to_rgba32f` can't convert
+    /// either. Float formats are clamped to `[0, 1]`.
+    pub fn to_rgba8(&self) -> Option<Vec<u8>> {
+        match self.format {
+            Format::R8G8B8A8_UNORM | Format::R8G8B8A8_SRGB => Some(self.data.clone()),
+            Format::B8G8R8A8_UNORM | Format::B8G8R8A8_SRGB => Some(
+                self.data
+                    .chunks_exact(4)
+                    .flat_map(|bgra| [bgra[2], bgra[1], bgra[0], bgra[3]])
+                    .collect(),
+            ),
+            _ => Some(
+                self.to_rgba32f()?
+                    .into_iter()
+                    .map(|v| (v.clamp(0.0, 1.0) * 255.0 + 0.5) as u8)
+                    .collect(),
+            ),
+        }
+    }
+
+    /// Texels as linear 32-bit float RGBA, `None` for formats it doesn't know.
+    /// sRGB formats are decoded.
+    pub fn to_rgba32f(&self) -> Option<Vec<f32>> {
+        let texels = match self.format {
+            Format::R8G8B8A8_UNORM => self.data.iter().map(|&v| v as f32 / 255.0).collect(),
+            Format::B8G8R8A8_UNORM => self
+                .data
+                .chunks_exact(4)
+                .flat_map(|bgra| [bgra[2], bgra[1], bgra[0], bgra[3]])
+                .map(|v| v as f32 / 255.0)
+                .collect(),
+            Format::R8G8B8A8_SRGB => self
+                .data
+                .chunks_exact(4)
+                .flat_map(|rgba| decode_srgb([rgba[0], rgba[1], rgba[2], rgba[3]]))
+                .collect(),
+            Format::B8G8R8A8_SRGB => self
+                .data
+                .chunks_exact(4)
+                .flat_map(|bgra| decode_srgb([bgra[2], bgra[1], bgra[0], bgra[3]]))
+                .collect(),
+            // Packed into a little endian u32, red in the lowest bits
+            Format::A2B10G10R10_UNORM_PACK32 => self
+                .data
+                .chunks_exact(4)
+                .flat_map(|packed| {
+                    let packed = u32::from_le_bytes([packed[0], packed[1], packed[2], packed[3]]);
+                    let channel =
+                        |shift: u32, max: u32| ((packed >> shift) & max) as f32 / max as f32;
+                    [
+                        channel(0, 0x3ff),
+                        channel(10, 0x3ff),
+                        channel(20, 0x3ff),
+                        channel(30, 0x3),
+                    ]
+                })
+                .collect(),
+            Format::R16G16B16A16_SFLOAT => self
+                .data
+                .chunks_exact(2)
+                .map(|half| f16_to_f32(u16::from_le_bytes([half[0], half[1]])))
+                .collect(),
+            Format::R32G32B32A32_SFLOAT => self
+                .data
+                .chunks_exact(4)
+                .map(|float| f32::from_le_bytes([float[0], float[1], float[2], float[3]]))
+                .collect(),
+            // Depth is spread over the color channels so it can be viewed
+            Format::D32_SFLOAT => self
+                .data
+                .chunks_exact(4)
+                .flat_map(|float| {
+                    let depth = f32::from_le_bytes([float[0], float[1], float[2], float[3]]);
+                    [depth, depth, depth, 1.0]
+                })
+                .collect(),
+            _ => return None,
+        };
+
+        Some(texels)
+    }
+
+    /// Writes the frame to `path`. An `.exr` extension writes linear float
+    /// data, anything else is written as 8-bit PNG. Fails for formats that
+    /// can't be converted.
+    pub fn save(&self, path: &Path) -> image::ImageResult<()> {
+        let is_exr = path
+            .extension()
+            .map_or(false, |ext| ext.eq_ignore_ascii_case("exr"));
+        let unsupported = || {
+            ImageError::Unsupported(UnsupportedError::from_format_and_kind(
+                ImageFormatHint::Unknown,
+                UnsupportedErrorKind::GenericFeature(format!("frames of format {:?}", self.format)),
+            ))
+        };
+
+        if is_exr {
+            let texels = self.to_rgba32f().ok_or_else(unsupported)?;
+            image::Rgba32FImage::from_raw(self.width, self.height, texels)
+                .expect("Frame data does not match its dimensions")
+                .save(path)
+        } else {
+            let texels = self.to_rgba8().ok_or_else(unsupported)?;
+            image::RgbaImage::from_raw(self.width, self.height, texels)
+                .expect("Frame data does not match its dimensions")
+                .save(path)
+        }
+    }
+}
+
+fn decode_srgb(rgba: [u8; 4]) -> [f32; 4] {
+    let decode = |v: u8| {
+        let v = v as f32 / 255.0;
+        if v <= 0.04045 {
+            v / 12.92
+        } else {
+            ((v + 0.055) / 1.055).powf(2.4)
+        }
+    };
+
+    [
+        decode(rgba[0]),
+        decode(rgba[1]),
+        decode(rgba[2]),
+        rgba[3] as f32 / 255.0,
+    ]
+}
+
+fn f16_to_f32(bits: u16) -> f32 {
+    let sign = bits >> 15;
+    let exponent = ((bits >> 10) & 0x1f) as u32;
+    let mantissa = (bits & 0x3ff) as u32;
+
+    let value = match exponent {
+        0 => mantissa as f32 * 2f32.powi(-24),
+        0x1f if mantissa == 0 => f32::INFINITY,
+        0x1f => f32::NAN,
+        _ => f32::from_bits(((exponent + 112) << 23) | (mantissa << 13)),
+    };
+
+    if sign == 1 { -value } else { value }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bgra_is_swizzled_to_rgba() {
+        let frame = Frame::new([1, 1], Format::B8G8R8A8_UNORM, vec![1, 2, 3, 4]);
+        assert_eq!(frame.to_rgba8(), Some(vec![3, 2, 1, 4]));
+    }
+
+    #[test]
+    fn packed_10_bit_channels_are_unpacked() {
+        // Red full, green empty, blue full, alpha full
+        let packed: u32 = 0x3ff | (0x3ff << 20) | (0x3 << 30);
+        let frame = Frame::new(
+            [1, 1],
+            Format::A2B10G10R10_UNORM_PACK32,
+            packed.to_le_bytes().to_vec(),
+        );
+        assert_eq!(frame.to_rgba32f(), Some(vec![1.0, 0.0, 1.0, 1.0]));
+        assert_eq!(frame.to_rgba8(), Some(vec![255, 0, 255, 255]));
+    }
+
+    #[test]
+    fn unknown_formats_are_not_converted() {
+        let frame = Frame::new([1, 1], Format::R8_UNORM, vec![0]);
+        assert_eq!(frame.to_rgba32f(), None);
+        assert_eq!(frame.to_rgba8(), None);
+        assert!(frame.save(Path::new("unused.png")).is_err());
+    }
+}
@@ -0,0 +1,125 @@
+use std::sync::Arc;
+
+use vulkano::buffer::{BufferUsage, CpuAccessibleBuffer, TypedBufferAccess};
+use vulkano::command_buffer::{
+    AutoCommandBufferBuilder, PrimaryAutoCommandBuffer, RenderPassBeginInfo, SubpassContents,
+};
+use vulkano::descriptor_set::PersistentDescriptorSet;
+use vulkano::device::Device;
+use vulkano::memory::allocator::StandardMemoryAllocator;
+use vulkano::pipeline::graphics::color_blend::{AttachmentBlend, ColorBlendState};
+use vulkano::pipeline::graphics::input_assembly::InputAssemblyState;
+use vulkano::pipeline::graphics::vertex_input::BuffersDefinition;
+use vulkano::pipeline::graphics::viewport::{Viewport, ViewportState};
+use vulkano::pipeline::{GraphicsPipeline, Pipeline, PipelineBindPoint};
+use vulkano::render_pass::{Framebuffer, Subpass};
+use vulkano::shader::ShaderModule;
+
+use crate::engine::DummyVertex;
+
+mod fullscreen_vert {
+    vulkano_shaders::shader! {
+        ty: "vertex",
+        path: "src/system/shaders/fullscreen.vert",
+    }
+}
+
+/// Runs a fragment shader once over every pixel of a single subpass render
+/// pass, for the post processing and baking passes.
+pub struct FullscreenPass {
+    vertex_shader: Arc<ShaderModule>,
+    dummy_verts: Arc<CpuAccessibleBuffer<[DummyVertex]>>,
+}
+
+impl FullscreenPass {
+    pub fn new(device: Arc<Device>, allocator: &StandardMemoryAllocator) -> FullscreenPass {
+        let vertex_shader = fullscreen_vert::load(device).unwrap();
+
+        let dummy_verts = CpuAccessibleBuffer::from_iter(
+            allocator,
+            BufferUsage {
+                vertex_buffer: true,
+                ..BufferUsage::empty()
+            },
+            false,
+            DummyVertex::list().iter().cloned(),
+        )
+        .unwrap();
+
+        FullscreenPass {
+            vertex_shader,
+            dummy_verts,
+        }
+    }
+
+    /// Builds a pipeline running `fragment_shader` in `subpass`, blending its
+    /// output into the target with `blend` if given.
+    pub fn pipeline(
+        &self,
+        device: Arc<Device>,
+        fragment_shader: &Arc<ShaderModule>,
+        subpass: Subpass,
+        blend: Option<AttachmentBlend>,
+    ) -> Arc<GraphicsPipeline> {
+        let mut color_blend_state = ColorBlendState::new(subpass.num_color_attachments());
+        if let Some(blend) = blend {
+            color_blend_state = color_blend_state.blend(blend);
+        }
+
+        GraphicsPipeline::start()
+            .vertex_input_state(BuffersDefinition::new().vertex::<DummyVertex>())
+            .vertex_shader(self.vertex_shader.entry_point("main").unwrap(), ())
+            .input_assembly_state(InputAssemblyState::new())
+            .viewport_state(ViewportState::viewport_dynamic_scissor_irrelevant())
+            .fragment_shader(fragment_shader.entry_point("main").unwrap(), ())
+            .color_blend_state(color_blend_state)
+            .render_pass(subpass)
+            .build(device)
+            .unwrap()
+    }
+
+    /// Records `pipeline` over the whole of `framebuffer`, keeping its
+    /// previous contents. Must be recorded outside a render pass.
+    pub fn draw(
+        &self,
+        commands: &mut AutoCommandBufferBuilder<PrimaryAutoCommandBuffer>,
+        framebuffer: Arc<Framebuffer>,
+        pipeline: &Arc<GraphicsPipeline>,
+        descriptor_set: Option<Arc<PersistentDescriptorSet>>,
+    ) {
+        let [width, height] = framebuffer.extent();
+        let viewport = Viewport {
+            origin: [0.0, 0.0],
+            dimensions: [width as f32, height as f32],
+            depth_range: 0.0..1.0,
+        };
+
+        commands
+            .begin_render_pass(
+                RenderPassBeginInfo {
+                    clear_values: vec![None; framebuffer.attachments().len()],
+                    ..RenderPassBeginInfo::framebuffer(framebuffer)
+                },
+                SubpassContents::Inline,
+            )
+            .unwrap()
+            .set_viewport(0, [viewport])
+            .bind_pipeline_graphics(pipeline.clone())
+            .bind_vertex_buffers(0, self.dummy_verts.clone());
+
+        if let Some(descriptor_set) = descriptor_set {
+            commands.bind_descriptor_sets(
+                PipelineBindPoint::Graphics,
+                pipeline.layout().clone(),
+                0,
+                descriptor_set,
+            );
+        }
+
+        commands
+            .draw(self.dummy_verts.len() as u32, 1, 0, 0)
+            .unwrap()
+            .end_render_pass()
+            .unwrap();
+    }
+}
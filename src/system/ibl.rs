@@ -0,0 +1,344 @@
+use std::sync::Arc;
+
+use vulkano::buffer::{BufferUsage, CpuAccessibleBuffer};
+use vulkano::command_buffer::{AutoCommandBufferBuilder, PrimaryAutoCommandBuffer};
+use vulkano::descriptor_set::allocator::StandardDescriptorSetAllocator;
+use vulkano::descriptor_set::{PersistentDescriptorSet, WriteDescriptorSet};
+use vulkano::device::Device;
+use vulkano::format::Format;
+use vulkano::image::view::{ImageView, ImageViewAbstract, ImageViewCreateInfo};
+use vulkano::image::{
+    AttachmentImage, ImageCreateFlags, ImageDimensions, ImageLayout, ImageUsage, ImmutableImage,
+    MipmapsCount,
+};
+use vulkano::memory::allocator::StandardMemoryAllocator;
+use vulkano::pipeline::{GraphicsPipeline, Pipeline};
+use vulkano::render_pass::{Framebuffer, FramebufferCreateInfo, RenderPass, Subpass};
+use vulkano::sampler::{
+    Filter, LOD_CLAMP_NONE, Sampler, SamplerAddressMode, SamplerCreateInfo, SamplerMipmapMode,
+};
+use vulkano::shader::ShaderModule;
+
+use crate::engine::Skybox;
+use crate::system::fullscreen::FullscreenPass;
+
+mod irradiance_frag {
+    vulkano_shaders::shader! {
+        ty: "fragment",
+        path: "src/system/shaders/irradiance.frag",
+    }
+}
+
+mod prefilter_frag {
+    vulkano_shaders::shader! {
+        ty: "fragment",
+        path: "src/system/shaders/prefilter.frag",
+        types_meta: {
+            use bytemuck::{Pod, Zeroable};
+
+            #[derive(Clone, Copy, Zeroable, Pod)]
+        },
+    }
+}
+
+mod brdf_lut_frag {
+    vulkano_shaders::shader! {
+        ty: "fragment",
+        path: "src/system/shaders/brdf_lut.frag",
+    }
+}
+
+const IRRADIANCE_SIZE: [u32; 2] = [64, 32];
+// Size of the mirror-like first mip, halved for every rougher level
+const PREFILTER_SIZE: [u32; 2] = [512, 256];
+// Roughness levels from 0.0 to 1.0, one per mip
+const PREFILTER_LEVELS: u32 = 6;
+const BRDF_LUT_SIZE: [u32; 2] = [256, 256];
+
+const ENVIRONMENT_FORMAT: Format = Format::R16G16B16A16_SFLOAT;
+const BRDF_LUT_FORMAT: Format = Format::R16G16_SFLOAT;
+
+/// Precomputes the image-based lighting inputs of a skybox: the diffuse
+/// irradiance, the specular environment prefiltered per roughness and the
+/// split-sum BRDF lookup table.
+pub struct IblBaker {
+    environment_pass: Arc<RenderPass>,
+    brdf_lut_pass: Arc<RenderPass>,
+    irradiance_pipeline: Arc<GraphicsPipeline>,
+    prefilter_pipeline: Arc<GraphicsPipeline>,
+    brdf_lut_pipeline: Arc<GraphicsPipeline>,
+    sampler: Arc<Sampler>,
+    fullscreen: FullscreenPass,
+}
+
+impl IblBaker {
+    pub fn new(device: Arc<Device>, allocator: &StandardMemoryAllocator) -> IblBaker {
+        let fullscreen = FullscreenPass::new(device.clone(), allocator);
+        let irradiance_frag = irradiance_frag::load(device.clone()).unwrap();
+        let prefilter_frag = prefilter_frag::load(device.clone()).unwrap();
+        let brdf_lut_frag = brdf_lut_frag::load(device.clone()).unwrap();
+
+        let environment_pass = vulkano::single_pass_renderpass!(device.clone(),
+            attachments: {
+                color: {
+                    load: DontCare,
+                    store: Store,
+                    format: ENVIRONMENT_FORMAT,
+                    samples: 1,
+                }
+            },
+            pass: {
+                color: [color],
+                depth_stencil: {}
+            }
+        )
+        .unwrap();
+
+        let brdf_lut_pass = vulkano::single_pass_renderpass!(device.clone(),
+            attachments: {
+                color: {
+                    load: DontCare,
+                    store: Store,
+                    format: BRDF_LUT_FORMAT,
+                    samples: 1,
+                }
+            },
+            pass: {
+                color: [color],
+                depth_stencil: {}
+            }
+        )
+        .unwrap();
+
+        let build_pipeline = |fragment_shader: Arc<ShaderModule>, render_pass: Arc<RenderPass>| {
+            fullscreen.pipeline(
+                device.clone(),
+                &fragment_shader,
+                Subpass::from(render_pass, 0).unwrap(),
+                None,
+            )
+        };
+
+        let irradiance_pipeline = build_pipeline(irradiance_frag, environment_pass.clone());
+        let prefilter_pipeline = build_pipeline(prefilter_frag, environment_pass.clone());
+        let brdf_lut_pipeline = build_pipeline(brdf_lut_frag, brdf_lut_pass.clone());
+
+        // Wraps horizontally around the equirect and picks mips explicitly
+        let sampler = Sampler::new(
+            device.clone(),
+            SamplerCreateInfo {
+                mag_filter: Filter::Linear,
+                min_filter: Filter::Linear,
+                mipmap_mode: SamplerMipmapMode::Linear,
+                address_mode: [
+                    SamplerAddressMode::Repeat,
+                    SamplerAddressMode::ClampToEdge,
+                    SamplerAddressMode::ClampToEdge,
+                ],
+                lod: 0.0..=LOD_CLAMP_NONE,
+                ..Default::default()
+            },
+        )
+        .unwrap();
+
+        IblBaker {
+            environment_pass,
+            brdf_lut_pass,
+            irradiance_pipeline,
+            prefilter_pipeline,
+            brdf_lut_pipeline,
+            sampler,
+            fullscreen,
+        }
+    }
+
+    /// Records the precomputation into `commands` and stores the results in
+    /// `skybox`. The skybox must already be loaded, possibly by an earlier
+    /// command in the same buffer.
+    pub fn bake(
+        &self,
+        skybox: &mut Skybox,
+        allocator: &StandardMemoryAllocator,
+        descriptor_set_allocator: &StandardDescriptorSetAllocator,
+        commands: &mut AutoCommandBufferBuilder<PrimaryAutoCommandBuffer>,
+    ) {
+        let environment = skybox
+            .image_view
+            .clone()
+            .expect("skybox must be loaded before baking");
+
+        let irradiance_view =
+            IblBaker::create_target(allocator, IRRADIANCE_SIZE, ENVIRONMENT_FORMAT);
+        let irradiance_set = PersistentDescriptorSet::new(
+            descriptor_set_allocator,
+            self.irradiance_pipeline
+                .layout()
+                .set_layouts()
+                .get(0)
+                .unwrap()
+                .clone(),
+            [WriteDescriptorSet::image_view_sampler(
+                0,
+                environment.clone(),
+                self.sampler.clone(),
+            )],
+        )
+        .unwrap();
+        self.draw(
+            commands,
+            &self.environment_pass,
+            &self.irradiance_pipeline,
+            irradiance_view.clone(),
+            Some(irradiance_set),
+        );
+
+        let (prefiltered_view, prefiltered_mips) = IblBaker::create_mip_target(
+            allocator,
+            PREFILTER_SIZE,
+            PREFILTER_LEVELS,
+            ENVIRONMENT_FORMAT,
+        );
+        for (level, mip_view) in prefiltered_mips.into_iter().enumerate() {
+            let mip_width = (PREFILTER_SIZE[0] >> level).max(1);
+            let prefilter_buffer = CpuAccessibleBuffer::from_data(
+                allocator,
+                BufferUsage {
+                    uniform_buffer: true,
+                    ..BufferUsage::empty()
+                },
+                false,
+                prefilter_frag::ty::Prefilter_Data {
+                    roughness: level as f32 / (PREFILTER_LEVELS - 1) as f32,
+                    target_width: mip_width as f32,
+                },
+            )
+            .unwrap();
+
+            let prefilter_set = PersistentDescriptorSet::new(
+                descriptor_set_allocator,
+                self.prefilter_pipeline
+                    .layout()
+                    .set_layouts()
+                    .get(0)
+                    .unwrap()
+                    .clone(),
+                [
+                    WriteDescriptorSet::image_view_sampler(
+                        0,
+                        environment.clone(),
+                        self.sampler.clone(),
+                    ),
+                    WriteDescriptorSet::buffer(1, prefilter_buffer),
+                ],
+            )
+            .unwrap();
+            self.draw(
+                commands,
+                &self.environment_pass,
+                &self.prefilter_pipeline,
+                mip_view,
+                Some(prefilter_set),
+            );
+        }
+
+        let brdf_lut_view = IblBaker::create_target(allocator, BRDF_LUT_SIZE, BRDF_LUT_FORMAT);
+        self.draw(
+            commands,
+            &self.brdf_lut_pass,
+            &self.brdf_lut_pipeline,
+            brdf_lut_view.clone(),
+            None,
+        );
+
+        skybox.irradiance_view = Some(irradiance_view);
+        skybox.prefiltered_view = Some(prefiltered_view);
+        skybox.brdf_lut_view = Some(brdf_lut_view);
+    }
+
+    /// Runs `pipeline` once over every texel of `target`.
+    fn draw(
+        &self,
+        commands: &mut AutoCommandBufferBuilder<PrimaryAutoCommandBuffer>,
+        render_pass: &Arc<RenderPass>,
+        pipeline: &Arc<GraphicsPipeline>,
+        target: Arc<dyn ImageViewAbstract>,
+        descriptor_set: Option<Arc<PersistentDescriptorSet>>,
+    ) {
+        let framebuffer = Framebuffer::new(
+            render_pass.clone(),
+            FramebufferCreateInfo {
+                attachments: vec![target],
+                ..Default::default()
+            },
+        )
+        .unwrap();
+
+        self.fullscreen
+            .draw(commands, framebuffer, pipeline, descriptor_set);
+    }
+
+    /// Creates a single level image to render into and sample.
+    fn create_target(
+        allocator: &StandardMemoryAllocator,
+        extent: [u32; 2],
+        format: Format,
+    ) -> Arc<ImageView<AttachmentImage>> {
+        ImageView::new_default(
+            AttachmentImage::with_usage(
+                allocator,
+                extent,
+                format,
+                ImageUsage {
+                    color_attachment: true,
+                    sampled: true,
+                    ..ImageUsage::empty()
+                },
+            )
+            .unwrap(),
+        )
+        .unwrap()
+    }
+
+    /// Creates an image with `levels` mips. Returns a view over all of them
+    /// for sampling and one view per mip to render into.
+    fn create_mip_target(
+        allocator: &StandardMemoryAllocator,
+        extent: [u32; 2],
+        levels: u32,
+        format: Format,
+    ) -> (
+        Arc<ImageView<ImmutableImage>>,
+        Vec<Arc<dyn ImageViewAbstract>>,
+    ) {
+        let (image, initialization) = ImmutableImage::uninitialized(
+            allocator,
+            ImageDimensions::Dim2d {
+                width: extent[0],
+                height: extent[1],
+                array_layers: 1,
+            },
+            format,
+            MipmapsCount::Specific(levels),
+            ImageUsage {
+                color_attachment: true,
+                sampled: true,
+                ..ImageUsage::empty()
+            },
+            ImageCreateFlags::empty(),
+            ImageLayout::ShaderReadOnlyOptimal,
+            [],
+        )
+        .unwrap();
+
+        let mip_views = (0..levels)
+            .map(|level| {
+                let mut mip_info = ImageViewCreateInfo::from_image(&initialization);
+                mip_info.subresource_range.mip_levels = level..level + 1;
+                ImageView::new(initialization.clone(), mip_info).unwrap()
+                    as Arc<dyn ImageViewAbstract>
+            })
+            .collect();
+
+        (ImageView::new_default(image).unwrap(), mip_views)
+    }
+}
@@ -0,0 +1,455 @@
+use std::collections::HashMap;
+use std::ops::Range;
+use std::sync::Arc;
+
+use vulkano::buffer::{BufferUsage, CpuAccessibleBuffer, DeviceLocalBuffer, TypedBufferAccess};
+use vulkano::command_buffer::{
+    AutoCommandBufferBuilder, DrawIndexedIndirectCommand, PrimaryAutoCommandBuffer,
+};
+use vulkano::descriptor_set::allocator::StandardDescriptorSetAllocator;
+use vulkano::descriptor_set::{PersistentDescriptorSet, WriteDescriptorSet};
+use vulkano::device::Device;
+use vulkano::memory::allocator::StandardMemoryAllocator;
+use vulkano::pipeline::{ComputePipeline, Pipeline, PipelineBindPoint};
+
+use crate::engine::{Assets, DrawBatch, DrawInstance, Frustum, Mesh, MeshLod, NormalVertex};
+use crate::system::buffer_ring::BufferRing;
+
+mod cull_comp {
+    vulkano_shaders::shader! {
+        ty: "compute",
+        path: "src/system/shaders/cull.comp",
+        types_meta: {
+            use bytemuck::{Pod, Zeroable};
+
+            #[derive(Clone, Copy, Zeroable, Pod)]
+        },
+    }
+}
+
+const CULL_GROUP_SIZE: u32 = 64;
+const MIN_CAPACITY: usize = 256;
+
+/// Where one mesh lives inside the shared buffers of a `MeshPool`.
+struct PooledMesh {
+    vertex_offset: u32,
+    // Levels of detail of every submesh with their first index already offset
+    // into the pool
+    submeshes: Vec<Vec<MeshLod>>,
+    // Local space bounding sphere, radius in w
+    sphere: [f32; 4],
+}
+
+/// Every mesh packed back to back in a single vertex and index buffer, so
+/// all of them can be drawn from one indirect buffer.
+struct MeshPool {
+    vertex_buffer: Option<Arc<DeviceLocalBuffer<[NormalVertex]>>>,
+    index_buffer: Option<Arc<DeviceLocalBuffer<[u32]>>>,
+    meshes: HashMap<usize, PooledMesh>,
+}
+
+/// The draw commands culled for the current frame.
+struct IndirectFrame {
+    // A copy of the commands per view, one after the other
+    commands: Arc<CpuAccessibleBuffer<[DrawIndexedIndirectCommand]>>,
+    // Number of commands of each view
+    command_count: u64,
+    // Consecutive commands sharing a material, in command order
+    material_ranges: Vec<(usize, Range<u64>)>,
+}
+
+/// GPU driven drawing: a compute shader frustum culls the frame's instances
+/// once per view, the camera and every shadow cascade, and fills
+/// `DrawIndexedIndirectCommand`s that the shadow and geometry passes consume
+/// without the CPU knowing what survived.
+pub struct IndirectDraws {
+    cull_pipeline: Arc<ComputePipeline>,
+    pool: MeshPool,
+    // Per-frame culling inputs
+    command_ring: BufferRing<DrawIndexedIndirectCommand>,
+    instance_command_ring: BufferRing<u32>,
+    sphere_ring: BufferRing<[f32; 4]>,
+    visible_instances: Option<Arc<DeviceLocalBuffer<[DrawInstance]>>>,
+    capacity: usize,
+    frame: Option<IndirectFrame>,
+    multi_draw: bool,
+}
+
+impl IndirectDraws {
+    pub fn new(device: Arc<Device>) -> IndirectDraws {
+        let cull_comp = cull_comp::load(device.clone()).unwrap();
+        let cull_pipeline = ComputePipeline::new(
+            device.clone(),
+            cull_comp.entry_point("main").unwrap(),
+            &(),
+            None,
+            |_| {},
+        )
+        .unwrap();
+
+        IndirectDraws {
+            cull_pipeline,
+            pool: MeshPool {
+                vertex_buffer: None,
+                index_buffer: None,
+                meshes: HashMap::new(),
+            },
+            command_ring: BufferRing::new(BufferUsage {
+                indirect_buffer: true,
+                storage_buffer: true,
+                ..BufferUsage::empty()
+            }),
+            instance_command_ring: BufferRing::new(BufferUsage {
+                storage_buffer: true,
+                ..BufferUsage::empty()
+            }),
+            sphere_ring: BufferRing::new(BufferUsage {
+                storage_buffer: true,
+                ..BufferUsage::empty()
+            }),
+            visible_instances: None,
+            capacity: 0,
+            frame: None,
+            multi_draw: device.enabled_features().multi_draw_indirect,
+        }
+    }
+
+    /// Copies every mesh into the shared vertex and index buffers, replacing
+    /// what was uploaded before.
+    pub fn upload_meshes(
+        &mut self,
+        meshes: &Assets<Mesh>,
+        allocator: &StandardMemoryAllocator,
+        commands: &mut AutoCommandBufferBuilder<PrimaryAutoCommandBuffer>,
+    ) {
+        let mut vertices = Vec::new();
+        let mut indices = Vec::new();
+        self.pool.meshes.clear();
+
+        for (mesh_id, mesh) in meshes.iter() {
+            let mesh = mesh.read().unwrap();
+            let vertex_offset = vertices.len() as u32;
+            let first_index = indices.len() as u32;
+            vertices.extend_from_slice(&mesh.build.vertices);
+            indices.extend_from_slice(&mesh.build.indices);
+
+            let center = mesh.bounds.center();
+            self.pool.meshes.insert(
+                mesh_id,
+                PooledMesh {
+                    vertex_offset,
+                    submeshes: mesh
+                        .submeshes
+                        .iter()
+                        .map(|submesh| {
+                            submesh
+                                .lods
+                                .iter()
+                                .map(|lod| MeshLod {
+                                    first_index: lod.first_index + first_index,
+                                    ..*lod
+                                })
+                                .collect()
+                        })
+                        .collect(),
+                    sphere: [center.x, center.y, center.z, mesh.bounds.radius()],
+                },
+            );
+        }
+
+        // Zero sized buffers aren't allowed
+        if vertices.is_empty() || indices.is_empty() {
+            self.pool.vertex_buffer = None;
+            self.pool.index_buffer = None;
+            return;
+        }
+
+        self.pool.vertex_buffer = Some(
+            DeviceLocalBuffer::from_iter(
+                allocator,
+                vertices,
+                BufferUsage {
+                    vertex_buffer: true,
+                    ..BufferUsage::empty()
+                },
+                commands,
+            )
+            .unwrap(),
+        );
+        self.pool.index_buffer = Some(
+            DeviceLocalBuffer::from_iter(
+                allocator,
+                indices,
+                BufferUsage {
+                    index_buffer: true,
+                    ..BufferUsage::empty()
+                },
+                commands,
+            )
+            .unwrap(),
+        );
+    }
+
+    /// Builds one draw command per batch and view and records a culling
+    /// dispatch per view in `frusta` that fills in their instance counts.
+    /// Batches whose mesh isn't in the pool are skipped. Must be recorded
+    /// outside a render pass.
+    #[allow(clippy::too_many_arguments)]
+    pub fn record_cull(
+        &mut self,
+        commands: &mut AutoCommandBufferBuilder<PrimaryAutoCommandBuffer>,
+        allocator: &StandardMemoryAllocator,
+        descriptor_set_allocator: &StandardDescriptorSetAllocator,
+        batches: &[DrawBatch],
+        instances: Arc<CpuAccessibleBuffer<[DrawInstance]>>,
+        instance_count: usize,
+        frusta: &[Frustum],
+    ) {
+        self.frame = None;
+
+        // Commands are grouped by material so each one binds its set once
+        let mut sorted: Vec<&DrawBatch> = batches
+            .iter()
+            .filter(|batch| self.pool.meshes.contains_key(&batch.mesh_id))
+            .collect();
+        sorted.sort_by_key(|batch| batch.material_id);
+
+        if sorted.is_empty()
+            || instance_count == 0
+            || frusta.is_empty()
+            || self.pool.vertex_buffer.is_none()
+        {
+            return;
+        }
+
+        let mut draw_commands = Vec::with_capacity(sorted.len() * frusta.len());
+        let mut spheres = Vec::with_capacity(sorted.len());
+        // Instances of skipped batches keep the sentinel and are never drawn
+        let mut instance_commands = vec![u32::MAX; instance_count];
+        let mut material_ranges: Vec<(usize, Range<u64>)> = Vec::new();
+
+        for (command, batch) in sorted.iter().enumerate() {
+            let mesh = &self.pool.meshes[&batch.mesh_id];
+            let lod = &mesh.submeshes[batch.submesh][batch.lod];
+
+            draw_commands.push(DrawIndexedIndirectCommand {
+                index_count: lod.index_count,
+                instance_count: 0,
+                first_index: lod.first_index,
+                vertex_offset: mesh.vertex_offset,
+                first_instance: batch.first_instance,
+            });
+            spheres.push(mesh.sphere);
+
+            let range = batch.first_instance as usize
+                ..(batch.first_instance + batch.instance_count) as usize;
+            instance_commands[range].fill(command as u32);
+
+            match material_ranges.last_mut() {
+                Some((material_id, range)) if *material_id == batch.material_id => {
+                    range.end += 1;
+                }
+                _ => material_ranges.push((batch.material_id, command as u64..command as u64 + 1)),
+            }
+        }
+
+        // Every view packs its survivors into its own stretch of the visible
+        // instances
+        let command_count = draw_commands.len();
+        for view in 1..frusta.len() {
+            for command in 0..command_count {
+                let mut draw_command = draw_commands[command];
+                draw_command.first_instance += (view * instance_count) as u32;
+                draw_commands.push(draw_command);
+            }
+        }
+
+        let draw_buffer = self.command_ring.next(allocator, draw_commands.len());
+        draw_buffer.write().unwrap()[..draw_commands.len()].copy_from_slice(&draw_commands);
+        let instance_command_buffer = self.instance_command_ring.next(allocator, instance_count);
+        instance_command_buffer.write().unwrap()[..instance_count]
+            .copy_from_slice(&instance_commands);
+        let sphere_buffer = self.sphere_ring.next(allocator, spheres.len());
+        sphere_buffer.write().unwrap()[..spheres.len()].copy_from_slice(&spheres);
+
+        let visible_instances = self.visible_instances(allocator, instance_count * frusta.len());
+
+        let cull_set = PersistentDescriptorSet::new(
+            descriptor_set_allocator,
+            self.cull_pipeline
+                .layout()
+                .set_layouts()
+                .get(0)
+                .unwrap()
+                .clone(),
+            [
+                WriteDescriptorSet::buffer(0, instances),
+                WriteDescriptorSet::buffer(1, instance_command_buffer),
+                WriteDescriptorSet::buffer(2, sphere_buffer),
+                WriteDescriptorSet::buffer(3, draw_buffer.clone()),
+                WriteDescriptorSet::buffer(4, visible_instances),
+            ],
+        )
+        .unwrap();
+
+        commands
+            .bind_pipeline_compute(self.cull_pipeline.clone())
+            .bind_descriptor_sets(
+                PipelineBindPoint::Compute,
+                self.cull_pipeline.layout().clone(),
+                0,
+                cull_set,
+            );
+        for (view, frustum) in frusta.iter().enumerate() {
+            commands
+                .push_constants(
+                    self.cull_pipeline.layout().clone(),
+                    0,
+                    cull_comp::ty::Cull_Data {
+                        planes: frustum.planes().map(|plane| plane.into()),
+                        count: [instance_count as u32, (view * command_count) as u32, 0, 0],
+                    },
+                )
+                .dispatch([(instance_count as u32).div_ceil(CULL_GROUP_SIZE), 1, 1])
+                .unwrap();
+        }
+
+        self.frame = Some(IndirectFrame {
+            commands: draw_buffer,
+            command_count: command_count as u64,
+            material_ranges,
+        });
+    }
+
+    /// Stops drawing freed meshes. Returns whether any of them was pooled, in
+    /// which case it takes up room until the next `upload_meshes`.
+    pub fn free_meshes(&mut self, mesh_ids: &[usize]) -> bool {
+        let pooled = self.pool.meshes.len();
+        self.pool
+            .meshes
+            .retain(|mesh_id, _| !mesh_ids.contains(mesh_id));
+        self.pool.meshes.len() != pooled
+    }
+
+    /// Drops the shared buffers, for when meshes are drawn from their own.
+    pub fn clear_meshes(&mut self) {
+        self.pool.vertex_buffer = None;
+        self.pool.index_buffer = None;
+        self.pool.meshes.clear();
+    }
+
+    /// Binds the shared buffers along with `instances` and draws `instance` of
+    /// them with level `lod` of `submesh` of a pooled mesh, with whatever
+    /// pipeline and descriptor sets are bound. Does nothing if the mesh isn't
+    /// in the pool.
+    #[allow(clippy::too_many_arguments)]
+    pub fn draw_pooled(
+        &self,
+        commands: &mut AutoCommandBufferBuilder<PrimaryAutoCommandBuffer>,
+        instances: Arc<CpuAccessibleBuffer<[DrawInstance]>>,
+        mesh_id: usize,
+        submesh: usize,
+        lod: usize,
+        instance: u32,
+    ) {
+        let Some(mesh) = self.pool.meshes.get(&mesh_id) else {
+            return;
+        };
+        let lod = &mesh.submeshes[submesh][lod];
+
+        commands
+            .bind_vertex_buffers(0, (self.pool.vertex_buffer.clone().unwrap(), instances))
+            .bind_index_buffer(self.pool.index_buffer.clone().unwrap())
+            .draw_indexed(
+                lod.index_count,
+                1,
+                lod.first_index,
+                mesh.vertex_offset as i32,
+                instance,
+            )
+            .unwrap();
+    }
+
+    /// Whether `record_cull` produced any commands this frame.
+    pub fn has_draws(&self) -> bool {
+        self.frame.is_some()
+    }
+
+    /// Materials of this frame's commands with the range of commands using
+    /// each, to be passed to `draw`. Covers every command of a view.
+    pub fn material_ranges(&self) -> &[(usize, Range<u64>)] {
+        self.frame
+            .as_ref()
+            .map_or(&[], |frame| frame.material_ranges.as_slice())
+    }
+
+    /// Binds the shared buffers and draws `range` of the commands culled for
+    /// `view`, the index of its frustum in `record_cull`, with whatever
+    /// pipeline and descriptor sets are bound.
+    pub fn draw(
+        &self,
+        commands: &mut AutoCommandBufferBuilder<PrimaryAutoCommandBuffer>,
+        view: usize,
+        range: Range<u64>,
+    ) {
+        let Some(frame) = self.frame.as_ref() else {
+            return;
+        };
+        if range.is_empty() {
+            return;
+        }
+        let offset = view as u64 * frame.command_count;
+        let range = range.start + offset..range.end + offset;
+
+        commands
+            .bind_vertex_buffers(
+                0,
+                (
+                    self.pool.vertex_buffer.clone().unwrap(),
+                    self.visible_instances.clone().unwrap(),
+                ),
+            )
+            .bind_index_buffer(self.pool.index_buffer.clone().unwrap());
+
+        let buffer = frame.commands.into_buffer_slice();
+        if self.multi_draw {
+            commands
+                .draw_indexed_indirect(buffer.slice(range).unwrap())
+                .unwrap();
+        } else {
+            // Without multiDrawIndirect every call is limited to one command
+            for command in range {
+                commands
+                    .draw_indexed_indirect(buffer.slice(command..command + 1).unwrap())
+                    .unwrap();
+            }
+        }
+    }
+
+    /// Device local buffer the culling pass packs the surviving instances
+    /// into, grown to hold at least `count` of them.
+    fn visible_instances(
+        &mut self,
+        allocator: &StandardMemoryAllocator,
+        count: usize,
+    ) -> Arc<DeviceLocalBuffer<[DrawInstance]>> {
+        if count > self.capacity || self.visible_instances.is_none() {
+            self.capacity = count.max(MIN_CAPACITY).next_power_of_two();
+            self.visible_instances = Some(
+                DeviceLocalBuffer::array(
+                    allocator,
+                    self.capacity as u64,
+                    BufferUsage {
+                        storage_buffer: true,
+                        vertex_buffer: true,
+                        ..BufferUsage::empty()
+                    },
+                    [],
+                )
+                .unwrap(),
+            );
+        }
+
+        self.visible_instances.clone().unwrap()
+    }
+}
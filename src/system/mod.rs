@@ -1,7 +1,25 @@
+mod antialiasing;
+mod bloom;
+mod buffer_ring;
+mod capture;
+mod frame;
+mod fullscreen;
+mod ibl;
+mod indirect;
+mod shadow;
+mod ssao;
 mod system;
+mod tonemap;
 
+pub use antialiasing::{AntiAliasingMode, AntiAliasingSettings};
+pub use bloom::BloomSettings;
+pub use capture::{Capture, CaptureTarget};
+pub use frame::Frame;
 use nalgebra_glm::{TVec3, vec3};
+pub use shadow::ShadowSettings;
+pub use ssao::SsaoSettings;
 pub use system::System;
+pub use tonemap::{TonemapOperator, TonemapSettings};
 
 #[derive(Default, Debug, Clone)]
 pub struct DirectionalLight {
@@ -2,9 +2,9 @@
 
 layout(input_attachment_index = 0, set = 0, binding = 0) uniform subpassInput u_albedo_ao;
 layout(input_attachment_index = 1, set = 0, binding = 1) uniform subpassInput u_surface; // RG = encoded normals, B = roughness, A = metalness
-layout(input_attachment_index = 2, set = 0, binding = 2) uniform subpassInput u_frag_position;
+layout(input_attachment_index = 2, set = 0, binding = 2) uniform subpassInput u_depth;
 
-layout(set = 0, binding = 3) uniform sampler2D skybox_hdr;
+layout(set = 0, binding = 3) uniform sampler2D irradiance_map;
 
 layout(set = 0, binding = 4) uniform Ambient_Data {
     vec3 color;
@@ -12,9 +12,16 @@ layout(set = 0, binding = 4) uniform Ambient_Data {
 } ambient;
 
 layout(set = 0, binding = 5) uniform Camera_Data {
+    mat4 inv_view_projection;
+    vec4 screen_size; // xy = size of the render target in pixels
     vec3 camera_pos;
 } camera;
 
+layout(set = 0, binding = 6) uniform sampler2D prefiltered_map; // one mip per roughness level
+layout(set = 0, binding = 7) uniform sampler2D brdf_lut; // RG = scale and bias applied to F0
+layout(set = 0, binding = 8) uniform sampler2D occlusion_map; // screen space ambient occlusion, 1 = unoccluded
+layout(input_attachment_index = 3, set = 0, binding = 9) uniform subpassInput u_emissive;
+
 layout(location = 0) out vec4 f_color;
 
 const float PI = 3.14159265359;
@@ -27,6 +34,13 @@ vec3 decode_octahedral(vec2 f) {
     return normalize(n);
 }
 
+// World position of this pixel from the depth written by the geometry pass
+vec3 reconstruct_position(float depth) {
+    vec2 ndc   = gl_FragCoord.xy / camera.screen_size.xy * 2.0 - 1.0;
+    vec4 world = camera.inv_view_projection * vec4(ndc, depth, 1.0);
+    return world.xyz / world.w;
+}
+
 vec2 directionToEquirect(vec3 dir) {
     dir = normalize(dir);
     float phi = atan(dir.z, dir.x);
@@ -34,79 +48,54 @@ vec2 directionToEquirect(vec3 dir) {
     return vec2(phi / (2.0 * PI) + 0.5, theta / PI + 0.5);
 }
 
-vec3 sampleSkybox(vec3 dir) {
-    return texture(skybox_hdr, directionToEquirect(dir)).rgb;
-}
-
-vec3 fresnelSchlick(float cosTheta, vec3 F0) {
-    return F0 + (1.0 - F0) * pow(clamp(1.0 - cosTheta, 0.0, 1.0), 5.0);
-}
-
 vec3 fresnelSchlickRoughness(float cosTheta, vec3 F0, float roughness) {
     return F0 + (max(vec3(1.0 - roughness), F0) - F0) * pow(clamp(1.0 - cosTheta, 0.0, 1.0), 5.0);
 }
 
-vec3 sampleSkyboxRoughness(vec3 dir, float roughness) {
-    vec3 color = vec3(0.0);
-    float totalWeight = 0.0;
-    
-    int samples = int(mix(1.0, 8.0, roughness));
-    
-    for (int i = 0; i < samples; i++) {
-        float angle = float(i) * 2.0 * PI / float(samples);
-        float spread = roughness * 0.3;
-        
-        vec3 tangent = normalize(cross(dir, vec3(0.0, 1.0, 0.0)));
-        if (length(tangent) < 0.1) {
-            tangent = normalize(cross(dir, vec3(1.0, 0.0, 0.0)));
-        }
-        vec3 bitangent = cross(dir, tangent);
-        
-        vec3 offset = tangent * cos(angle) * spread + bitangent * sin(angle) * spread;
-        vec3 sampleDir = normalize(dir + offset);
-        
-        color += sampleSkybox(sampleDir);
-        totalWeight += 1.0;
-    }
-    
-    return color / totalWeight;
+// The sampler blends between the two mips around the surface's roughness
+vec3 samplePrefiltered(vec3 dir, float roughness) {
+    float max_lod = float(textureQueryLevels(prefiltered_map) - 1);
+    return textureLod(prefiltered_map, directionToEquirect(dir), roughness * max_lod).rgb;
 }
 
 void main() {
     vec4 albedo_ao = subpassLoad(u_albedo_ao);
     vec4 surface   = subpassLoad(u_surface);
-    vec3 fragPos   = subpassLoad(u_frag_position).xyz;
+    float depth    = subpassLoad(u_depth).r;
+    vec3 emissive  = subpassLoad(u_emissive).rgb;
     vec3 camPos    = camera.camera_pos;
 
-    // Otherwise it kinda affects background too
-    if (length(fragPos) > 1000.0 || (albedo_ao.r == 0.0 && albedo_ao.g == 0.0 && albedo_ao.b == 0.0)) {
-        f_color = vec4(0.0, 0.0, 0.0, 1.0);
+    // Background pixels keep the cleared depth and are left to the skybox
+    if (depth >= 1.0) {
+        f_color = vec4(emissive, 1.0);
         return;
     }
 
+    vec3 fragPos = reconstruct_position(depth);
+
     vec3  albedo    = albedo_ao.rgb;
-    float ao        = albedo_ao.a;
+    float ao        = albedo_ao.a * texelFetch(occlusion_map, ivec2(gl_FragCoord.xy), 0).r;
     float roughness = surface.b;
     float metallic  = surface.a;
     vec3  normal    = decode_octahedral(surface.rg);
-    
+
     vec3  V     = normalize(camPos - fragPos);
     vec3  F0    = mix(vec3(0.04), albedo, metallic);
     float NdotV = max(dot(normal, V), 0.0);
     vec3  F     = fresnelSchlickRoughness(NdotV, F0, roughness);
-    
-    
-    vec3 R                  = reflect(-V, normal);
-    vec3 specularIrradiance = sampleSkyboxRoughness(R, roughness * 0.5);
 
-    vec3 irradiance = sampleSkyboxRoughness(normal, roughness);
+    vec3 irradiance = textureLod(irradiance_map, directionToEquirect(normal), 0.0).rgb;
     vec3 kD         = (1.0 - F) * (1.0 - metallic);
     vec3 diffuse    = kD * albedo * irradiance;
-    vec3 specular   = specularIrradiance * F;
-    
+
+    vec3 R           = reflect(-V, normal);
+    vec3 prefiltered = samplePrefiltered(R, roughness);
+    vec2 brdf        = textureLod(brdf_lut, vec2(NdotV, roughness), 0.0).rg;
+    vec3 specular    = prefiltered * (F0 * brdf.x + brdf.y);
+
     vec3 ambientColor = ambient.color * ambient.intensity;
-    
-    vec3 finalColor = (diffuse + specular) * ambientColor * ao;
-    finalColor     += irradiance * ambientColor * 0.1 * ao;
+
+    // Emitted light isn't shaded, so it skips ambient occlusion as well
+    vec3 finalColor = (diffuse + specular) * ambientColor * ao + emissive;
     f_color         = vec4(finalColor, 1.0);
-}
\ No newline at end of file
+}
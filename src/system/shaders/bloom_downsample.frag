@@ -0,0 +1,59 @@
+#version 450
+
+layout(location = 0) in vec2 frag_coord;
+
+layout(set = 0, binding = 0) uniform sampler2D source;
+
+layout(set = 0, binding = 1) uniform Downsample_Data {
+    vec4 params; // x = threshold, y = soft knee, z = 1 when reading the HDR image
+} downsample;
+
+layout(location = 0) out vec4 f_color;
+
+// 13 taps forming overlapping 2x2 boxes, a single bilinear tap flickers as
+// bright pixels move between texels
+vec3 downsample13(vec2 uv) {
+    vec2 texel = 1.0 / vec2(textureSize(source, 0));
+
+    vec3 a = texture(source, uv + texel * vec2(-2.0, -2.0)).rgb;
+    vec3 b = texture(source, uv + texel * vec2( 0.0, -2.0)).rgb;
+    vec3 c = texture(source, uv + texel * vec2( 2.0, -2.0)).rgb;
+    vec3 d = texture(source, uv + texel * vec2(-1.0, -1.0)).rgb;
+    vec3 e = texture(source, uv + texel * vec2( 1.0, -1.0)).rgb;
+    vec3 f = texture(source, uv + texel * vec2(-2.0,  0.0)).rgb;
+    vec3 g = texture(source, uv).rgb;
+    vec3 h = texture(source, uv + texel * vec2( 2.0,  0.0)).rgb;
+    vec3 i = texture(source, uv + texel * vec2(-1.0,  1.0)).rgb;
+    vec3 j = texture(source, uv + texel * vec2( 1.0,  1.0)).rgb;
+    vec3 k = texture(source, uv + texel * vec2(-2.0,  2.0)).rgb;
+    vec3 l = texture(source, uv + texel * vec2( 0.0,  2.0)).rgb;
+    vec3 m = texture(source, uv + texel * vec2( 2.0,  2.0)).rgb;
+
+    vec3 result = (d + e + i + j) * 0.125;
+    result += (a + b + f + g) * 0.03125;
+    result += (b + c + g + h) * 0.03125;
+    result += (f + g + k + l) * 0.03125;
+    result += (g + h + l + m) * 0.03125;
+    return result;
+}
+
+// Keeps what is brighter than the threshold, fading in over the knee
+vec3 threshold(vec3 color) {
+    float brightness = max(color.r, max(color.g, color.b));
+    float knee       = downsample.params.x * downsample.params.y;
+
+    float soft = clamp(brightness - downsample.params.x + knee, 0.0, 2.0 * knee);
+    soft       = soft * soft / (4.0 * knee + 0.00001);
+
+    float contribution = max(soft, brightness - downsample.params.x) / max(brightness, 0.00001);
+    return color * contribution;
+}
+
+void main() {
+    vec3 color = downsample13(frag_coord);
+
+    if (downsample.params.z > 0.5)
+        color = threshold(color);
+
+    f_color = vec4(color, 1.0);
+}
@@ -0,0 +1,28 @@
+#version 450
+
+layout(location = 0) in vec2 frag_coord;
+
+layout(set = 0, binding = 0) uniform sampler2D source;
+
+layout(set = 0, binding = 1) uniform Upsample_Data {
+    vec4 params; // x = filter radius in source texels
+} upsample;
+
+layout(location = 0) out vec4 f_color;
+
+void main() {
+    vec2 offset = upsample.params.x / vec2(textureSize(source, 0));
+
+    // 3x3 tent filter, blended additively onto the larger level
+    vec3 color = texture(source, frag_coord).rgb * 4.0;
+    color += texture(source, frag_coord + vec2(-offset.x, 0.0)).rgb * 2.0;
+    color += texture(source, frag_coord + vec2( offset.x, 0.0)).rgb * 2.0;
+    color += texture(source, frag_coord + vec2(0.0, -offset.y)).rgb * 2.0;
+    color += texture(source, frag_coord + vec2(0.0,  offset.y)).rgb * 2.0;
+    color += texture(source, frag_coord + vec2(-offset.x, -offset.y)).rgb;
+    color += texture(source, frag_coord + vec2( offset.x, -offset.y)).rgb;
+    color += texture(source, frag_coord + vec2(-offset.x,  offset.y)).rgb;
+    color += texture(source, frag_coord + vec2( offset.x,  offset.y)).rgb;
+
+    f_color = vec4(color / 16.0, 1.0);
+}
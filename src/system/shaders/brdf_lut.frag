@@ -0,0 +1,65 @@
+#version 450
+
+layout(location = 0) in vec2 frag_coord; // x = N dot V, y = roughness
+
+layout(location = 0) out vec2 f_color; // x = scale, y = bias applied to F0
+
+const float PI          = 3.14159265359;
+const uint SAMPLE_COUNT = 1024u;
+
+float radicalInverse(uint bits) {
+    bits = (bits << 16u) | (bits >> 16u);
+    bits = ((bits & 0x55555555u) << 1u) | ((bits & 0xAAAAAAAAu) >> 1u);
+    bits = ((bits & 0x33333333u) << 2u) | ((bits & 0xCCCCCCCCu) >> 2u);
+    bits = ((bits & 0x0F0F0F0Fu) << 4u) | ((bits & 0xF0F0F0F0u) >> 4u);
+    bits = ((bits & 0x00FF00FFu) << 8u) | ((bits & 0xFF00FF00u) >> 8u);
+    return float(bits) * 2.3283064365386963e-10;
+}
+
+vec2 hammersley(uint i, uint count) {
+    return vec2(float(i) / float(count), radicalInverse(i));
+}
+
+vec3 importanceSampleGGX(vec2 xi, float roughness) {
+    float a        = roughness * roughness;
+    float phi      = 2.0 * PI * xi.x;
+    float cosTheta = sqrt((1.0 - xi.y) / (1.0 + (a * a - 1.0) * xi.y));
+    float sinTheta = sqrt(1.0 - cosTheta * cosTheta);
+    return vec3(cos(phi) * sinTheta, sin(phi) * sinTheta, cosTheta);
+}
+
+float geometrySchlickGGX(float ndv, float roughness) {
+    // IBL uses k = a / 2 rather than the (a + 1)^2 / 8 of direct lighting
+    float k = roughness * roughness / 2.0;
+    return ndv / (ndv * (1.0 - k) + k);
+}
+
+void main() {
+    float ndv       = max(frag_coord.x, 0.001);
+    float roughness = frag_coord.y;
+
+    // Tangent space with the normal along z
+    vec3 viewDir = vec3(sqrt(1.0 - ndv * ndv), 0.0, ndv);
+
+    float scale = 0.0;
+    float bias  = 0.0;
+    for (uint i = 0u; i < SAMPLE_COUNT; i++) {
+        vec3 halfway  = importanceSampleGGX(hammersley(i, SAMPLE_COUNT), roughness);
+        vec3 lightDir = normalize(2.0 * dot(viewDir, halfway) * halfway - viewDir);
+
+        float ndl = max(lightDir.z, 0.0);
+        float ndh = max(halfway.z, 0.0);
+        float vdh = max(dot(viewDir, halfway), 0.0);
+
+        if (ndl > 0.0) {
+            float g    = geometrySchlickGGX(ndv, roughness) * geometrySchlickGGX(ndl, roughness);
+            float gVis = g * vdh / (ndh * ndv);
+            float fc   = pow(1.0 - vdh, 5.0);
+
+            scale += (1.0 - fc) * gVis;
+            bias  += fc * gVis;
+        }
+    }
+
+    f_color = vec2(scale, bias) / float(SAMPLE_COUNT);
+}
@@ -2,15 +2,23 @@
 
 layout(location = 0) in vec3 in_normal;
 layout(location = 1) in vec2 in_tex_coords;
-layout(location = 2) in vec3 in_world_pos;
-layout(location = 3) in vec4 in_tangent;
-
+layout(location = 2) in vec4 in_tangent;
+layout(location = 3) in vec4 in_current_clip;
+layout(location = 4) in vec4 in_previous_clip;
+
+layout(set = 1, binding = 0) uniform Material_Data {
+    vec4 params;   // x = alpha cutoff, 0 when not a cutout
+    vec4 emissive; // rgb = factor multiplied with tex_emissive
+} material;
 layout(set = 1, binding = 1) uniform sampler2D tex_albedo_ao; // RGB = color, A = AO
 layout(set = 1, binding = 2) uniform sampler2D tex_material; // RG = encoded normals, B = roughness, A = metalness
+layout(set = 1, binding = 3) uniform sampler2D tex_mask; // R = opacity
+layout(set = 1, binding = 4) uniform sampler2D tex_emissive;
 
 layout(location = 0) out vec4 gbuffer_color;
 layout(location = 1) out vec4 gbuffer_surface;
-layout(location = 2) out vec4 gbuffer_position;
+layout(location = 2) out vec2 gbuffer_velocity; // UV offset from last frame to this one
+layout(location = 3) out vec4 gbuffer_emissive;
 
 vec3 decode_octahedral(vec2 f) {
     f = f * 2.0 - 1.0;
@@ -28,6 +36,9 @@ vec2 encode_octahedral(vec3 n) {
 }
 
 void main() {
+    if (texture(tex_mask, in_tex_coords).r < material.params.x)
+        discard;
+
     vec4 albedo_ao   = texture(tex_albedo_ao, in_tex_coords);
     vec4 mat         = texture(tex_material, in_tex_coords);
 
@@ -42,5 +53,6 @@ void main() {
 
     gbuffer_color    = albedo_ao;
     gbuffer_surface  = vec4(encoded, mat.b, mat.a);
-    gbuffer_position = vec4(in_world_pos, 1.0);
+    gbuffer_velocity = (in_current_clip.xy / in_current_clip.w - in_previous_clip.xy / in_previous_clip.w) * 0.5;
+    gbuffer_emissive = vec4(texture(tex_emissive, in_tex_coords).rgb * material.emissive.rgb, 1.0);
 }
\ No newline at end of file
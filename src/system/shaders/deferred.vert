@@ -6,15 +6,19 @@ layout(location = 2) in vec4 tangent;
 layout(location = 3) in vec2 uv;
 layout(location = 4) in mat4 instance_model;
 layout(location = 8) in mat4 instance_normal;
+layout(location = 12) in mat4 instance_previous_model;
 
 layout(location = 0) out vec3 out_normal;
 layout(location = 1) out vec2 out_tex_coords;
-layout(location = 2) out vec3 out_world_pos;
-layout(location = 3) out vec4 out_tangent;
+layout(location = 2) out vec4 out_tangent;
+layout(location = 3) out vec4 out_current_clip;
+layout(location = 4) out vec4 out_previous_clip;
 
 layout(set = 0, binding = 0) uniform VP_Data {
     mat4 view;
-    mat4 projection;
+    mat4 projection; // jittered when temporal anti-aliasing is on
+    mat4 view_projection; // without jitter, for motion vectors
+    mat4 previous_view_projection;
 } vp_uniforms;
 
 void main() {
@@ -24,5 +28,7 @@ void main() {
     out_normal     = normalize(mat3(instance_normal) * normal);
     out_tangent    = vec4(normalize(mat3(instance_normal) * tangent.xyz), tangent.w);
     out_tex_coords = uv;
-    out_world_pos  = world_pos.xyz;
-}
\ No newline at end of file
+
+    out_current_clip  = vp_uniforms.view_projection * world_pos;
+    out_previous_clip = vp_uniforms.previous_view_projection * instance_previous_model * vec4(position, 1.0);
+}
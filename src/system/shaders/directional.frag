@@ -2,7 +2,7 @@
 
 layout(input_attachment_index = 0, set = 0, binding = 0) uniform subpassInput u_albedo_ao; // RGB = color, A = AO
 layout(input_attachment_index = 1, set = 0, binding = 1) uniform subpassInput u_surface; // RG = encoded normals, B = roughness, A = metalness
-layout(input_attachment_index = 2, set = 0, binding = 2) uniform subpassInput u_frag_position;
+layout(input_attachment_index = 2, set = 0, binding = 2) uniform subpassInput u_depth;
 
 layout(set = 0, binding = 4) uniform Directional_Light_Data {
     vec4 position;  // xyz = direction
@@ -10,11 +10,29 @@ layout(set = 0, binding = 4) uniform Directional_Light_Data {
 } light;
 
 layout(set = 0, binding = 5) uniform Camera_Data {
+    mat4 inv_view_projection;
+    vec4 screen_size; // xy = size of the render target in pixels
     vec3 position;
 } camera;
 
+layout(set = 0, binding = 6) uniform sampler2DArray shadow_map;
+
+layout(set = 0, binding = 7) uniform Shadow_Data {
+    mat4 light_vp[4];
+    vec4 splits;         // view distance at which each cascade ends
+    vec4 camera_forward; // xyz = camera view direction
+    vec4 params;         // x = depth bias, y = PCF radius in texels, z = blend fraction, w = debug view
+} shadow;
+
 layout(location = 0) out vec4 f_color;
 
+const vec3 CASCADE_COLORS[4] = vec3[](
+    vec3(1.0, 0.2, 0.2),
+    vec3(0.2, 1.0, 0.2),
+    vec3(0.2, 0.4, 1.0),
+    vec3(1.0, 1.0, 0.2)
+);
+
 vec3 decode_octahedral(vec2 f) {
     f = f * 2.0 - 1.0;
     vec3 n = vec3(f.x, f.y, 1.0 - abs(f.x) - abs(f.y));
@@ -23,10 +41,80 @@ vec3 decode_octahedral(vec2 f) {
     return normalize(n);
 }
 
+// World position of this pixel from the depth written by the geometry pass
+vec3 reconstruct_position(float depth) {
+    vec2 ndc   = gl_FragCoord.xy / camera.screen_size.xy * 2.0 - 1.0;
+    vec4 world = camera.inv_view_projection * vec4(ndc, depth, 1.0);
+    return world.xyz / world.w;
+}
+
+float cascadeShadow(int cascade, vec3 fragPos, vec3 normal, vec3 lightDir) {
+    vec4 lightClip = shadow.light_vp[cascade] * vec4(fragPos, 1.0);
+    vec3 ndc       = lightClip.xyz / lightClip.w;
+    vec2 uv        = ndc.xy * 0.5 + 0.5;
+
+    // Outside of the shadow map counts as lit
+    if (ndc.z > 1.0 || any(lessThan(uv, vec2(0.0))) || any(greaterThan(uv, vec2(1.0))))
+        return 1.0;
+
+    // Surfaces at grazing angles to the light need more bias to avoid acne
+    float bias   = shadow.params.x * (1.0 + 4.0 * (1.0 - max(dot(normal, lightDir), 0.0)));
+    int   radius = int(shadow.params.y);
+    vec2  texel  = 1.0 / vec2(textureSize(shadow_map, 0).xy);
+
+    float lit = 0.0;
+    for (int x = -radius; x <= radius; x++) {
+        for (int y = -radius; y <= radius; y++) {
+            float depth = texture(shadow_map, vec3(uv + vec2(x, y) * texel, cascade)).r;
+            lit += ndc.z - bias <= depth ? 1.0 : 0.0;
+        }
+    }
+
+    float taps = float((2 * radius + 1) * (2 * radius + 1));
+    return lit / taps;
+}
+
+int selectCascade(float viewDepth) {
+    for (int i = 0; i < 3; i++) {
+        if (viewDepth < shadow.splits[i])
+            return i;
+    }
+    return 3;
+}
+
+float shadowFactor(int cascade, float viewDepth, vec3 fragPos, vec3 normal, vec3 lightDir) {
+    // Past the last cascade nothing is shadowed
+    if (viewDepth > shadow.splits[3])
+        return 1.0;
+
+    float lit = cascadeShadow(cascade, fragPos, normal, lightDir);
+
+    // Fade into the next cascade near the far end to hide the seam
+    if (cascade < 3) {
+        float start      = cascade == 0 ? 0.0 : shadow.splits[cascade - 1];
+        float end        = shadow.splits[cascade];
+        float blendStart = end - (end - start) * shadow.params.z;
+        if (viewDepth > blendStart) {
+            float t = smoothstep(blendStart, end, viewDepth);
+            lit = mix(lit, cascadeShadow(cascade + 1, fragPos, normal, lightDir), t);
+        }
+    }
+
+    return lit;
+}
+
 void main() {
+    float depth = subpassLoad(u_depth).r;
+
+    // Background pixels keep the cleared depth and are left to the skybox
+    if (depth >= 1.0) {
+        f_color = vec4(0.0);
+        return;
+    }
+
     vec4 albedoAO = subpassLoad(u_albedo_ao);
     vec4 surface  = subpassLoad(u_surface);
-    vec3 fragPos  = subpassLoad(u_frag_position).xyz;
+    vec3 fragPos  = reconstruct_position(depth);
 
     vec3 albedo = albedoAO.rgb;
     float ao    = albedoAO.a;
@@ -48,6 +136,14 @@ void main() {
     float spec    = pow(ndh, 1.0 / max(roughness, 0.001));
     vec3 specular = spec * mix(vec3(0.04), albedo, metallic);
 
-    vec3 color = (diffuse + specular) * ao;
-    f_color    = vec4(color, 1.0);
+    float viewDepth = dot(fragPos - camera.position, shadow.camera_forward.xyz);
+    int cascade     = selectCascade(viewDepth);
+
+    float lit  = shadowFactor(cascade, viewDepth, fragPos, normal, lightDir);
+    vec3 color = (diffuse + specular) * ao * lit;
+
+    if (shadow.params.w > 0.5)
+        color = CASCADE_COLORS[cascade] * (0.25 + 0.75 * lit);
+
+    f_color = vec4(color, 1.0);
 }
\ No newline at end of file
@@ -0,0 +1,58 @@
+#version 450
+
+layout(location = 0) in vec2 frag_coord;
+
+layout(set = 0, binding = 0) uniform sampler2D u_color;
+
+layout(location = 0) out vec4 f_color;
+
+const float EDGE_THRESHOLD     = 1.0 / 8.0;
+const float EDGE_THRESHOLD_MIN = 1.0 / 32.0;
+const float REDUCE_MUL         = 1.0 / 8.0;
+const float REDUCE_MIN         = 1.0 / 128.0;
+const float SPAN_MAX           = 8.0;
+
+// Edges are found on roughly tonemapped luminance so bright HDR pixels don't dominate
+float luma(vec3 color) {
+    return dot(color / (1.0 + color), vec3(0.299, 0.587, 0.114));
+}
+
+vec3 fetch(vec2 uv) {
+    return texture(u_color, uv).rgb;
+}
+
+void main() {
+    vec2 texel = 1.0 / vec2(textureSize(u_color, 0));
+
+    vec3 rgbM = fetch(frag_coord);
+    float lumaNW = luma(fetch(frag_coord + vec2(-1.0, -1.0) * texel));
+    float lumaNE = luma(fetch(frag_coord + vec2( 1.0, -1.0) * texel));
+    float lumaSW = luma(fetch(frag_coord + vec2(-1.0,  1.0) * texel));
+    float lumaSE = luma(fetch(frag_coord + vec2( 1.0,  1.0) * texel));
+    float lumaM  = luma(rgbM);
+
+    float lumaMin = min(lumaM, min(min(lumaNW, lumaNE), min(lumaSW, lumaSE)));
+    float lumaMax = max(lumaM, max(max(lumaNW, lumaNE), max(lumaSW, lumaSE)));
+
+    if (lumaMax - lumaMin < max(EDGE_THRESHOLD_MIN, lumaMax * EDGE_THRESHOLD)) {
+        f_color = vec4(rgbM, 1.0);
+        return;
+    }
+
+    vec2 dir = vec2(
+        -((lumaNW + lumaNE) - (lumaSW + lumaSE)),
+         ((lumaNW + lumaSW) - (lumaNE + lumaSE))
+    );
+
+    float dirReduce = max((lumaNW + lumaNE + lumaSW + lumaSE) * 0.25 * REDUCE_MUL, REDUCE_MIN);
+    float rcpDirMin = 1.0 / (min(abs(dir.x), abs(dir.y)) + dirReduce);
+    dir = clamp(dir * rcpDirMin, vec2(-SPAN_MAX), vec2(SPAN_MAX)) * texel;
+
+    vec3 rgbA = 0.5 * (fetch(frag_coord + dir * (1.0 / 3.0 - 0.5)) +
+                       fetch(frag_coord + dir * (2.0 / 3.0 - 0.5)));
+    vec3 rgbB = rgbA * 0.5 + 0.25 * (fetch(frag_coord - dir * 0.5) +
+                                     fetch(frag_coord + dir * 0.5));
+
+    float lumaB = luma(rgbB);
+    f_color = vec4((lumaB < lumaMin || lumaB > lumaMax) ? rgbA : rgbB, 1.0);
+}
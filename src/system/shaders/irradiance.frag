@@ -0,0 +1,46 @@
+#version 450
+
+layout(location = 0) in vec2 frag_coord;
+
+layout(set = 0, binding = 0) uniform sampler2D environment;
+
+layout(location = 0) out vec4 f_color;
+
+const float PI           = 3.14159265359;
+const float SAMPLE_DELTA = 0.025;
+
+vec2 directionToEquirect(vec3 dir) {
+    float phi   = atan(dir.z, dir.x);
+    float theta = asin(clamp(dir.y, -1.0, 1.0));
+    return vec2(phi / (2.0 * PI) + 0.5, theta / PI + 0.5);
+}
+
+vec3 equirectToDirection(vec2 uv) {
+    float phi   = (uv.x - 0.5) * 2.0 * PI;
+    float theta = (uv.y - 0.5) * PI;
+    return vec3(cos(theta) * cos(phi), sin(theta), cos(theta) * sin(phi));
+}
+
+void main() {
+    vec3 normal = equirectToDirection(frag_coord);
+    vec3 up     = abs(normal.y) < 0.999 ? vec3(0.0, 1.0, 0.0) : vec3(1.0, 0.0, 0.0);
+    vec3 right  = normalize(cross(up, normal));
+    up          = cross(normal, right);
+
+    // Read from a mip whose texels roughly match the spacing of the samples
+    float lod = max(log2(float(textureSize(environment, 0).x) / 256.0), 0.0);
+
+    vec3 irradiance = vec3(0.0);
+    float samples   = 0.0;
+    for (float phi = 0.0; phi < 2.0 * PI; phi += SAMPLE_DELTA) {
+        for (float theta = 0.0; theta < 0.5 * PI; theta += SAMPLE_DELTA) {
+            vec3 tangent = vec3(sin(theta) * cos(phi), sin(theta) * sin(phi), cos(theta));
+            vec3 dir     = tangent.x * right + tangent.y * up + tangent.z * normal;
+
+            irradiance += textureLod(environment, directionToEquirect(dir), lod).rgb * cos(theta) * sin(theta);
+            samples    += 1.0;
+        }
+    }
+
+    f_color = vec4(PI * irradiance / samples, 1.0);
+}
@@ -0,0 +1,100 @@
+#version 450
+
+layout(input_attachment_index = 0, set = 0, binding = 0) uniform subpassInput u_albedo_ao; // RGB = color, A = AO
+layout(input_attachment_index = 1, set = 0, binding = 1) uniform subpassInput u_surface; // RG = encoded normals, B = roughness, A = metalness
+layout(input_attachment_index = 2, set = 0, binding = 2) uniform subpassInput u_depth;
+
+struct Local_Light {
+    vec4 position;  // xyz = position, w = range
+    vec4 color;     // rgb = color * intensity
+    vec4 direction; // xyz = spot direction
+    vec4 cone;      // x = cos inner angle, y = cos outer angle, z = 1 for spot lights
+};
+
+// Must match MAX_LOCAL_LIGHTS in system.rs
+layout(set = 0, binding = 4) uniform Local_Light_Data {
+    Local_Light lights[64];
+    ivec4 count; // x = number of lights used
+} data;
+
+layout(set = 0, binding = 5) uniform Camera_Data {
+    mat4 inv_view_projection;
+    vec4 screen_size; // xy = size of the render target in pixels
+    vec3 position;
+} camera;
+
+layout(location = 0) out vec4 f_color;
+
+vec3 decode_octahedral(vec2 f) {
+    f = f * 2.0 - 1.0;
+    vec3 n = vec3(f.x, f.y, 1.0 - abs(f.x) - abs(f.y));
+    if (n.z < 0.0)
+        n.xy = (1.0 - abs(n.yx)) * sign(n.xy);
+    return normalize(n);
+}
+
+// World position of this pixel from the depth written by the geometry pass
+vec3 reconstruct_position(float depth) {
+    vec2 ndc   = gl_FragCoord.xy / camera.screen_size.xy * 2.0 - 1.0;
+    vec4 world = camera.inv_view_projection * vec4(ndc, depth, 1.0);
+    return world.xyz / world.w;
+}
+
+// Inverse square falloff windowed so it reaches zero at the light's range
+float attenuation(float distance, float range) {
+    float ratio  = distance / range;
+    float window = clamp(1.0 - ratio * ratio * ratio * ratio, 0.0, 1.0);
+    return window * window / (distance * distance + 1.0);
+}
+
+void main() {
+    float depth = subpassLoad(u_depth).r;
+
+    // Background pixels keep the cleared depth, black materials are still lit
+    if (depth >= 1.0) {
+        f_color = vec4(0.0);
+        return;
+    }
+
+    vec4 albedoAO = subpassLoad(u_albedo_ao);
+    vec4 surface  = subpassLoad(u_surface);
+    vec3 fragPos  = reconstruct_position(depth);
+
+    vec3 albedo     = albedoAO.rgb;
+    float ao        = albedoAO.a;
+    float roughness = surface.b;
+    float metallic  = surface.a;
+    vec3 normal     = decode_octahedral(surface.rg);
+    vec3 viewDir    = normalize(camera.position - fragPos);
+    vec3 F0         = mix(vec3(0.04), albedo, metallic);
+
+    vec3 color = vec3(0.0);
+    for (int i = 0; i < data.count.x; i++) {
+        Local_Light light = data.lights[i];
+
+        vec3 toLight   = light.position.xyz - fragPos;
+        float distance = length(toLight);
+        if (distance >= light.position.w)
+            continue;
+
+        vec3 lightDir = toLight / distance;
+        float falloff = attenuation(distance, light.position.w);
+
+        if (light.cone.z > 0.5) {
+            float theta = dot(-lightDir, normalize(light.direction.xyz));
+            falloff *= smoothstep(light.cone.y, light.cone.x, theta);
+        }
+
+        float ndl    = max(dot(normal, lightDir), 0.0);
+        vec3 diffuse = albedo * ndl;
+
+        vec3 halfway  = normalize(lightDir + viewDir);
+        float ndh     = max(dot(normal, halfway), 0.0);
+        float spec    = pow(ndh, 1.0 / max(roughness, 0.001));
+        vec3 specular = spec * F0;
+
+        color += (diffuse + specular) * light.color.rgb * falloff;
+    }
+
+    f_color = vec4(color * ao, 1.0);
+}
@@ -0,0 +1,100 @@
+#version 450
+
+layout(location = 0) in vec2 frag_coord;
+
+layout(set = 0, binding = 0) uniform sampler2D environment;
+
+layout(set = 0, binding = 1) uniform Prefilter_Data {
+    float roughness;
+    float target_width;
+} prefilter;
+
+layout(location = 0) out vec4 f_color;
+
+const float PI          = 3.14159265359;
+const uint SAMPLE_COUNT = 512u;
+
+vec2 directionToEquirect(vec3 dir) {
+    float phi   = atan(dir.z, dir.x);
+    float theta = asin(clamp(dir.y, -1.0, 1.0));
+    return vec2(phi / (2.0 * PI) + 0.5, theta / PI + 0.5);
+}
+
+vec3 equirectToDirection(vec2 uv) {
+    float phi   = (uv.x - 0.5) * 2.0 * PI;
+    float theta = (uv.y - 0.5) * PI;
+    return vec3(cos(theta) * cos(phi), sin(theta), cos(theta) * sin(phi));
+}
+
+float radicalInverse(uint bits) {
+    bits = (bits << 16u) | (bits >> 16u);
+    bits = ((bits & 0x55555555u) << 1u) | ((bits & 0xAAAAAAAAu) >> 1u);
+    bits = ((bits & 0x33333333u) << 2u) | ((bits & 0xCCCCCCCCu) >> 2u);
+    bits = ((bits & 0x0F0F0F0Fu) << 4u) | ((bits & 0xF0F0F0F0u) >> 4u);
+    bits = ((bits & 0x00FF00FFu) << 8u) | ((bits & 0xFF00FF00u) >> 8u);
+    return float(bits) * 2.3283064365386963e-10;
+}
+
+vec2 hammersley(uint i, uint count) {
+    return vec2(float(i) / float(count), radicalInverse(i));
+}
+
+vec3 importanceSampleGGX(vec2 xi, vec3 normal, float roughness) {
+    float a        = roughness * roughness;
+    float phi      = 2.0 * PI * xi.x;
+    float cosTheta = sqrt((1.0 - xi.y) / (1.0 + (a * a - 1.0) * xi.y));
+    float sinTheta = sqrt(1.0 - cosTheta * cosTheta);
+
+    vec3 h         = vec3(cos(phi) * sinTheta, sin(phi) * sinTheta, cosTheta);
+    vec3 up        = abs(normal.y) < 0.999 ? vec3(0.0, 1.0, 0.0) : vec3(1.0, 0.0, 0.0);
+    vec3 tangent   = normalize(cross(up, normal));
+    vec3 bitangent = cross(normal, tangent);
+
+    return normalize(tangent * h.x + bitangent * h.y + normal * h.z);
+}
+
+float distributionGGX(float ndh, float roughness) {
+    float a2    = roughness * roughness * roughness * roughness;
+    float denom = ndh * ndh * (a2 - 1.0) + 1.0;
+    return a2 / (PI * denom * denom);
+}
+
+void main() {
+    vec3 normal  = equirectToDirection(frag_coord);
+    vec2 envSize = vec2(textureSize(environment, 0));
+
+    // A mirror needs no integration, just a mip matching the target's texels
+    if (prefilter.roughness < 0.001) {
+        float lod = max(log2(envSize.x / prefilter.target_width), 0.0);
+        f_color   = vec4(textureLod(environment, frag_coord, lod).rgb, 1.0);
+        return;
+    }
+
+    // Assume the reflection is viewed head on, as the split sum does
+    vec3 viewDir = normal;
+
+    // Solid angle of one texel of the environment, used to pick a mip per sample
+    float texelSolidAngle = 4.0 * PI / (envSize.x * envSize.y);
+
+    vec3 color   = vec3(0.0);
+    float weight = 0.0;
+    for (uint i = 0u; i < SAMPLE_COUNT; i++) {
+        vec3 halfway  = importanceSampleGGX(hammersley(i, SAMPLE_COUNT), normal, prefilter.roughness);
+        vec3 lightDir = normalize(2.0 * dot(viewDir, halfway) * halfway - viewDir);
+
+        float ndl = dot(normal, lightDir);
+        if (ndl > 0.0) {
+            float ndh = max(dot(normal, halfway), 0.0);
+            float hdv = max(dot(halfway, viewDir), 0.0);
+            float pdf = distributionGGX(ndh, prefilter.roughness) * ndh / (4.0 * hdv) + 0.0001;
+
+            float sampleSolidAngle = 1.0 / (float(SAMPLE_COUNT) * pdf + 0.0001);
+            float lod              = max(0.5 * log2(sampleSolidAngle / texelSolidAngle), 0.0);
+
+            color  += textureLod(environment, directionToEquirect(lightDir), lod).rgb * ndl;
+            weight += ndl;
+        }
+    }
+
+    f_color = vec4(color / weight, 1.0);
+}
@@ -0,0 +1,16 @@
+#version 450
+
+layout(location = 0) in vec3 position;
+layout(location = 3) in vec2 uv;
+layout(location = 4) in mat4 instance_model;
+
+layout(location = 0) out vec2 out_tex_coords; // only read by cutout materials
+
+layout(set = 0, binding = 0) uniform Light_Data {
+    mat4 light_vp;
+} light;
+
+void main() {
+    gl_Position    = light.light_vp * instance_model * vec4(position, 1.0);
+    out_tex_coords = uv;
+}
@@ -0,0 +1,14 @@
+#version 450
+
+layout(location = 0) in vec2 in_tex_coords;
+
+layout(set = 1, binding = 0) uniform Material_Data {
+    vec4 params; // x = alpha cutoff
+} material;
+layout(set = 1, binding = 1) uniform sampler2D tex_mask; // R = opacity
+
+// Depth only, holes in the mask cast no shadow
+void main() {
+    if (texture(tex_mask, in_tex_coords).r < material.params.x)
+        discard;
+}
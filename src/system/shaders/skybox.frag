@@ -2,6 +2,8 @@
 
 layout(location = 0) in vec2 frag_coord;
 
+layout(input_attachment_index = 2, set = 0, binding = 2) uniform subpassInput u_depth;
+
 layout(set = 0, binding = 0) uniform sampler2D hdr_sky;
 
 layout(set = 0, binding = 1) uniform Camera {
@@ -26,6 +28,10 @@ vec2 directionToEquirect(vec3 dir) {
 }
 
 void main() {
+    // Only where the geometry pass left the cleared depth
+    if (subpassLoad(u_depth).r < 1.0)
+        discard;
+
     vec4 ndc = vec4(frag_coord * 2.0 - 1.0, -1.0, 1.0); 
 
     vec4 view = camera.inv_proj * ndc;
@@ -35,7 +41,8 @@ void main() {
     vec3 ray       = normalize(world_pos.xyz - camera.camera_pos);
 
     vec2 uv    = directionToEquirect(ray);
-    vec3 color = texture(hdr_sky, uv).rgb;
+    // The equirect seam would otherwise pick the smallest mip
+    vec3 color = textureLod(hdr_sky, uv, 0.0).rgb;
     
     f_color = vec4(color, 1.0);
 }
\ No newline at end of file
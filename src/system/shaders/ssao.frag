@@ -0,0 +1,100 @@
+#version 450
+
+layout(location = 0) in vec2 frag_coord;
+
+layout(set = 0, binding = 0) uniform sampler2D u_surface; // RG = encoded normals
+layout(set = 0, binding = 1) uniform sampler2D u_depth; // 1 where no geometry was drawn
+
+layout(set = 0, binding = 2) uniform SSAO_Data {
+    mat4 view;
+    mat4 projection;
+    mat4 inv_projection;
+    vec4 params; // x = radius, y = strength, z = depth bias
+} data;
+
+layout(location = 0) out float f_occlusion;
+
+const int KERNEL_SIZE = 16;
+const float PI = 3.14159265359;
+
+// Rotation of the kernel per pixel in a 4x4 tile, evened out by the blur pass
+const float ROTATIONS[16] = float[](
+     0.0,  8.0,  2.0, 10.0,
+    12.0,  4.0, 14.0,  6.0,
+     3.0, 11.0,  1.0,  9.0,
+    15.0,  7.0, 13.0,  5.0
+);
+
+vec3 decode_octahedral(vec2 f) {
+    f = f * 2.0 - 1.0;
+    vec3 n = vec3(f.x, f.y, 1.0 - abs(f.x) - abs(f.y));
+    if (n.z < 0.0)
+        n.xy = (1.0 - abs(n.yx)) * sign(n.xy);
+    return normalize(n);
+}
+
+float radicalInverse(uint bits) {
+    bits = (bits << 16u) | (bits >> 16u);
+    bits = ((bits & 0x55555555u) << 1u) | ((bits & 0xAAAAAAAAu) >> 1u);
+    bits = ((bits & 0x33333333u) << 2u) | ((bits & 0xCCCCCCCCu) >> 2u);
+    bits = ((bits & 0x0F0F0F0Fu) << 4u) | ((bits & 0xF0F0F0F0u) >> 4u);
+    bits = ((bits & 0x00FF00FFu) << 8u) | ((bits & 0xFF00FF00u) >> 8u);
+    return float(bits) * 2.3283064365386963e-10;
+}
+
+// Point in the unit hemisphere around +Z, packed closer to the center for low indices
+vec3 kernelSample(int i) {
+    float t        = float(i) / float(KERNEL_SIZE);
+    float cosTheta = 1.0 - radicalInverse(uint(i));
+    float sinTheta = sqrt(1.0 - cosTheta * cosTheta);
+    float phi      = 2.0 * PI * t;
+    float scale    = mix(0.1, 1.0, t * t);
+    return vec3(cos(phi) * sinTheta, sin(phi) * sinTheta, cosTheta) * scale;
+}
+
+// View space position of the surface at `uv` from its depth
+vec3 reconstruct_view_position(vec2 uv, float depth) {
+    vec4 view = data.inv_projection * vec4(uv * 2.0 - 1.0, depth, 1.0);
+    return view.xyz / view.w;
+}
+
+void main() {
+    float depth = texture(u_depth, frag_coord).r;
+    if (depth >= 1.0) {
+        f_occlusion = 1.0;
+        return;
+    }
+
+    float radius   = data.params.x;
+    float strength = data.params.y;
+    float bias     = data.params.z;
+
+    vec3 fragPos = reconstruct_view_position(frag_coord, depth);
+    vec3 normal  = normalize(mat3(data.view) * decode_octahedral(texture(u_surface, frag_coord).rg));
+
+    ivec2 cell      = ivec2(gl_FragCoord.xy) & 3;
+    float angle     = ROTATIONS[cell.y * 4 + cell.x] * (2.0 * PI / 16.0);
+    vec3 randomVec  = vec3(cos(angle), sin(angle), 0.0);
+    vec3 tangent    = normalize(randomVec - normal * dot(randomVec, normal));
+    vec3 bitangent  = cross(normal, tangent);
+    mat3 TBN        = mat3(tangent, bitangent, normal);
+
+    float occlusion = 0.0;
+    for (int i = 0; i < KERNEL_SIZE; i++) {
+        vec3 samplePos = fragPos + TBN * kernelSample(i) * radius;
+
+        vec4 clip = data.projection * vec4(samplePos, 1.0);
+        vec2 uv   = clip.xy / clip.w * 0.5 + 0.5;
+
+        float sampleDepth = texture(u_depth, uv).r;
+        if (sampleDepth >= 1.0)
+            continue;
+
+        // View space looks down -Z, so a larger depth is closer to the camera
+        float sceneDepth = reconstruct_view_position(uv, sampleDepth).z;
+        float rangeCheck = smoothstep(0.0, 1.0, radius / abs(fragPos.z - sceneDepth));
+        occlusion += (sceneDepth >= samplePos.z + bias ? 1.0 : 0.0) * rangeCheck;
+    }
+
+    f_occlusion = pow(1.0 - occlusion / float(KERNEL_SIZE), strength);
+}
@@ -0,0 +1,21 @@
+#version 450
+
+layout(location = 0) in vec2 frag_coord;
+
+layout(set = 0, binding = 0) uniform sampler2D u_occlusion;
+
+layout(location = 0) out float f_occlusion;
+
+// Averages a 4x4 block, the size of the tile the kernel rotations repeat over
+void main() {
+    vec2 texel = 1.0 / vec2(textureSize(u_occlusion, 0));
+
+    float result = 0.0;
+    for (int x = -2; x < 2; x++) {
+        for (int y = -2; y < 2; y++) {
+            result += texture(u_occlusion, frag_coord + vec2(x, y) * texel).r;
+        }
+    }
+
+    f_occlusion = result / 16.0;
+}
@@ -0,0 +1,37 @@
+#version 450
+
+layout(location = 0) in vec2 frag_coord;
+
+layout(set = 0, binding = 0) uniform sampler2D u_current;
+layout(set = 0, binding = 1) uniform sampler2D u_history;
+layout(set = 0, binding = 2) uniform sampler2D u_velocity; // UV offset from last frame to this one
+
+layout(set = 0, binding = 3) uniform TAA_Data {
+    vec4 params; // x = weight of the history, 0 when there is none
+} data;
+
+layout(location = 0) out vec4 f_color;
+
+void main() {
+    vec2 texel   = 1.0 / vec2(textureSize(u_current, 0));
+    vec3 current = texture(u_current, frag_coord).rgb;
+
+    // Clamping the history to the current neighbourhood rejects most disocclusions
+    vec3 minColor = current;
+    vec3 maxColor = current;
+    for (int x = -1; x <= 1; x++) {
+        for (int y = -1; y <= 1; y++) {
+            vec3 neighbour = texture(u_current, frag_coord + vec2(x, y) * texel).rgb;
+            minColor = min(minColor, neighbour);
+            maxColor = max(maxColor, neighbour);
+        }
+    }
+
+    vec2 historyUv = frag_coord - texture(u_velocity, frag_coord).xy;
+    float weight   = data.params.x;
+    if (any(lessThan(historyUv, vec2(0.0))) || any(greaterThan(historyUv, vec2(1.0))))
+        weight = 0.0;
+
+    vec3 history = clamp(texture(u_history, historyUv).rgb, minColor, maxColor);
+    f_color = vec4(mix(current, history, weight), 1.0);
+}
@@ -0,0 +1,107 @@
+#version 450
+
+layout(location = 0) in vec2 frag_coord;
+
+layout(set = 0, binding = 0) uniform sampler2D hdr_color;
+
+layout(set = 0, binding = 1) readonly buffer Exposure {
+    float average_luminance;
+} exposure;
+
+layout(set = 0, binding = 2) uniform Tonemap_Data {
+    vec4 params; // x = operator, y = exposure scale, z = auto exposure, w = bloom intensity
+} tonemap;
+
+layout(set = 0, binding = 3) uniform sampler2D bloom_color;
+
+layout(location = 0) out vec4 f_color;
+
+const float REINHARD = 0.0;
+const float ACES     = 1.0;
+
+// Middle grey the average luminance is mapped to with auto exposure
+const float KEY_VALUE = 0.18;
+
+vec3 reinhard(vec3 color) {
+    return color / (1.0 + color);
+}
+
+// Stephen Hill's fit of the ACES RRT and ODT, operating on linear sRGB
+const mat3 ACES_INPUT = mat3(
+    0.59719, 0.35458, 0.04823,
+    0.07600, 0.90834, 0.01566,
+    0.02840, 0.13383, 0.83777
+);
+
+const mat3 ACES_OUTPUT = mat3(
+     1.60475, -0.53108, -0.07367,
+    -0.10208,  1.10813, -0.00605,
+    -0.00327, -0.07276,  1.07602
+);
+
+vec3 rrtAndOdtFit(vec3 v) {
+    vec3 a = v * (v + 0.0245786) - 0.000090537;
+    vec3 b = v * (0.983729 * v + 0.4329510) + 0.238081;
+    return a / b;
+}
+
+vec3 aces(vec3 color) {
+    color = color * ACES_INPUT;
+    color = rrtAndOdtFit(color);
+    color = color * ACES_OUTPUT;
+    return clamp(color, 0.0, 1.0);
+}
+
+// Minimal AgX with the default contrast look
+const mat3 AGX_INSET = mat3(
+    0.842479062253094, 0.0423282422610123, 0.0423756549057051,
+    0.0784335999999992, 0.878468636469772, 0.0784336,
+    0.0792237451477643, 0.0791661274605434, 0.879142973793104
+);
+
+const mat3 AGX_OUTSET = mat3(
+    1.19687900512017, -0.0528968517574562, -0.0529716355144438,
+    -0.0980208811401368, 1.15190312990417, -0.0980434501171241,
+    -0.0990297440797205, -0.0989611768448433, 1.15107367264116
+);
+
+vec3 agxContrast(vec3 x) {
+    vec3 x2 = x * x;
+    vec3 x4 = x2 * x2;
+    return 15.5 * x4 * x2 - 40.14 * x4 * x + 31.96 * x4 - 6.868 * x2 * x + 0.4298 * x2 + 0.1191 * x - 0.00232;
+}
+
+vec3 agx(vec3 color) {
+    const float minEv = -12.47393;
+    const float maxEv = 4.026069;
+
+    color = AGX_INSET * color;
+    color = clamp(log2(max(color, vec3(1e-10))), minEv, maxEv);
+    color = (color - minEv) / (maxEv - minEv);
+    color = agxContrast(color);
+    color = AGX_OUTSET * color;
+
+    // The curve outputs display encoded values, the target encodes to sRGB itself
+    return pow(max(color, vec3(0.0)), vec3(2.2));
+}
+
+void main() {
+    vec3 color = texture(hdr_color, frag_coord).rgb;
+
+    if (tonemap.params.w > 0.0)
+        color += texture(bloom_color, frag_coord).rgb * tonemap.params.w;
+
+    float scale = tonemap.params.y;
+    if (tonemap.params.z > 0.5)
+        scale *= KEY_VALUE / max(exposure.average_luminance, 0.0001);
+    color *= scale;
+
+    if (tonemap.params.x == REINHARD)
+        color = reinhard(color);
+    else if (tonemap.params.x == ACES)
+        color = aces(color);
+    else
+        color = agx(color);
+
+    f_color = vec4(color, 1.0);
+}
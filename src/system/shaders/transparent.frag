@@ -0,0 +1,117 @@
+#version 450
+
+layout(location = 0) in vec3 in_normal;
+layout(location = 1) in vec2 in_tex_coords;
+layout(location = 2) in vec4 in_tangent;
+layout(location = 3) in vec4 in_current_clip;
+layout(location = 4) in vec4 in_previous_clip;
+
+layout(set = 1, binding = 0) uniform Material_Data {
+    vec4 params;   // x = opacity, y = 1 when additive
+    vec4 emissive; // rgb = factor multiplied with tex_emissive
+} material;
+layout(set = 1, binding = 1) uniform sampler2D tex_albedo_ao; // RGB = color, A = AO
+layout(set = 1, binding = 2) uniform sampler2D tex_material; // RG = encoded normals, B = roughness, A = metalness
+layout(set = 1, binding = 4) uniform sampler2D tex_emissive;
+
+layout(set = 2, binding = 0) uniform sampler2D irradiance_map;
+layout(set = 2, binding = 1) uniform sampler2D prefiltered_map; // one mip per roughness level
+layout(set = 2, binding = 2) uniform sampler2D brdf_lut; // RG = scale and bias applied to F0
+
+layout(set = 2, binding = 3) uniform Ambient_Data {
+    vec3 color;
+    float intensity;
+} ambient;
+
+layout(set = 2, binding = 4) uniform Camera_Data {
+    mat4 inv_view_projection;
+    vec4 screen_size; // xy = size of the render target in pixels
+    vec3 camera_pos;
+} camera;
+
+layout(set = 2, binding = 5) uniform Directional_Light_Data {
+    vec4 position;
+    vec3 color;
+} light;
+
+layout(location = 0) out vec4 f_color; // premultiplied by opacity
+
+const float PI = 3.14159265359;
+
+vec3 decode_octahedral(vec2 f) {
+    f = f * 2.0 - 1.0;
+    vec3 n = vec3(f.x, f.y, 1.0 - abs(f.x) - abs(f.y));
+    if (n.z < 0.0)
+        n.xy = (1.0 - abs(n.yx)) * sign(n.xy);
+    return normalize(n);
+}
+
+// World position of this fragment, from its own depth
+vec3 reconstruct_position(float depth) {
+    vec2 ndc   = gl_FragCoord.xy / camera.screen_size.xy * 2.0 - 1.0;
+    vec4 world = camera.inv_view_projection * vec4(ndc, depth, 1.0);
+    return world.xyz / world.w;
+}
+
+vec2 directionToEquirect(vec3 dir) {
+    dir = normalize(dir);
+    float phi = atan(dir.z, dir.x);
+    float theta = asin(clamp(dir.y, -1.0, 1.0));
+    return vec2(phi / (2.0 * PI) + 0.5, theta / PI + 0.5);
+}
+
+vec3 fresnelSchlickRoughness(float cosTheta, vec3 F0, float roughness) {
+    return F0 + (max(vec3(1.0 - roughness), F0) - F0) * pow(clamp(1.0 - cosTheta, 0.0, 1.0), 5.0);
+}
+
+// The sampler blends between the two mips around the surface's roughness
+vec3 samplePrefiltered(vec3 dir, float roughness) {
+    float max_lod = float(textureQueryLevels(prefiltered_map) - 1);
+    return textureLod(prefiltered_map, directionToEquirect(dir), roughness * max_lod).rgb;
+}
+
+void main() {
+    vec4 albedo_ao = texture(tex_albedo_ao, in_tex_coords);
+    vec4 mat       = texture(tex_material, in_tex_coords);
+
+    vec3 N = normalize(in_normal);
+    vec3 T = normalize(in_tangent.xyz);
+    vec3 B = cross(N, T) * in_tangent.w;
+    vec3 normal = normalize(mat3(T, B, N) * decode_octahedral(mat.rg));
+
+    vec3  albedo    = albedo_ao.rgb;
+    float ao        = albedo_ao.a;
+    float roughness = mat.b;
+    float metallic  = mat.a;
+
+    vec3 fragPos = reconstruct_position(gl_FragCoord.z);
+    vec3 V       = normalize(camera.camera_pos - fragPos);
+
+    // Seen from behind, light the side facing the camera
+    if (dot(normal, V) < 0.0)
+        normal = -normal;
+
+    vec3  F0    = mix(vec3(0.04), albedo, metallic);
+    float NdotV = max(dot(normal, V), 0.0);
+    vec3  F     = fresnelSchlickRoughness(NdotV, F0, roughness);
+
+    // Image based ambient, as in the ambient pass
+    vec3 irradiance  = textureLod(irradiance_map, directionToEquirect(normal), 0.0).rgb;
+    vec3 kD          = (1.0 - F) * (1.0 - metallic);
+    vec3 prefiltered = samplePrefiltered(reflect(-V, normal), roughness);
+    vec2 brdf        = textureLod(brdf_lut, vec2(NdotV, roughness), 0.0).rg;
+    vec3 color       = (kD * albedo * irradiance + prefiltered * (F0 * brdf.x + brdf.y))
+        * ambient.color * ambient.intensity * ao;
+
+    // Unshadowed sunlight, as in the directional pass
+    vec3 lightDir = normalize(light.position.xyz - fragPos);
+    float ndl     = max(dot(normal, lightDir), 0.0);
+    float ndh     = max(dot(normal, normalize(lightDir + V)), 0.0);
+    float spec    = pow(ndh, 1.0 / max(roughness, 0.001));
+    color += (albedo * ndl + spec * F0) * light.color * ao;
+    color += texture(tex_emissive, in_tex_coords).rgb * material.emissive.rgb;
+
+    float opacity = material.params.x;
+    // Additive output leaves the destination's weight untouched
+    f_color = vec4(color * opacity, material.params.y > 0.5 ? 0.0 : opacity);
+}
@@ -0,0 +1,455 @@
+use std::sync::Arc;
+
+use nalgebra_glm::{TMat4, TVec3, identity, inverse, look_at, normalize, ortho_rh_zo, vec3, vec4};
+use vulkano::buffer::{BufferUsage, CpuAccessibleBuffer, DeviceLocalBuffer};
+use vulkano::command_buffer::{
+    AutoCommandBufferBuilder, PrimaryAutoCommandBuffer, RenderPassBeginInfo, SubpassContents,
+};
+use vulkano::descriptor_set::allocator::StandardDescriptorSetAllocator;
+use vulkano::descriptor_set::layout::DescriptorSetLayout;
+use vulkano::descriptor_set::{PersistentDescriptorSet, WriteDescriptorSet};
+use vulkano::device::Device;
+use vulkano::format::Format;
+use vulkano::image::view::{ImageView, ImageViewCreateInfo, ImageViewType};
+use vulkano::image::{AttachmentImage, ImageUsage, SampleCount};
+use vulkano::memory::allocator::StandardMemoryAllocator;
+use vulkano::pipeline::graphics::depth_stencil::DepthStencilState;
+use vulkano::pipeline::graphics::input_assembly::InputAssemblyState;
+use vulkano::pipeline::graphics::rasterization::{CullMode, RasterizationState};
+use vulkano::pipeline::graphics::vertex_input::BuffersDefinition;
+use vulkano::pipeline::graphics::viewport::{Viewport, ViewportState};
+use vulkano::pipeline::{GraphicsPipeline, Pipeline, PipelineBindPoint};
+use vulkano::render_pass::{Framebuffer, FramebufferCreateInfo, RenderPass, Subpass};
+use vulkano::sampler::{Filter, Sampler, SamplerAddressMode, SamplerCreateInfo};
+
+use crate::engine::{DrawBatch, DrawInstance, MeshLod, NormalVertex};
+use crate::system::DirectionalLight;
+
+mod shadow_vert {
+    vulkano_shaders::shader! {
+        ty: "vertex",
+        path: "src/system/shaders/shadow.vert",
+        types_meta: {
+            use bytemuck::{Pod, Zeroable};
+
+            #[derive(Clone, Copy, Zeroable, Pod)]
+        },
+    }
+}
+
+mod shadow_frag {
+    vulkano_shaders::shader! {
+        ty: "fragment",
+        path: "src/system/shaders/shadow.frag",
+    }
+}
+
+mod shadow_cutout_frag {
+    vulkano_shaders::shader! {
+        ty: "fragment",
+        path: "src/system/shaders/shadow_cutout.frag",
+    }
+}
+
+/// Number of cascades, matches the array sizes in `directional.frag`
+pub const CASCADE_COUNT: usize = 4;
+
+const SHADOW_FORMAT: Format = Format::D32_SFLOAT;
+
+/// Tunables of the directional light's cascaded shadow map.
+#[derive(Debug, Clone)]
+pub struct ShadowSettings {
+    /// Width and height of each cascade in texels
+    pub resolution: u32,
+    /// Depth offset applied before comparing against the shadow map
+    pub bias: f32,
+    /// PCF kernel radius in texels, 0 samples a single texel
+    pub pcf_radius: u32,
+    /// View distance at which the last cascade ends
+    pub max_distance: f32,
+    /// Blend between uniform (0.0) and logarithmic (1.0) split distances
+    pub split_lambda: f32,
+    /// Fraction at the far end of each cascade that fades into the next one
+    pub blend: f32,
+    /// How far towards the light casters outside a cascade are still rendered
+    pub caster_distance: f32,
+    /// Colors the directional light by cascade index
+    pub debug_cascades: bool,
+}
+
+impl Default for ShadowSettings {
+    fn default() -> Self {
+        Self {
+            resolution: 2048,
+            bias: 0.002,
+            pcf_radius: 1,
+            max_distance: 150.0,
+            split_lambda: 0.75,
+            blend: 0.1,
+            caster_distance: 100.0,
+            debug_cascades: false,
+        }
+    }
+}
+
+/// Depth-only renders of the scene from the sun's point of view, one layer of
+/// an array image per slice of the camera frustum. Sampled by the directional
+/// lighting pass.
+pub struct ShadowMap {
+    pub settings: ShadowSettings,
+    render_pass: Arc<RenderPass>,
+    pipeline: Arc<GraphicsPipeline>,
+    // Discards by a cutout material's mask, which is bound as set 1
+    cutout_pipeline: Arc<GraphicsPipeline>,
+    pub image_view: Arc<ImageView<AttachmentImage>>,
+    framebuffers: Vec<Arc<Framebuffer>>,
+    pub sampler: Arc<Sampler>,
+    pub light_vps: [TMat4<f32>; CASCADE_COUNT],
+    /// View distance at which each cascade ends
+    pub splits: [f32; CASCADE_COUNT],
+}
+
+impl ShadowMap {
+    pub fn new(
+        device: Arc<Device>,
+        allocator: &StandardMemoryAllocator,
+        settings: ShadowSettings,
+    ) -> ShadowMap {
+        let shadow_vert = shadow_vert::load(device.clone()).unwrap();
+        let shadow_frag = shadow_frag::load(device.clone()).unwrap();
+        let shadow_cutout_frag = shadow_cutout_frag::load(device.clone()).unwrap();
+
+        let render_pass = vulkano::single_pass_renderpass!(device.clone(),
+            attachments: {
+                depth: {
+                    load: Clear,
+                    store: Store,
+                    format: SHADOW_FORMAT,
+                    samples: 1,
+                }
+            },
+            pass: {
+                color: [],
+                depth_stencil: {depth}
+            }
+        )
+        .unwrap();
+
+        // Both faces are drawn since the light's projection can flip the winding
+        let pipeline = GraphicsPipeline::start()
+            .vertex_input_state(
+                BuffersDefinition::new()
+                    .vertex::<NormalVertex>()
+                    .instance::<DrawInstance>(),
+            )
+            .vertex_shader(shadow_vert.entry_point("main").unwrap(), ())
+            .input_assembly_state(InputAssemblyState::new())
+            .viewport_state(ViewportState::viewport_dynamic_scissor_irrelevant())
+            .fragment_shader(shadow_frag.entry_point("main").unwrap(), ())
+            .depth_stencil_state(DepthStencilState::simple_depth_test())
+            .rasterization_state(RasterizationState::new().cull_mode(CullMode::None))
+            .render_pass(Subpass::from(render_pass.clone(), 0).unwrap())
+            .build(device.clone())
+            .unwrap();
+
+        let cutout_pipeline = GraphicsPipeline::start()
+            .vertex_input_state(
+                BuffersDefinition::new()
+                    .vertex::<NormalVertex>()
+                    .instance::<DrawInstance>(),
+            )
+            .vertex_shader(shadow_vert.entry_point("main").unwrap(), ())
+            .input_assembly_state(InputAssemblyState::new())
+            .viewport_state(ViewportState::viewport_dynamic_scissor_irrelevant())
+            .fragment_shader(shadow_cutout_frag.entry_point("main").unwrap(), ())
+            .depth_stencil_state(DepthStencilState::simple_depth_test())
+            .rasterization_state(RasterizationState::new().cull_mode(CullMode::None))
+            .render_pass(Subpass::from(render_pass.clone(), 0).unwrap())
+            .build(device.clone())
+            .unwrap();
+
+        let sampler = Sampler::new(
+            device.clone(),
+            SamplerCreateInfo {
+                mag_filter: Filter::Nearest,
+                min_filter: Filter::Nearest,
+                address_mode: [SamplerAddressMode::ClampToEdge; 3],
+                ..Default::default()
+            },
+        )
+        .unwrap();
+
+        let (image_view, framebuffers) =
+            ShadowMap::create_target(allocator, render_pass.clone(), settings.resolution);
+        let splits = [settings.max_distance; CASCADE_COUNT];
+
+        ShadowMap {
+            settings,
+            render_pass,
+            pipeline,
+            cutout_pipeline,
+            image_view,
+            framebuffers,
+            sampler,
+            light_vps: [identity(); CASCADE_COUNT],
+            splits,
+        }
+    }
+
+    pub fn set_settings(&mut self, allocator: &StandardMemoryAllocator, settings: ShadowSettings) {
+        if settings.resolution != self.settings.resolution {
+            let (image_view, framebuffers) =
+                ShadowMap::create_target(allocator, self.render_pass.clone(), settings.resolution);
+            self.image_view = image_view;
+            self.framebuffers = framebuffers;
+        }
+
+        self.settings = settings;
+    }
+
+    /// Splits the camera frustum described by `view` and `projection` into
+    /// cascades and fits the light's orthographic box around each slice.
+    pub fn update_cascades(
+        &mut self,
+        light: &DirectionalLight,
+        view: &TMat4<f32>,
+        projection: &TMat4<f32>,
+    ) {
+        // Frustum shape recovered from the OpenGL style perspective matrix
+        let tan_half_x = 1.0 / projection[(0, 0)];
+        let tan_half_y = 1.0 / projection[(1, 1)];
+        let near = projection[(2, 3)] / (projection[(2, 2)] - 1.0);
+        let camera_far = projection[(2, 3)] / (projection[(2, 2)] + 1.0);
+        let far = self.settings.max_distance.min(camera_far);
+        let lambda = self.settings.split_lambda;
+
+        let inverse_view = inverse(view);
+        let to_light = normalize(&light.get_position());
+
+        // look_at breaks down when looking straight along the up vector
+        let up = if to_light.y.abs() > 0.99 {
+            vec3(0.0, 0.0, 1.0)
+        } else {
+            vec3(0.0, 1.0, 0.0)
+        };
+        let light_view = look_at(&to_light, &vec3(0.0, 0.0, 0.0), &up);
+        let inverse_light_view = inverse(&light_view);
+
+        let mut split_near = near;
+        for cascade in 0..CASCADE_COUNT {
+            let fraction = (cascade + 1) as f32 / CASCADE_COUNT as f32;
+            let log_split = near * (far / near).powf(fraction);
+            let uniform_split = near + (far - near) * fraction;
+            let split_far = lambda * log_split + (1.0 - lambda) * uniform_split;
+
+            let mut corners: Vec<TVec3<f32>> = Vec::with_capacity(8);
+            for distance in [split_near, split_far] {
+                for (x, y) in [(-1.0, -1.0), (1.0, -1.0), (-1.0, 1.0), (1.0, 1.0)] {
+                    let corner = vec4(
+                        x * tan_half_x * distance,
+                        y * tan_half_y * distance,
+                        -distance,
+                        1.0,
+                    );
+                    corners.push((inverse_view * corner).xyz());
+                }
+            }
+
+            // A bounding sphere keeps the box the same size while the camera
+            // rotates, so texels don't change size between frames
+            let center = corners.iter().sum::<TVec3<f32>>() / corners.len() as f32;
+            let radius = corners
+                .iter()
+                .map(|corner| (corner - center).norm())
+                .fold(0.0f32, f32::max)
+                .ceil();
+
+            // Moving the box in whole texels stops shadow edges shimmering
+            let texel = 2.0 * radius / self.settings.resolution as f32;
+            let mut snapped = light_view * vec4(center.x, center.y, center.z, 1.0);
+            snapped.x = (snapped.x / texel).floor() * texel;
+            snapped.y = (snapped.y / texel).floor() * texel;
+            let center = (inverse_light_view * snapped).xyz();
+
+            let eye = center + to_light * (radius + self.settings.caster_distance);
+            let light_projection = ortho_rh_zo(
+                -radius,
+                radius,
+                -radius,
+                radius,
+                0.0,
+                2.0 * radius + self.settings.caster_distance,
+            );
+
+            self.light_vps[cascade] = light_projection * look_at(&eye, &center, &up);
+            self.splits[cascade] = split_far;
+            split_near = split_far;
+        }
+    }
+
+    /// Starts the shadow render pass of one cascade and binds everything
+    /// except the mesh.
+    pub fn begin(
+        &self,
+        commands: &mut AutoCommandBufferBuilder<PrimaryAutoCommandBuffer>,
+        allocator: &StandardMemoryAllocator,
+        descriptor_set_allocator: &StandardDescriptorSetAllocator,
+        cascade: usize,
+    ) {
+        let light_buffer = CpuAccessibleBuffer::from_data(
+            allocator,
+            BufferUsage {
+                uniform_buffer: true,
+                ..BufferUsage::empty()
+            },
+            false,
+            shadow_vert::ty::Light_Data {
+                light_vp: self.light_vps[cascade].into(),
+            },
+        )
+        .unwrap();
+
+        let light_layout = self.pipeline.layout().set_layouts().get(0).unwrap();
+        let light_set = PersistentDescriptorSet::new(
+            descriptor_set_allocator,
+            light_layout.clone(),
+            [WriteDescriptorSet::buffer(0, light_buffer)],
+        )
+        .unwrap();
+
+        let resolution = self.settings.resolution as f32;
+        let viewport = Viewport {
+            origin: [0.0, 0.0],
+            dimensions: [resolution, resolution],
+            depth_range: 0.0..1.0,
+        };
+
+        commands
+            .begin_render_pass(
+                RenderPassBeginInfo {
+                    clear_values: vec![Some(1.0.into())],
+                    ..RenderPassBeginInfo::framebuffer(self.framebuffers[cascade].clone())
+                },
+                SubpassContents::Inline,
+            )
+            .unwrap()
+            .set_viewport(0, [viewport])
+            .bind_pipeline_graphics(self.pipeline.clone())
+            .bind_descriptor_sets(
+                PipelineBindPoint::Graphics,
+                self.pipeline.layout().clone(),
+                0,
+                light_set,
+            );
+    }
+
+    /// Layout of the per-material set a cutout binds with `bind_material`.
+    pub fn cutout_layout(&self) -> Arc<DescriptorSetLayout> {
+        self.cutout_pipeline
+            .layout()
+            .set_layouts()
+            .get(1)
+            .unwrap()
+            .clone()
+    }
+
+    /// Selects the pipeline for the following draws: the cutout one with
+    /// `cutout_set` bound, or the plain depth one for `None`.
+    pub fn bind_material(
+        &self,
+        commands: &mut AutoCommandBufferBuilder<PrimaryAutoCommandBuffer>,
+        cutout_set: Option<Arc<PersistentDescriptorSet>>,
+    ) {
+        match cutout_set {
+            Some(cutout_set) => {
+                commands
+                    .bind_pipeline_graphics(self.cutout_pipeline.clone())
+                    .bind_descriptor_sets(
+                        PipelineBindPoint::Graphics,
+                        self.cutout_pipeline.layout().clone(),
+                        1,
+                        cutout_set,
+                    );
+            }
+            None => {
+                commands.bind_pipeline_graphics(self.pipeline.clone());
+            }
+        }
+    }
+
+    pub fn draw(
+        &self,
+        commands: &mut AutoCommandBufferBuilder<PrimaryAutoCommandBuffer>,
+        batch: &DrawBatch,
+        lod: &MeshLod,
+        vertex_buffer: Arc<DeviceLocalBuffer<[NormalVertex]>>,
+        index_buffer: Arc<DeviceLocalBuffer<[u32]>>,
+        instance_buffer: Arc<CpuAccessibleBuffer<[DrawInstance]>>,
+    ) {
+        commands
+            .bind_vertex_buffers(0, (vertex_buffer, instance_buffer))
+            .bind_index_buffer(index_buffer)
+            .draw_indexed(
+                lod.index_count,
+                batch.instance_count,
+                lod.first_index,
+                0,
+                batch.first_instance,
+            )
+            .unwrap();
+    }
+
+    pub fn end(&self, commands: &mut AutoCommandBufferBuilder<PrimaryAutoCommandBuffer>) {
+        commands.end_render_pass().unwrap();
+    }
+
+    /// Creates the layered depth image with one framebuffer per cascade and an
+    /// array view over all of them for sampling.
+    fn create_target(
+        allocator: &StandardMemoryAllocator,
+        render_pass: Arc<RenderPass>,
+        resolution: u32,
+    ) -> (Arc<ImageView<AttachmentImage>>, Vec<Arc<Framebuffer>>) {
+        let image = AttachmentImage::multisampled_with_usage_with_layers(
+            allocator,
+            [resolution, resolution],
+            CASCADE_COUNT as u32,
+            SampleCount::Sample1,
+            SHADOW_FORMAT,
+            ImageUsage {
+                depth_stencil_attachment: true,
+                sampled: true,
+                ..ImageUsage::empty()
+            },
+        )
+        .unwrap();
+
+        let image_view = ImageView::new(
+            image.clone(),
+            ImageViewCreateInfo {
+                view_type: ImageViewType::Dim2dArray,
+                ..ImageViewCreateInfo::from_image(&image)
+            },
+        )
+        .unwrap();
+
+        let framebuffers = (0..CASCADE_COUNT as u32)
+            .map(|layer| {
+                let mut layer_info = ImageViewCreateInfo::from_image(&image);
+                layer_info.view_type = ImageViewType::Dim2d;
+                layer_info.subresource_range.array_layers = layer..layer + 1;
+
+                Framebuffer::new(
+                    render_pass.clone(),
+                    FramebufferCreateInfo {
+                        attachments: vec![ImageView::new(image.clone(), layer_info).unwrap()],
+                        ..Default::default()
+                    },
+                )
+                .unwrap()
+            })
+            .collect();
+
+        (image_view, framebuffers)
+    }
+}
@@ -0,0 +1,286 @@
+use std::sync::Arc;
+
+use nalgebra_glm::{TMat4, inverse};
+use vulkano::buffer::{BufferUsage, CpuAccessibleBuffer};
+use vulkano::command_buffer::{
+    AutoCommandBufferBuilder, ClearColorImageInfo, PrimaryAutoCommandBuffer,
+};
+use vulkano::descriptor_set::allocator::StandardDescriptorSetAllocator;
+use vulkano::descriptor_set::{PersistentDescriptorSet, WriteDescriptorSet};
+use vulkano::device::Device;
+use vulkano::format::{ClearColorValue, Format};
+use vulkano::image::view::ImageView;
+use vulkano::image::{AttachmentImage, ImageUsage};
+use vulkano::memory::allocator::StandardMemoryAllocator;
+use vulkano::pipeline::{GraphicsPipeline, Pipeline};
+use vulkano::render_pass::{Framebuffer, FramebufferCreateInfo, RenderPass, Subpass};
+use vulkano::sampler::{Filter, Sampler, SamplerAddressMode, SamplerCreateInfo};
+
+use crate::system::fullscreen::FullscreenPass;
+
+mod ssao_frag {
+    vulkano_shaders::shader! {
+        ty: "fragment",
+        path: "src/system/shaders/ssao.frag",
+        types_meta: {
+            use bytemuck::{Pod, Zeroable};
+
+            #[derive(Clone, Copy, Zeroable, Pod)]
+        },
+    }
+}
+
+mod blur_frag {
+    vulkano_shaders::shader! {
+        ty: "fragment",
+        path: "src/system/shaders/ssao_blur.frag",
+    }
+}
+
+const OCCLUSION_FORMAT: Format = Format::R8_UNORM;
+
+/// Tunables of the screen space ambient occlusion pass.
+#[derive(Debug, Clone)]
+pub struct SsaoSettings {
+    pub enabled: bool,
+    /// World space radius of the hemisphere sampled around each pixel
+    pub radius: f32,
+    /// Exponent applied to the result, higher values darken occluded areas more
+    pub strength: f32,
+    /// Depth difference below which a sample doesn't count as occluding
+    pub bias: f32,
+}
+
+impl Default for SsaoSettings {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            radius: 0.5,
+            strength: 1.5,
+            bias: 0.025,
+        }
+    }
+}
+
+/// A full resolution occlusion image with the framebuffer that renders into it.
+struct OcclusionTarget {
+    image_view: Arc<ImageView<AttachmentImage>>,
+    framebuffer: Arc<Framebuffer>,
+}
+
+/// Darkens creases and contact points by sampling the G-buffer around each
+/// pixel. The blurred result is multiplied into the ambient term.
+pub struct Ssao {
+    pub settings: SsaoSettings,
+    render_pass: Arc<RenderPass>,
+    ssao_pipeline: Arc<GraphicsPipeline>,
+    blur_pipeline: Arc<GraphicsPipeline>,
+    occlusion: OcclusionTarget,
+    blurred: OcclusionTarget,
+    sampler: Arc<Sampler>,
+    fullscreen: FullscreenPass,
+}
+
+impl Ssao {
+    pub fn new(
+        device: Arc<Device>,
+        allocator: &StandardMemoryAllocator,
+        extent: [u32; 2],
+        settings: SsaoSettings,
+    ) -> Ssao {
+        let fullscreen = FullscreenPass::new(device.clone(), allocator);
+        let ssao_frag = ssao_frag::load(device.clone()).unwrap();
+        let blur_frag = blur_frag::load(device.clone()).unwrap();
+
+        let render_pass = vulkano::single_pass_renderpass!(device.clone(),
+            attachments: {
+                color: {
+                    load: DontCare,
+                    store: Store,
+                    format: OCCLUSION_FORMAT,
+                    samples: 1,
+                }
+            },
+            pass: {
+                color: [color],
+                depth_stencil: {}
+            }
+        )
+        .unwrap();
+
+        let ssao_pipeline = fullscreen.pipeline(
+            device.clone(),
+            &ssao_frag,
+            Subpass::from(render_pass.clone(), 0).unwrap(),
+            None,
+        );
+
+        let blur_pipeline = fullscreen.pipeline(
+            device.clone(),
+            &blur_frag,
+            Subpass::from(render_pass.clone(), 0).unwrap(),
+            None,
+        );
+
+        // Nearest so samples never blend depths across silhouettes
+        let sampler = Sampler::new(
+            device.clone(),
+            SamplerCreateInfo {
+                mag_filter: Filter::Nearest,
+                min_filter: Filter::Nearest,
+                address_mode: [SamplerAddressMode::ClampToEdge; 3],
+                ..Default::default()
+            },
+        )
+        .unwrap();
+
+        Ssao {
+            settings,
+            occlusion: Ssao::create_target(allocator, render_pass.clone(), extent),
+            blurred: Ssao::create_target(allocator, render_pass.clone(), extent),
+            render_pass,
+            ssao_pipeline,
+            blur_pipeline,
+            sampler,
+            fullscreen,
+        }
+    }
+
+    /// Recreates the occlusion images for a new target size.
+    pub fn resize(&mut self, allocator: &StandardMemoryAllocator, extent: [u32; 2]) {
+        self.occlusion = Ssao::create_target(allocator, self.render_pass.clone(), extent);
+        self.blurred = Ssao::create_target(allocator, self.render_pass.clone(), extent);
+    }
+
+    /// The blurred occlusion of the last recorded frame, one value per pixel.
+    pub fn output(&self) -> Arc<ImageView<AttachmentImage>> {
+        self.blurred.image_view.clone()
+    }
+
+    /// Records the occlusion and blur passes from the G-buffer, or fills the
+    /// output with 1.0 when disabled. Must be recorded outside a render pass.
+    #[allow(clippy::too_many_arguments)]
+    pub fn record(
+        &self,
+        commands: &mut AutoCommandBufferBuilder<PrimaryAutoCommandBuffer>,
+        allocator: &StandardMemoryAllocator,
+        descriptor_set_allocator: &StandardDescriptorSetAllocator,
+        surface: Arc<ImageView<AttachmentImage>>,
+        depth: Arc<ImageView<AttachmentImage>>,
+        view: &TMat4<f32>,
+        projection: &TMat4<f32>,
+    ) {
+        if !self.settings.enabled {
+            commands
+                .clear_color_image(ClearColorImageInfo {
+                    clear_value: ClearColorValue::Float([1.0; 4]),
+                    ..ClearColorImageInfo::image(self.blurred.image_view.image().clone())
+                })
+                .unwrap();
+            return;
+        }
+
+        let ssao_buffer = CpuAccessibleBuffer::from_data(
+            allocator,
+            BufferUsage {
+                uniform_buffer: true,
+                ..BufferUsage::empty()
+            },
+            false,
+            ssao_frag::ty::SSAO_Data {
+                view: (*view).into(),
+                projection: (*projection).into(),
+                inv_projection: inverse(projection).into(),
+                params: [
+                    self.settings.radius,
+                    self.settings.strength,
+                    self.settings.bias,
+                    0.0,
+                ],
+            },
+        )
+        .unwrap();
+
+        let ssao_set = PersistentDescriptorSet::new(
+            descriptor_set_allocator,
+            self.ssao_pipeline
+                .layout()
+                .set_layouts()
+                .get(0)
+                .unwrap()
+                .clone(),
+            [
+                WriteDescriptorSet::image_view_sampler(0, surface, self.sampler.clone()),
+                WriteDescriptorSet::image_view_sampler(1, depth, self.sampler.clone()),
+                WriteDescriptorSet::buffer(2, ssao_buffer),
+            ],
+        )
+        .unwrap();
+
+        self.fullscreen.draw(
+            commands,
+            self.occlusion.framebuffer.clone(),
+            &self.ssao_pipeline,
+            Some(ssao_set),
+        );
+
+        let blur_set = PersistentDescriptorSet::new(
+            descriptor_set_allocator,
+            self.blur_pipeline
+                .layout()
+                .set_layouts()
+                .get(0)
+                .unwrap()
+                .clone(),
+            [WriteDescriptorSet::image_view_sampler(
+                0,
+                self.occlusion.image_view.clone(),
+                self.sampler.clone(),
+            )],
+        )
+        .unwrap();
+
+        self.fullscreen.draw(
+            commands,
+            self.blurred.framebuffer.clone(),
+            &self.blur_pipeline,
+            Some(blur_set),
+        );
+    }
+
+    fn create_target(
+        allocator: &StandardMemoryAllocator,
+        render_pass: Arc<RenderPass>,
+        extent: [u32; 2],
+    ) -> OcclusionTarget {
+        let image_view = ImageView::new_default(
+            AttachmentImage::with_usage(
+                allocator,
+                extent,
+                OCCLUSION_FORMAT,
+                ImageUsage {
+                    color_attachment: true,
+                    sampled: true,
+                    transfer_dst: true,
+                    ..ImageUsage::empty()
+                },
+            )
+            .unwrap(),
+        )
+        .unwrap();
+
+        let framebuffer = Framebuffer::new(
+            render_pass,
+            FramebufferCreateInfo {
+                attachments: vec![image_view.clone()],
+                ..Default::default()
+            },
+        )
+        .unwrap();
+
+        OcclusionTarget {
+            image_view,
+            framebuffer,
+        }
+    }
+}
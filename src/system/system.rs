@@ -1,25 +1,41 @@
-use crate::engine::{DrawInstance, DummyVertex, Engine, Material, Mesh, NormalVertex, Skybox};
-use crate::system::DirectionalLight;
+use crate::engine::{
+    BlendMode, DrawBatch, DrawInstance, DummyVertex, Engine, Frustum, LightInstance, Material,
+    Mesh, NormalVertex, Skybox,
+};
+use crate::system::antialiasing::AntiAliasing;
+use crate::system::bloom::Bloom;
+use crate::system::buffer_ring::BufferRing;
+use crate::system::ibl::IblBaker;
+use crate::system::indirect::IndirectDraws;
+use crate::system::shadow::{CASCADE_COUNT, ShadowMap};
+use crate::system::ssao::Ssao;
+use crate::system::tonemap::{HDR_FORMAT, Tonemapper};
+use crate::system::{
+    AntiAliasingSettings, BloomSettings, Capture, CaptureTarget, DirectionalLight, Frame,
+    ShadowSettings, SsaoSettings, TonemapSettings,
+};
 
 use vulkano::buffer::cpu_pool::CpuBufferPoolSubbuffer;
 use vulkano::buffer::{BufferUsage, CpuAccessibleBuffer, CpuBufferPool, TypedBufferAccess};
 use vulkano::command_buffer::allocator::StandardCommandBufferAllocator;
 use vulkano::command_buffer::{
-    AutoCommandBufferBuilder, CommandBufferUsage, PrimaryAutoCommandBuffer,
+    AutoCommandBufferBuilder, CommandBufferUsage, CopyImageToBufferInfo, PrimaryAutoCommandBuffer,
     PrimaryCommandBufferAbstract, RenderPassBeginInfo, SubpassContents,
 };
 use vulkano::descriptor_set::allocator::StandardDescriptorSetAllocator;
 use vulkano::descriptor_set::{PersistentDescriptorSet, WriteDescriptorSet};
 use vulkano::device::physical::PhysicalDeviceType;
-use vulkano::device::{Device, DeviceCreateInfo, DeviceExtensions, Queue, QueueCreateInfo};
+use vulkano::device::{
+    Device, DeviceCreateInfo, DeviceExtensions, Features, Queue, QueueCreateInfo,
+};
 use vulkano::format::Format;
-use vulkano::image::view::ImageView;
-use vulkano::image::{AttachmentImage, ImageAccess, SwapchainImage};
+use vulkano::image::view::{ImageView, ImageViewAbstract};
+use vulkano::image::{AttachmentImage, ImageAccess, ImageUsage, SwapchainImage};
 use vulkano::instance::debug::{
     DebugUtilsMessageSeverity, DebugUtilsMessageType, DebugUtilsMessenger,
     DebugUtilsMessengerCreateInfo,
 };
-use vulkano::instance::{Instance, InstanceCreateInfo};
+use vulkano::instance::{Instance, InstanceCreateInfo, InstanceExtensions};
 use vulkano::memory::allocator::StandardMemoryAllocator;
 use vulkano::pipeline::graphics::color_blend::{
     AttachmentBlend, BlendFactor, BlendOp, ColorBlendState,
@@ -32,7 +48,9 @@ use vulkano::pipeline::graphics::vertex_input::BuffersDefinition;
 use vulkano::pipeline::graphics::viewport::{Viewport, ViewportState};
 use vulkano::pipeline::{GraphicsPipeline, Pipeline, PipelineBindPoint, StateMode};
 use vulkano::render_pass::{Framebuffer, FramebufferCreateInfo, RenderPass, Subpass};
-use vulkano::sampler::{Filter, Sampler, SamplerAddressMode, SamplerCreateInfo, SamplerMipmapMode};
+use vulkano::sampler::{
+    Filter, LOD_CLAMP_NONE, Sampler, SamplerAddressMode, SamplerCreateInfo, SamplerMipmapMode,
+};
 use vulkano::swapchain::{
     self, AcquireError, PresentMode, Surface, Swapchain, SwapchainAcquireFuture,
     SwapchainCreateInfo, SwapchainCreationError, SwapchainPresentInfo,
@@ -52,7 +70,12 @@ use std::sync::{Arc, RwLockReadGuard};
 
 vulkano::impl_vertex!(DummyVertex, position);
 vulkano::impl_vertex!(NormalVertex, position, normal, tangent, uv);
-vulkano::impl_vertex!(DrawInstance, instance_model, instance_normal);
+vulkano::impl_vertex!(
+    DrawInstance,
+    instance_model,
+    instance_normal,
+    instance_previous_model
+);
 
 mod deferred_vert {
     vulkano_shaders::shader! {
@@ -70,6 +93,11 @@ mod deferred_frag {
     vulkano_shaders::shader! {
         ty: "fragment",
         path: "src/system/shaders/deferred.frag",
+        types_meta: {
+            use bytemuck::{Pod, Zeroable};
+
+            #[derive(Clone, Copy, Zeroable, Pod)]
+        },
     }
 }
 
@@ -92,6 +120,18 @@ mod directional_frag {
     }
 }
 
+mod local_lights_frag {
+    vulkano_shaders::shader! {
+        ty: "fragment",
+        path: "src/system/shaders/local_lights.frag",
+        types_meta: {
+            use bytemuck::{Pod, Zeroable};
+
+            #[derive(Clone, Copy, Zeroable, Pod)]
+        },
+    }
+}
+
 mod ambient_vert {
     vulkano_shaders::shader! {
         ty: "vertex",
@@ -111,6 +151,18 @@ mod ambient_frag {
     }
 }
 
+mod transparent_frag {
+    vulkano_shaders::shader! {
+        ty: "fragment",
+        path: "src/system/shaders/transparent.frag",
+        types_meta: {
+            use bytemuck::{Pod, Zeroable};
+
+            #[derive(Clone, Copy, Zeroable, Pod)]
+        },
+    }
+}
+
 mod skybox_vert {
     vulkano_shaders::shader! {
         ty: "vertex",
@@ -133,39 +185,104 @@ mod skybox_frag {
 #[derive(Debug, Clone)]
 enum RenderStage {
     Stopped,
+    Shadow,
     Geometry,
     Lighting,
+    Transparent,
     NeedsRedraw,
 }
 
+/// Where the final color of a frame ends up. A window target presents to a
+/// swapchain, an offscreen target renders into a single image that is read
+/// back to the CPU at the end of the frame.
+enum Target {
+    Window {
+        surface: Arc<Surface>,
+        swapchain: Arc<Swapchain>,
+        images: Vec<Arc<SwapchainImage>>,
+        acquire_future: Option<SwapchainAcquireFuture>,
+    },
+    Offscreen {
+        image: Arc<AttachmentImage>,
+    },
+}
+
+impl Target {
+    fn extent(&self) -> [u32; 2] {
+        match self {
+            Target::Window { swapchain, .. } => swapchain.image_extent(),
+            Target::Offscreen { image } => image.dimensions().width_height(),
+        }
+    }
+
+    fn final_views(&self) -> Vec<Arc<dyn ImageViewAbstract>> {
+        match self {
+            Target::Window { images, .. } => images
+                .iter()
+                .map(|image| {
+                    ImageView::new_default(image.clone()).unwrap() as Arc<dyn ImageViewAbstract>
+                })
+                .collect(),
+            Target::Offscreen { image } => {
+                vec![ImageView::new_default(image.clone()).unwrap() as Arc<dyn ImageViewAbstract>]
+            }
+        }
+    }
+}
+
 pub struct System {
-    surface: Arc<Surface>,
     pub device: Arc<Device>,
     queue: Arc<Queue>,
     vp: VP,
-    swapchain: Arc<Swapchain>,
+    target: Target,
     memory_allocator: Arc<StandardMemoryAllocator>,
     descriptor_set_allocator: StandardDescriptorSetAllocator,
     command_buffer_allocator: StandardCommandBufferAllocator,
-    render_pass: Arc<RenderPass>,
+    geometry_render_pass: Arc<RenderPass>,
+    lighting_render_pass: Arc<RenderPass>,
     skybox_pipeline: Arc<GraphicsPipeline>,
     deferred_pipeline: Arc<GraphicsPipeline>,
     directional_pipeline: Arc<GraphicsPipeline>,
+    local_lights_pipeline: Arc<GraphicsPipeline>,
     ambient_pipeline: Arc<GraphicsPipeline>,
+    transparent_pipeline: Arc<GraphicsPipeline>,
     vp_buffer: Arc<CpuAccessibleBuffer<deferred_vert::ty::VP_Data>>,
     ambient_buffer: Arc<CpuAccessibleBuffer<ambient_frag::ty::Ambient_Data>>,
     directional_buffer: CpuBufferPool<directional_frag::ty::Directional_Light_Data>,
     dummy_verts: Arc<CpuAccessibleBuffer<[DummyVertex]>>,
+    instance_ring: BufferRing<DrawInstance>,
+    instance_buffer: Option<Arc<CpuAccessibleBuffer<[DrawInstance]>>>,
+    indirect: IndirectDraws,
+    gpu_driven: bool,
+    // Set when `gpu_driven` changes, meshes then move between their own
+    // buffers and the pool on the next upload
+    mesh_storage_changed: bool,
+    // Set when a pooled mesh is freed, the pool is then repacked on the next
+    // upload
+    pool_outdated: bool,
+    shadow_map: ShadowMap,
+    shadow_rendered: bool,
+    ibl_baker: IblBaker,
+    material_sampler: Arc<Sampler>,
+    skybox_sampler: Arc<Sampler>,
     vp_set: Arc<PersistentDescriptorSet>,
     viewport: Viewport,
-    framebuffers: Vec<Arc<Framebuffer>>,
+    geometry_framebuffer: Arc<Framebuffer>,
+    lighting_framebuffer: Arc<Framebuffer>,
+    tonemapper: Tonemapper,
+    bloom: Bloom,
+    ssao: Ssao,
+    anti_aliasing: AntiAliasing,
+    hdr_buffer: Arc<ImageView<AttachmentImage>>,
     albedo_ao_buffer: Arc<ImageView<AttachmentImage>>,
     surface_buffer: Arc<ImageView<AttachmentImage>>,
-    position_buffer: Arc<ImageView<AttachmentImage>>,
+    depth_buffer: Arc<ImageView<AttachmentImage>>,
+    velocity_buffer: Arc<ImageView<AttachmentImage>>,
+    emissive_buffer: Arc<ImageView<AttachmentImage>>,
     render_stage: RenderStage,
     commands: Option<AutoCommandBufferBuilder<PrimaryAutoCommandBuffer>>,
     image_index: u32,
-    acquire_future: Option<SwapchainAcquireFuture>,
+    capture_targets: Vec<CaptureTarget>,
 }
 
 #[derive(Debug, Clone)]
@@ -173,6 +290,11 @@ struct VP {
     view: TMat4<f32>,
     projection: TMat4<f32>,
     camera_pos: TVec3<f32>,
+    /// Projection the geometry pass rendered with this frame, the same as
+    /// `projection` unless TAA jitters it
+    jittered_projection: TMat4<f32>,
+    /// Unjittered view projection of the last frame, for motion vectors
+    previous_view_projection: TMat4<f32>,
 }
 
 impl VP {
@@ -181,6 +303,8 @@ impl VP {
             view: identity(),
             projection: identity(),
             camera_pos: vec3(0.0, 0.0, 0.0),
+            jittered_projection: identity(),
+            previous_view_projection: identity(),
         }
     }
 }
@@ -188,12 +312,126 @@ impl VP {
 const AMBIENT_COLOR: [f32; 3] = [1.0, 1.0, 1.0];
 const AMBIENT_BRIGHTNESS: f32 = 1.0;
 
+// Lights per draw of the local lights pass, must match `local_lights.frag`
+const MAX_LOCAL_LIGHTS: usize = 64;
+
+// Lighting reconstructs world positions from depth, so it needs the precision
+const DEPTH_FORMAT: Format = Format::D32_SFLOAT;
+
+// Screen space motion written by the geometry pass for TAA
+const VELOCITY_FORMAT: Format = Format::R16G16_SFLOAT;
+
+// Format of the final color image when rendering without a window
+const OFFSCREEN_FORMAT: Format = Format::R8G8B8A8_SRGB;
+
 impl System {
     pub fn new(event_loop: &EventLoop<()>) -> System {
-        let instance = {
-            let library = VulkanLibrary::new().unwrap();
-            let extensions = vulkano_win::required_extensions(&library);
+        let library = VulkanLibrary::new().unwrap();
+        let extensions = vulkano_win::required_extensions(&library);
+        let instance = System::create_instance(library, extensions);
+
+        let surface = WindowBuilder::new()
+            .build_vk_surface(event_loop, instance.clone())
+            .unwrap();
+
+        let device_extensions = DeviceExtensions {
+            khr_swapchain: true,
+            ext_full_screen_exclusive: true,
+            ..DeviceExtensions::empty()
+        };
+
+        let (device, queue) = System::create_device(&instance, device_extensions, Some(&surface));
+
+        let (swapchain, images) = {
+            let caps = device
+                .physical_device()
+                .surface_capabilities(&surface, Default::default())
+                .unwrap();
+
+            let usage = caps.supported_usage_flags;
+            let alpha = caps.supported_composite_alpha.iter().next().unwrap();
 
+            let image_format = Some(
+                device
+                    .physical_device()
+                    .surface_formats(&surface, Default::default())
+                    .unwrap()[0]
+                    .0,
+            );
+
+            let window = surface.object().unwrap().downcast_ref::<Window>().unwrap();
+            let image_extent: [u32; 2] = window.inner_size().into();
+
+            Swapchain::new(
+                device.clone(),
+                surface.clone(),
+                SwapchainCreateInfo {
+                    min_image_count: caps.min_image_count,
+                    image_format,
+                    image_extent,
+                    image_usage: usage,
+                    composite_alpha: alpha,
+                    present_mode: PresentMode::Immediate,
+                    full_screen_exclusive: swapchain::FullScreenExclusive::Disallowed,
+                    ..Default::default()
+                },
+            )
+            .unwrap()
+        };
+
+        let memory_allocator = Arc::new(StandardMemoryAllocator::new_default(device.clone()));
+        let final_format = swapchain.image_format();
+
+        System::with_target(
+            device,
+            queue,
+            memory_allocator,
+            Target::Window {
+                surface,
+                swapchain,
+                images,
+                acquire_future: None,
+            },
+            final_format,
+        )
+    }
+
+    /// Creates a system without a window or swapchain. Frames are rendered into
+    /// an offscreen image of the given size and handed back by `finish`, which
+    /// makes this usable on machines without a display (e.g. with lavapipe).
+    pub fn new_headless(extent: [u32; 2]) -> System {
+        let library = VulkanLibrary::new().unwrap();
+        let instance = System::create_instance(library, InstanceExtensions::empty());
+
+        let (device, queue) = System::create_device(&instance, DeviceExtensions::empty(), None);
+
+        let memory_allocator = Arc::new(StandardMemoryAllocator::new_default(device.clone()));
+        let image = AttachmentImage::with_usage(
+            &memory_allocator,
+            extent,
+            OFFSCREEN_FORMAT,
+            ImageUsage {
+                color_attachment: true,
+                transfer_src: true,
+                ..ImageUsage::empty()
+            },
+        )
+        .unwrap();
+
+        System::with_target(
+            device,
+            queue,
+            memory_allocator,
+            Target::Offscreen { image },
+            OFFSCREEN_FORMAT,
+        )
+    }
+
+    fn create_instance(
+        library: Arc<VulkanLibrary>,
+        extensions: InstanceExtensions,
+    ) -> Arc<Instance> {
+        let instance = {
             let mut layers = vec![];
             if library
                 .layer_properties()
@@ -243,16 +481,14 @@ impl System {
             .ok();
         }
 
-        let surface = WindowBuilder::new()
-            .build_vk_surface(event_loop, instance.clone())
-            .unwrap();
-
-        let device_extensions = DeviceExtensions {
-            khr_swapchain: true,
-            ext_full_screen_exclusive: true,
-            ..DeviceExtensions::empty()
-        };
+        instance
+    }
 
+    fn create_device(
+        instance: &Arc<Instance>,
+        device_extensions: DeviceExtensions,
+        surface: Option<&Arc<Surface>>,
+    ) -> (Arc<Device>, Arc<Queue>) {
         let (physical_device, queue_family_index) = instance
             .enumerate_physical_devices()
             .unwrap()
@@ -262,9 +498,11 @@ impl System {
                     .iter()
                     .enumerate()
                     .position(|(i, q)| {
-                        // pick first queue_familiy_index that handles graphics and can draw on the surface created by winit
+                        // pick first queue_familiy_index that handles graphics and can draw on the surface created by winit (if any)
                         q.queue_flags.graphics
-                            && p.surface_support(i as u32, &surface).unwrap_or(false)
+                            && surface.map_or(true, |surface| {
+                                p.surface_support(i as u32, surface).unwrap_or(false)
+                            })
                     })
                     .map(|i| (p, i as u32))
             })
@@ -281,10 +519,21 @@ impl System {
             })
             .expect("No suitable physical device found");
 
+        // Optional features used by GPU driven drawing, enabled where supported
+        let indirect_features = Features {
+            multi_draw_indirect: true,
+            draw_indirect_first_instance: true,
+            ..Features::empty()
+        };
+        let enabled_features = physical_device
+            .supported_features()
+            .intersection(&indirect_features);
+
         let (device, mut queues) = Device::new(
             physical_device,
             DeviceCreateInfo {
                 enabled_extensions: device_extensions,
+                enabled_features,
                 queue_create_infos: vec![QueueCreateInfo {
                     queue_family_index,
                     ..Default::default()
@@ -294,51 +543,22 @@ impl System {
         )
         .unwrap();
 
-        let queue = queues.next().unwrap();
+        (device, queues.next().unwrap())
+    }
 
+    fn with_target(
+        device: Arc<Device>,
+        queue: Arc<Queue>,
+        memory_allocator: Arc<StandardMemoryAllocator>,
+        target: Target,
+        final_format: Format,
+    ) -> System {
         let mut vp = VP::new();
 
-        let (swapchain, images) = {
-            let caps = device
-                .physical_device()
-                .surface_capabilities(&surface, Default::default())
-                .unwrap();
-
-            let usage = caps.supported_usage_flags;
-            let alpha = caps.supported_composite_alpha.iter().next().unwrap();
-
-            let image_format = Some(
-                device
-                    .physical_device()
-                    .surface_formats(&surface, Default::default())
-                    .unwrap()[0]
-                    .0,
-            );
-
-            let window = surface.object().unwrap().downcast_ref::<Window>().unwrap();
-            let image_extent: [u32; 2] = window.inner_size().into();
-
-            let aspect_ratio = image_extent[0] as f32 / image_extent[1] as f32;
-            vp.projection = perspective(aspect_ratio, half_pi(), 0.01, 100.0);
-
-            Swapchain::new(
-                device.clone(),
-                surface.clone(),
-                SwapchainCreateInfo {
-                    min_image_count: caps.min_image_count,
-                    image_format,
-                    image_extent,
-                    image_usage: usage,
-                    composite_alpha: alpha,
-                    present_mode: PresentMode::Immediate,
-                    full_screen_exclusive: swapchain::FullScreenExclusive::Disallowed,
-                    ..Default::default()
-                },
-            )
-            .unwrap()
-        };
+        let image_extent = target.extent();
+        let aspect_ratio = image_extent[0] as f32 / image_extent[1] as f32;
+        vp.projection = perspective(aspect_ratio, half_pi(), 0.01, 100.0);
 
-        let memory_allocator = Arc::new(StandardMemoryAllocator::new_default(device.clone()));
         let descriptor_set_allocator = StandardDescriptorSetAllocator::new(device.clone());
         let command_buffer_allocator =
             StandardCommandBufferAllocator::new(device.clone(), Default::default());
@@ -347,61 +567,110 @@ impl System {
         let deferred_frag = deferred_frag::load(device.clone()).unwrap();
         let directional_vert = directional_vert::load(device.clone()).unwrap();
         let directional_frag = directional_frag::load(device.clone()).unwrap();
+        let local_lights_frag = local_lights_frag::load(device.clone()).unwrap();
         let ambient_vert = ambient_vert::load(device.clone()).unwrap();
         let ambient_frag = ambient_frag::load(device.clone()).unwrap();
         let skybox_vert = skybox_vert::load(device.clone()).unwrap();
         let skybox_frag = skybox_frag::load(device.clone()).unwrap();
+        let transparent_frag = transparent_frag::load(device.clone()).unwrap();
 
-        let render_pass = vulkano::ordered_passes_renderpass!(device.clone(),
+        // The G-buffer is written by one render pass and read by another so
+        // SSAO can sample it in between
+        let geometry_render_pass = vulkano::ordered_passes_renderpass!(device.clone(),
             attachments: {
-                final_color: {
-                    load: Clear,
-                    store: Store,
-                    format: swapchain.image_format(),
-                    samples: 1,
-                },
                 albedo_ao: {
                     load: Clear,
-                    store: DontCare,
+                    store: Store,
                     format: Format::R8G8B8A8_SRGB,
                     samples: 1,
                 },
                 surface: {
                     load: Clear,
-                    store: DontCare,
+                    store: Store,
                     format: Format::R16G16B16A16_SFLOAT,
                     samples: 1,
                 },
-                position: {
+                velocity: {
                     load: Clear,
-                    store: DontCare,
-                    format: Format::R16G16B16A16_SFLOAT,
+                    store: Store,
+                    format: VELOCITY_FORMAT,
+                    samples: 1,
+                },
+                emissive: {
+                    load: Clear,
+                    store: Store,
+                    format: HDR_FORMAT,
                     samples: 1,
                 },
                 depth: {
                     load: Clear,
-                    store: DontCare,
-                    format: Format::D16_UNORM,
+                    store: Store,
+                    format: DEPTH_FORMAT,
                     samples: 1,
                 }
             },
             passes: [
                 {
-                    color: [albedo_ao, surface, position],
+                    color: [albedo_ao, surface, velocity, emissive],
                     depth_stencil: {depth},
                     input: []
+                }
+            ]
+        )
+        .unwrap();
+
+        let lighting_render_pass = vulkano::ordered_passes_renderpass!(device.clone(),
+            attachments: {
+                hdr_color: {
+                    load: Clear,
+                    store: Store,
+                    format: HDR_FORMAT,
+                    samples: 1,
+                },
+                albedo_ao: {
+                    load: Load,
+                    store: Store,
+                    format: Format::R8G8B8A8_SRGB,
+                    samples: 1,
+                },
+                surface: {
+                    load: Load,
+                    store: Store,
+                    format: Format::R16G16B16A16_SFLOAT,
+                    samples: 1,
+                },
+                depth: {
+                    load: Load,
+                    store: Store,
+                    format: DEPTH_FORMAT,
+                    samples: 1,
+                },
+                emissive: {
+                    load: Load,
+                    store: Store,
+                    format: HDR_FORMAT,
+                    samples: 1,
+                }
+            },
+            passes: [
+                {
+                    color: [hdr_color],
+                    depth_stencil: {},
+                    input: [albedo_ao, surface, depth, emissive]
                 },
                 {
-                    color: [final_color],
+                    // Forward transparent pass, tested against the opaque depth
+                    color: [hdr_color],
                     depth_stencil: {depth},
-                    input: [albedo_ao, surface, position]
+                    input: []
                 }
             ]
         )
         .unwrap();
 
-        let deferred_pass = Subpass::from(render_pass.clone(), 0).unwrap();
-        let lighting_pass = Subpass::from(render_pass.clone(), 1).unwrap();
+        let deferred_pass = Subpass::from(geometry_render_pass.clone(), 0).unwrap();
+        let lighting_pass = Subpass::from(lighting_render_pass.clone(), 0).unwrap();
+        let transparent_pass = Subpass::from(lighting_render_pass.clone(), 1).unwrap();
 
         let skybox_pipeline = GraphicsPipeline::start()
             .vertex_input_state(BuffersDefinition::new().vertex::<DummyVertex>())
@@ -409,14 +678,6 @@ impl System {
             .input_assembly_state(InputAssemblyState::new())
             .viewport_state(ViewportState::viewport_dynamic_scissor_irrelevant())
             .fragment_shader(skybox_frag.entry_point("main").unwrap(), ())
-            .depth_stencil_state(DepthStencilState {
-                depth: Some(DepthState {
-                    write_enable: StateMode::Fixed(false),
-                    compare_op: StateMode::Fixed(CompareOp::LessOrEqual),
-                    ..Default::default()
-                }),
-                ..Default::default()
-            })
             .render_pass(lighting_pass.clone())
             .build(device.clone())
             .unwrap();
@@ -460,6 +721,29 @@ impl System {
             .build(device.clone())
             .unwrap();
 
+        let local_lights_pipeline = GraphicsPipeline::start()
+            .vertex_input_state(BuffersDefinition::new().vertex::<DummyVertex>())
+            .vertex_shader(directional_vert.entry_point("main").unwrap(), ())
+            .input_assembly_state(InputAssemblyState::new())
+            .viewport_state(ViewportState::viewport_dynamic_scissor_irrelevant())
+            .fragment_shader(local_lights_frag.entry_point("main").unwrap(), ())
+            .color_blend_state(
+                ColorBlendState::new(lighting_pass.num_color_attachments()).blend(
+                    AttachmentBlend {
+                        color_op: BlendOp::Add,
+                        color_source: BlendFactor::One,
+                        color_destination: BlendFactor::One,
+                        alpha_op: BlendOp::Max,
+                        alpha_source: BlendFactor::One,
+                        alpha_destination: BlendFactor::One,
+                    },
+                ),
+            )
+            .rasterization_state(RasterizationState::new().cull_mode(CullMode::Back))
+            .render_pass(lighting_pass.clone())
+            .build(device.clone())
+            .unwrap();
+
         let ambient_pipeline = GraphicsPipeline::start()
             .vertex_input_state(BuffersDefinition::new().vertex::<DummyVertex>())
             .vertex_shader(ambient_vert.entry_point("main").unwrap(), ())
@@ -483,22 +767,59 @@ impl System {
             .build(device.clone())
             .unwrap();
 
-        let vp_buffer = CpuAccessibleBuffer::from_data(
-            &memory_allocator,
-            BufferUsage {
-                uniform_buffer: true,
-                ..BufferUsage::empty()
-            },
-            false,
-            deferred_vert::ty::VP_Data {
-                view: vp.view.into(),
-                projection: vp.projection.into(),
-            },
-        )
-        .unwrap();
-
-        let ambient_buffer = CpuAccessibleBuffer::from_data(
-            &memory_allocator,
+        // Colors come out premultiplied, additive materials output zero alpha
+        let transparent_pipeline = GraphicsPipeline::start()
+            .vertex_input_state(
+                BuffersDefinition::new()
+                    .vertex::<NormalVertex>()
+                    .instance::<DrawInstance>(),
+            )
+            .vertex_shader(deferred_vert.entry_point("main").unwrap(), ())
+            .input_assembly_state(InputAssemblyState::new())
+            .viewport_state(ViewportState::viewport_dynamic_scissor_irrelevant())
+            .fragment_shader(transparent_frag.entry_point("main").unwrap(), ())
+            .depth_stencil_state(DepthStencilState {
+                depth: Some(DepthState {
+                    enable_dynamic: false,
+                    write_enable: StateMode::Fixed(false),
+                    compare_op: StateMode::Fixed(CompareOp::Less),
+                }),
+                ..Default::default()
+            })
+            .color_blend_state(
+                ColorBlendState::new(transparent_pass.num_color_attachments()).blend(
+                    AttachmentBlend {
+                        color_op: BlendOp::Add,
+                        color_source: BlendFactor::One,
+                        color_destination: BlendFactor::OneMinusSrcAlpha,
+                        alpha_op: BlendOp::Add,
+                        alpha_source: BlendFactor::Zero,
+                        alpha_destination: BlendFactor::One,
+                    },
+                ),
+            )
+            .render_pass(transparent_pass.clone())
+            .build(device.clone())
+            .unwrap();
+
+        let vp_buffer = CpuAccessibleBuffer::from_data(
+            &memory_allocator,
+            BufferUsage {
+                uniform_buffer: true,
+                ..BufferUsage::empty()
+            },
+            false,
+            deferred_vert::ty::VP_Data {
+                view: vp.view.into(),
+                projection: vp.projection.into(),
+                view_projection: (vp.projection * vp.view).into(),
+                previous_view_projection: (vp.projection * vp.view).into(),
+            },
+        )
+        .unwrap();
+
+        let ambient_buffer = CpuAccessibleBuffer::from_data(
+            &memory_allocator,
             BufferUsage {
                 uniform_buffer: true,
                 ..BufferUsage::empty()
@@ -525,6 +846,39 @@ impl System {
         )
         .unwrap();
 
+        let material_sampler = Sampler::new(
+            device.clone(),
+            SamplerCreateInfo {
+                mag_filter: Filter::Linear,
+                min_filter: Filter::Linear,
+                mipmap_mode: SamplerMipmapMode::Nearest,
+                address_mode: [SamplerAddressMode::Repeat; 3],
+                mip_lod_bias: 0.0,
+                ..Default::default()
+            },
+        )
+        .unwrap();
+
+        // Blends between mips so the prefiltered map's roughness levels
+        // can be sampled with `textureLod`
+        let skybox_sampler = Sampler::new(
+            device.clone(),
+            SamplerCreateInfo {
+                mag_filter: Filter::Linear,
+                min_filter: Filter::Linear,
+                mipmap_mode: SamplerMipmapMode::Linear,
+                address_mode: [SamplerAddressMode::ClampToEdge; 3],
+                lod: 0.0..=LOD_CLAMP_NONE,
+                ..Default::default()
+            },
+        )
+        .unwrap();
+
+        let shadow_map =
+            ShadowMap::new(device.clone(), &memory_allocator, ShadowSettings::default());
+
+        let ibl_baker = IblBaker::new(device.clone(), &memory_allocator);
+
         let vp_layout = deferred_pipeline.layout().set_layouts().get(0).unwrap();
         let vp_set = PersistentDescriptorSet::new(
             &descriptor_set_allocator,
@@ -539,48 +893,110 @@ impl System {
             depth_range: 0.0..1.0,
         };
 
-        let (framebuffers, albedo_ao_buffer, surface_buffer, position_buffer) =
-            System::window_size_dependent_setup(
-                &memory_allocator,
-                &images,
-                render_pass.clone(),
-                &mut viewport,
-            );
+        let (
+            geometry_framebuffer,
+            lighting_framebuffer,
+            hdr_buffer,
+            albedo_ao_buffer,
+            surface_buffer,
+            depth_buffer,
+            velocity_buffer,
+            emissive_buffer,
+        ) = System::window_size_dependent_setup(
+            &memory_allocator,
+            image_extent,
+            geometry_render_pass.clone(),
+            lighting_render_pass.clone(),
+            &mut viewport,
+        );
+
+        let tonemapper = Tonemapper::new(
+            device.clone(),
+            &memory_allocator,
+            final_format,
+            target.final_views(),
+            TonemapSettings::default(),
+        );
+        let bloom = Bloom::new(
+            device.clone(),
+            &memory_allocator,
+            image_extent,
+            BloomSettings::default(),
+        );
+        let ssao = Ssao::new(
+            device.clone(),
+            &memory_allocator,
+            image_extent,
+            SsaoSettings::default(),
+        );
+        let anti_aliasing = AntiAliasing::new(
+            device.clone(),
+            &memory_allocator,
+            image_extent,
+            AntiAliasingSettings::default(),
+        );
+
+        let indirect = IndirectDraws::new(device.clone());
 
         let render_stage = RenderStage::Stopped;
 
         let commands = None;
         let image_index = 0;
-        let acquire_future = None;
 
         System {
-            surface,
             device,
             queue,
             vp,
-            swapchain,
+            target,
             memory_allocator,
             descriptor_set_allocator,
             command_buffer_allocator,
-            render_pass,
+            geometry_render_pass,
+            lighting_render_pass,
             skybox_pipeline,
             deferred_pipeline,
             directional_pipeline,
+            local_lights_pipeline,
             ambient_pipeline,
+            transparent_pipeline,
             vp_buffer,
             ambient_buffer,
             directional_buffer,
             dummy_verts,
+            instance_ring: BufferRing::new(BufferUsage {
+                vertex_buffer: true,
+                // Read by the culling pass when drawing indirectly
+                storage_buffer: true,
+                ..BufferUsage::empty()
+            }),
+            instance_buffer: None,
+            indirect,
+            gpu_driven: false,
+            mesh_storage_changed: false,
+            pool_outdated: false,
+            shadow_map,
+            shadow_rendered: false,
+            ibl_baker,
+            material_sampler,
+            skybox_sampler,
             vp_set,
             viewport,
-            framebuffers,
+            geometry_framebuffer,
+            lighting_framebuffer,
+            tonemapper,
+            bloom,
+            ssao,
+            anti_aliasing,
+            hdr_buffer,
             albedo_ao_buffer,
             surface_buffer,
-            position_buffer,
+            depth_buffer,
+            velocity_buffer,
+            emissive_buffer,
             render_stage,
             commands,
             image_index,
-            acquire_future,
+            capture_targets: Vec::new(),
         }
     }
 
@@ -617,17 +1033,6 @@ impl System {
         )
         .unwrap();
 
-        let sampler = Sampler::new(
-            self.device.clone(),
-            SamplerCreateInfo {
-                mag_filter: Filter::Linear,
-                min_filter: Filter::Linear,
-                address_mode: [SamplerAddressMode::ClampToEdge; 3],
-                ..Default::default()
-            },
-        )
-        .unwrap();
-
         let skybox_layout = self.skybox_pipeline.layout().set_layouts().get(0).unwrap();
         let skybox_set = PersistentDescriptorSet::new(
             &self.descriptor_set_allocator,
@@ -636,9 +1041,10 @@ impl System {
                 WriteDescriptorSet::image_view_sampler(
                     0,
                     skybox.image_view.clone().unwrap(),
-                    sampler.clone(),
+                    self.skybox_sampler.clone(),
                 ),
                 WriteDescriptorSet::buffer(1, camera_buffer.clone()),
+                WriteDescriptorSet::image_view(2, self.depth_buffer.clone()),
             ],
         )
         .unwrap();
@@ -659,6 +1065,8 @@ impl System {
             .unwrap();
     }
 
+    /// Lights the G-buffer with the environment and adds the emissive color
+    /// of every surface on top, unlit.
     pub fn ambient(&mut self, skybox: &Skybox) {
         match self.render_stage {
             RenderStage::Lighting => {}
@@ -683,22 +1091,13 @@ impl System {
             },
             false,
             ambient_frag::ty::Camera_Data {
+                inv_view_projection: self.inverse_view_projection().into(),
+                screen_size: self.screen_size(),
                 camera_pos: self.vp.camera_pos.into(),
             },
         )
         .unwrap();
 
-        let sampler = Sampler::new(
-            self.device.clone(),
-            SamplerCreateInfo {
-                mag_filter: Filter::Linear,
-                min_filter: Filter::Linear,
-                address_mode: [SamplerAddressMode::ClampToEdge; 3],
-                ..Default::default()
-            },
-        )
-        .unwrap();
-
         let ambient_layout = self.ambient_pipeline.layout().set_layouts().get(0).unwrap();
         let ambient_set = PersistentDescriptorSet::new(
             &self.descriptor_set_allocator,
@@ -706,14 +1105,30 @@ impl System {
             [
                 WriteDescriptorSet::image_view(0, self.albedo_ao_buffer.clone()),
                 WriteDescriptorSet::image_view(1, self.surface_buffer.clone()),
-                WriteDescriptorSet::image_view(2, self.position_buffer.clone()),
+                WriteDescriptorSet::image_view(2, self.depth_buffer.clone()),
                 WriteDescriptorSet::image_view_sampler(
                     3,
-                    skybox.image_view.clone().unwrap(),
-                    sampler.clone(),
+                    skybox.irradiance_view.clone().unwrap(),
+                    self.skybox_sampler.clone(),
                 ),
                 WriteDescriptorSet::buffer(4, self.ambient_buffer.clone()),
                 WriteDescriptorSet::buffer(5, camera_buffer.clone()),
+                WriteDescriptorSet::image_view_sampler(
+                    6,
+                    skybox.prefiltered_view.clone().unwrap(),
+                    self.skybox_sampler.clone(),
+                ),
+                WriteDescriptorSet::image_view_sampler(
+                    7,
+                    skybox.brdf_lut_view.clone().unwrap(),
+                    self.skybox_sampler.clone(),
+                ),
+                WriteDescriptorSet::image_view_sampler(
+                    8,
+                    self.ssao.output(),
+                    self.skybox_sampler.clone(),
+                ),
+                WriteDescriptorSet::image_view(9, self.emissive_buffer.clone()),
             ],
         )
         .unwrap();
@@ -758,6 +1173,8 @@ impl System {
             },
             false,
             directional_frag::ty::Camera_Data {
+                inv_view_projection: self.inverse_view_projection().into(),
+                screen_size: self.screen_size(),
                 position: self.vp.camera_pos.into(),
             },
         )
@@ -766,6 +1183,31 @@ impl System {
         let directional_subbuffer =
             self.generate_directional_buffer(&self.directional_buffer, &directional_light);
 
+        // The camera looks down -z in view space, the third row of the view
+        // matrix is that axis in world space
+        let view_row = self.vp.view.row(2);
+        let shadow_settings = &self.shadow_map.settings;
+        let shadow_buffer = CpuAccessibleBuffer::from_data(
+            &self.memory_allocator,
+            BufferUsage {
+                uniform_buffer: true,
+                ..BufferUsage::empty()
+            },
+            false,
+            directional_frag::ty::Shadow_Data {
+                light_vp: self.shadow_map.light_vps.map(|light_vp| light_vp.into()),
+                splits: self.shadow_map.splits,
+                camera_forward: [-view_row[0], -view_row[1], -view_row[2], 0.0],
+                params: [
+                    shadow_settings.bias,
+                    shadow_settings.pcf_radius as f32,
+                    shadow_settings.blend,
+                    shadow_settings.debug_cascades as u32 as f32,
+                ],
+            },
+        )
+        .unwrap();
+
         let directional_layout = self
             .directional_pipeline
             .layout()
@@ -778,9 +1220,15 @@ impl System {
             [
                 WriteDescriptorSet::image_view(0, self.albedo_ao_buffer.clone()),
                 WriteDescriptorSet::image_view(1, self.surface_buffer.clone()),
-                WriteDescriptorSet::image_view(2, self.position_buffer.clone()),
+                WriteDescriptorSet::image_view(2, self.depth_buffer.clone()),
                 WriteDescriptorSet::buffer(4, directional_subbuffer.clone()),
                 WriteDescriptorSet::buffer(5, camera_buffer.clone()),
+                WriteDescriptorSet::image_view_sampler(
+                    6,
+                    self.shadow_map.image_view.clone(),
+                    self.shadow_map.sampler.clone(),
+                ),
+                WriteDescriptorSet::buffer(7, shadow_buffer.clone()),
             ],
         )
         .unwrap();
@@ -801,10 +1249,12 @@ impl System {
             .unwrap();
     }
 
-    pub fn finish(&mut self, previous_frame_end: &mut Option<Box<dyn GpuFuture>>) {
+    /// Adds the contribution of point and spot lights on top of the lighting
+    /// subpass. Every light is evaluated per pixel, in draws of up to
+    /// `MAX_LOCAL_LIGHTS` lights that blend additively.
+    pub fn local_lights(&mut self, lights: &[LightInstance]) {
         match self.render_stage {
             RenderStage::Lighting => {}
-            RenderStage::Geometry => {}
             RenderStage::NeedsRedraw => {
                 self.recreate_swapchain();
                 self.commands = None;
@@ -818,176 +1268,815 @@ impl System {
             }
         }
 
-        let mut commands = self.commands.take().unwrap();
-        commands.end_render_pass().unwrap();
-        let command_buffer = commands.build().unwrap();
-
-        let af = self.acquire_future.take().unwrap();
-
-        let mut local_future: Option<Box<dyn GpuFuture>> =
-            Some(Box::new(sync::now(self.device.clone())) as Box<dyn GpuFuture>);
-
-        mem::swap(&mut local_future, previous_frame_end);
-
-        let future = local_future
-            .take()
-            .unwrap()
-            .join(af)
-            .then_execute(self.queue.clone(), command_buffer)
-            .unwrap()
-            .then_swapchain_present(
-                self.queue.clone(),
-                SwapchainPresentInfo::swapchain_image_index(
-                    self.swapchain.clone(),
-                    self.image_index,
-                ),
-            )
-            .then_signal_fence_and_flush();
-
-        match future {
-            Ok(future) => {
-                *previous_frame_end = Some(Box::new(future) as Box<_>);
-            }
-            Err(FlushError::OutOfDate) => {
-                self.recreate_swapchain();
-                *previous_frame_end = Some(Box::new(sync::now(self.device.clone())) as Box<_>);
-            }
-            Err(e) => {
-                println!("Failed to flush future: {:?}", e);
-                *previous_frame_end = Some(Box::new(sync::now(self.device.clone())) as Box<_>);
-            }
+        if lights.is_empty() {
+            return;
         }
 
-        self.commands = None;
-        self.render_stage = RenderStage::Stopped;
-    }
-
-    fn generate_directional_buffer(
-        &self,
-        pool: &CpuBufferPool<directional_frag::ty::Directional_Light_Data>,
-        light: &DirectionalLight,
-    ) -> Arc<CpuBufferPoolSubbuffer<directional_frag::ty::Directional_Light_Data>> {
-        let uniform_data = directional_frag::ty::Directional_Light_Data {
-            position: light.position.into(),
-            color: light.color.into(),
-        };
-
-        pool.from_data(uniform_data).unwrap()
-    }
-
-    pub fn preload_textures(&mut self, engine: &mut Engine) {
-        let mut upload_builder = AutoCommandBufferBuilder::primary(
-            &self.command_buffer_allocator,
-            self.queue.queue_family_index(),
-            CommandBufferUsage::OneTimeSubmit,
+        let camera_buffer = CpuAccessibleBuffer::from_data(
+            &self.memory_allocator,
+            BufferUsage {
+                uniform_buffer: true,
+                ..BufferUsage::empty()
+            },
+            false,
+            local_lights_frag::ty::Camera_Data {
+                inv_view_projection: self.inverse_view_projection().into(),
+                screen_size: self.screen_size(),
+                position: self.vp.camera_pos.into(),
+            },
         )
         .unwrap();
 
-        for (_, material_arc) in engine.materials.iter() {
-            let material = Arc::clone(material_arc);
-            let mut material_guard = material.write().unwrap();
-            material_guard.load(&self.memory_allocator, &mut upload_builder);
-        }
+        let local_lights_layout = self
+            .local_lights_pipeline
+            .layout()
+            .set_layouts()
+            .get(0)
+            .unwrap();
 
-        engine
-            .skybox
-            .load(&self.memory_allocator, &mut upload_builder);
+        for chunk in lights.chunks(MAX_LOCAL_LIGHTS) {
+            let mut light_data = local_lights_frag::ty::Local_Light_Data {
+                lights: [local_lights_frag::ty::Local_Light {
+                    position: [0.0; 4],
+                    color: [0.0; 4],
+                    direction: [0.0; 4],
+                    cone: [0.0; 4],
+                }; MAX_LOCAL_LIGHTS],
+                count: [chunk.len() as i32, 0, 0, 0],
+            };
+            for (gpu_light, light) in light_data.lights.iter_mut().zip(chunk) {
+                let (cos_inner, cos_outer, is_spot) = match light.cone {
+                    Some((cos_inner, cos_outer)) => (cos_inner, cos_outer, 1.0),
+                    None => (1.0, 0.0, 0.0),
+                };
+                *gpu_light = local_lights_frag::ty::Local_Light {
+                    position: [
+                        light.position.x,
+                        light.position.y,
+                        light.position.z,
+                        light.range,
+                    ],
+                    color: [light.color.x, light.color.y, light.color.z, 0.0],
+                    direction: [light.direction.x, light.direction.y, light.direction.z, 0.0],
+                    cone: [cos_inner, cos_outer, is_spot, 0.0],
+                };
+            }
 
-        upload_builder
-            .build()
-            .unwrap()
-            .execute(self.queue.clone())
-            .unwrap()
-            .then_signal_fence_and_flush()
-            .unwrap()
-            .wait(None)
+            let light_buffer = CpuAccessibleBuffer::from_data(
+                &self.memory_allocator,
+                BufferUsage {
+                    uniform_buffer: true,
+                    ..BufferUsage::empty()
+                },
+                false,
+                light_data,
+            )
+            .unwrap();
+
+            let local_lights_set = PersistentDescriptorSet::new(
+                &self.descriptor_set_allocator,
+                local_lights_layout.clone(),
+                [
+                    WriteDescriptorSet::image_view(0, self.albedo_ao_buffer.clone()),
+                    WriteDescriptorSet::image_view(1, self.surface_buffer.clone()),
+                    WriteDescriptorSet::image_view(2, self.depth_buffer.clone()),
+                    WriteDescriptorSet::buffer(4, light_buffer),
+                    WriteDescriptorSet::buffer(5, camera_buffer.clone()),
+                ],
+            )
             .unwrap();
+
+            self.commands
+                .as_mut()
+                .unwrap()
+                .set_viewport(0, [self.viewport.clone()])
+                .bind_pipeline_graphics(self.local_lights_pipeline.clone())
+                .bind_vertex_buffers(0, self.dummy_verts.clone())
+                .bind_descriptor_sets(
+                    PipelineBindPoint::Graphics,
+                    self.local_lights_pipeline.layout().clone(),
+                    0,
+                    local_lights_set,
+                )
+                .draw(self.dummy_verts.len() as u32, 1, 0, 0)
+                .unwrap();
+        }
     }
 
-    pub fn geometry(
-        &mut self,
-        instances: Vec<DrawInstance>,
-        material: RwLockReadGuard<Material>,
-        mesh: Arc<Mesh>,
-    ) {
+    /// Moves to the forward subpass and blends every transparent instance of
+    /// `engine` over the lit scene, back to front. Lit by the environment and
+    /// `directional_light`, without shadows.
+    pub fn transparent(&mut self, engine: &Engine, directional_light: &DirectionalLight) {
         match self.render_stage {
-            RenderStage::Geometry => {}
+            RenderStage::Lighting => {
+                self.render_stage = RenderStage::Transparent;
+            }
             RenderStage::NeedsRedraw => {
                 self.recreate_swapchain();
-                self.render_stage = RenderStage::Stopped;
                 self.commands = None;
+                self.render_stage = RenderStage::Stopped;
                 return;
             }
             _ => {
-                self.render_stage = RenderStage::Stopped;
                 self.commands = None;
+                self.render_stage = RenderStage::Stopped;
                 return;
             }
         }
 
-        let instance_len = instances.len();
-        let instance_buffer = CpuAccessibleBuffer::from_iter(
+        self.commands
+            .as_mut()
+            .unwrap()
+            .next_subpass(SubpassContents::Inline)
+            .unwrap();
+
+        if engine.transparent_draws.is_empty() {
+            return;
+        }
+
+        let instance_buffer = self
+            .instance_buffer
+            .clone()
+            .expect("instances not written for this frame");
+
+        let camera_buffer = CpuAccessibleBuffer::from_data(
             &self.memory_allocator,
             BufferUsage {
-                vertex_buffer: true,
+                uniform_buffer: true,
                 ..BufferUsage::empty()
             },
             false,
-            instances.into_iter(),
-        )
-        .unwrap();
-
-        let sampler = Sampler::new(
-            self.device.clone(),
-            SamplerCreateInfo {
-                mag_filter: Filter::Linear,
-                min_filter: Filter::Linear,
-                mipmap_mode: SamplerMipmapMode::Nearest,
-                address_mode: [SamplerAddressMode::Repeat; 3],
-                mip_lod_bias: 0.0,
-                ..Default::default()
+            transparent_frag::ty::Camera_Data {
+                inv_view_projection: self.inverse_view_projection().into(),
+                screen_size: self.screen_size(),
+                camera_pos: self.vp.camera_pos.into(),
             },
         )
         .unwrap();
+        let directional_subbuffer =
+            self.generate_directional_buffer(&self.directional_buffer, directional_light);
 
-        let model_layout = self
-            .deferred_pipeline
-            .layout()
-            .set_layouts()
-            .get(1)
-            .unwrap();
-        let (albedo_ao, surface) = material.unpack();
-        let model_set = PersistentDescriptorSet::new(
+        let skybox = engine.skybox();
+        let skybox = skybox.read().unwrap();
+        let lighting_set = PersistentDescriptorSet::new(
             &self.descriptor_set_allocator,
-            model_layout.clone(),
+            self.transparent_pipeline
+                .layout()
+                .set_layouts()
+                .get(2)
+                .unwrap()
+                .clone(),
             [
-                WriteDescriptorSet::image_view_sampler(1, albedo_ao, sampler.clone()),
-                WriteDescriptorSet::image_view_sampler(2, surface, sampler.clone()),
+                WriteDescriptorSet::image_view_sampler(
+                    0,
+                    skybox.irradiance_view.clone().unwrap(),
+                    self.skybox_sampler.clone(),
+                ),
+                WriteDescriptorSet::image_view_sampler(
+                    1,
+                    skybox.prefiltered_view.clone().unwrap(),
+                    self.skybox_sampler.clone(),
+                ),
+                WriteDescriptorSet::image_view_sampler(
+                    2,
+                    skybox.brdf_lut_view.clone().unwrap(),
+                    self.skybox_sampler.clone(),
+                ),
+                WriteDescriptorSet::buffer(3, self.ambient_buffer.clone()),
+                WriteDescriptorSet::buffer(4, camera_buffer),
+                WriteDescriptorSet::buffer(5, directional_subbuffer),
             ],
         )
         .unwrap();
 
-        let vertex_buffer = CpuAccessibleBuffer::from_iter(
-            &self.memory_allocator,
-            BufferUsage {
-                vertex_buffer: true,
-                ..BufferUsage::empty()
-            },
-            false,
-            mesh.build.vertices.iter().cloned(),
-        )
-        .unwrap();
-        let index_buffer = CpuAccessibleBuffer::from_iter(
+        let commands = self.commands.as_mut().unwrap();
+        commands
+            .set_viewport(0, [self.viewport.clone()])
+            .bind_pipeline_graphics(self.transparent_pipeline.clone());
+
+        for draw in engine.transparent_draws.iter() {
+            let (Some(mesh_data), Some(material_data)) = (
+                engine.assets.meshes.get(draw.mesh_id),
+                engine.assets.materials.get(draw.material_id),
+            ) else {
+                continue;
+            };
+            let mesh = mesh_data.read().unwrap();
+            let model_set = material_data
+                .read()
+                .unwrap()
+                .descriptor_set
+                .clone()
+                .expect("material descriptor set not built");
+
+            commands.bind_descriptor_sets(
+                PipelineBindPoint::Graphics,
+                self.transparent_pipeline.layout().clone(),
+                0,
+                (self.vp_set.clone(), model_set, lighting_set.clone()),
+            );
+
+            // Meshes only have their own buffers when not GPU driven
+            if self.gpu_driven {
+                self.indirect.draw_pooled(
+                    commands,
+                    instance_buffer.clone(),
+                    draw.mesh_id,
+                    draw.submesh,
+                    draw.lod,
+                    draw.instance,
+                );
+                continue;
+            }
+
+            let (vertex_buffer, index_buffer) = mesh.unpack();
+            let lod = mesh.lod(draw.submesh, draw.lod);
+            commands
+                .bind_vertex_buffers(0, (vertex_buffer, instance_buffer.clone()))
+                .bind_index_buffer(index_buffer)
+                .draw_indexed(lod.index_count, 1, lod.first_index, 0, draw.instance)
+                .unwrap();
+        }
+    }
+
+    /// Ends the frame and presents it when rendering to a window. Returns the
+    /// attachments requested through `request_capture`; an offscreen target
+    /// always hands back at least the final color.
+    pub fn finish(
+        &mut self,
+        previous_frame_end: &mut Option<Box<dyn GpuFuture>>,
+    ) -> Option<Capture> {
+        match self.render_stage {
+            RenderStage::Lighting => {
+                // The transparent subpass still has to be stepped through
+                self.commands
+                    .as_mut()
+                    .unwrap()
+                    .next_subpass(SubpassContents::Inline)
+                    .unwrap();
+            }
+            RenderStage::Transparent => {}
+            RenderStage::Geometry => {}
+            RenderStage::NeedsRedraw => {
+                self.recreate_swapchain();
+                self.commands = None;
+                self.render_stage = RenderStage::Stopped;
+                return None;
+            }
+            _ => {
+                self.commands = None;
+                self.render_stage = RenderStage::Stopped;
+                return None;
+            }
+        }
+
+        let mut commands = self.commands.take().unwrap();
+        commands.end_render_pass().unwrap();
+
+        let hdr_color = self.anti_aliasing.record(
+            &mut commands,
+            &self.memory_allocator,
+            &self.descriptor_set_allocator,
+            self.hdr_buffer.clone(),
+            self.velocity_buffer.clone(),
+        );
+
+        let bloom = if self.bloom.settings.enabled {
+            let bloom_color = self.bloom.record(
+                &mut commands,
+                &self.memory_allocator,
+                &self.descriptor_set_allocator,
+                hdr_color.clone(),
+            );
+            Some((bloom_color, self.bloom.settings.intensity))
+        } else {
+            None
+        };
+
+        self.tonemapper.record(
+            &mut commands,
+            &self.memory_allocator,
+            &self.descriptor_set_allocator,
+            hdr_color,
+            bloom,
+            self.image_index,
+        );
+
+        let mut capture_targets = mem::take(&mut self.capture_targets);
+        if matches!(self.target, Target::Offscreen { .. })
+            && !capture_targets.contains(&CaptureTarget::FinalColor)
+        {
+            capture_targets.insert(0, CaptureTarget::FinalColor);
+        }
+
+        let readbacks: Vec<_> = capture_targets
+            .into_iter()
+            .map(|capture_target| {
+                let image = self.capture_image(capture_target);
+                let readback = self.record_readback(&mut commands, image.clone());
+                (capture_target, image, readback)
+            })
+            .collect();
+
+        let command_buffer = commands.build().unwrap();
+
+        let mut local_future: Option<Box<dyn GpuFuture>> =
+            Some(Box::new(sync::now(self.device.clone())) as Box<dyn GpuFuture>);
+
+        mem::swap(&mut local_future, previous_frame_end);
+
+        let future = match &mut self.target {
+            Target::Window {
+                swapchain,
+                acquire_future,
+                ..
+            } => local_future
+                .take()
+                .unwrap()
+                .join(acquire_future.take().unwrap())
+                .then_execute(self.queue.clone(), command_buffer)
+                .unwrap()
+                .then_swapchain_present(
+                    self.queue.clone(),
+                    SwapchainPresentInfo::swapchain_image_index(
+                        swapchain.clone(),
+                        self.image_index,
+                    ),
+                )
+                .boxed()
+                .then_signal_fence_and_flush(),
+            Target::Offscreen { .. } => local_future
+                .take()
+                .unwrap()
+                .then_execute(self.queue.clone(), command_buffer)
+                .unwrap()
+                .boxed()
+                .then_signal_fence_and_flush(),
+        };
+
+        let capture = match future {
+            Ok(future) => {
+                let capture = if readbacks.is_empty() {
+                    None
+                } else {
+                    // The readback buffers are only valid once the frame is done
+                    future.wait(None).unwrap();
+                    Some(Capture {
+                        frames: readbacks
+                            .into_iter()
+                            .map(|(capture_target, image, readback)| {
+                                let frame = Frame::new(
+                                    image.dimensions().width_height(),
+                                    image.format(),
+                                    readback.read().unwrap().to_vec(),
+                                );
+                                (capture_target, frame)
+                            })
+                            .collect(),
+                    })
+                };
+
+                *previous_frame_end = Some(Box::new(future) as Box<_>);
+                capture
+            }
+            Err(FlushError::OutOfDate) => {
+                self.recreate_swapchain();
+                *previous_frame_end = Some(Box::new(sync::now(self.device.clone())) as Box<_>);
+                None
+            }
+            Err(e) => {
+                println!("Failed to flush future: {:?}", e);
+                *previous_frame_end = Some(Box::new(sync::now(self.device.clone())) as Box<_>);
+                None
+            }
+        };
+
+        self.commands = None;
+        self.render_stage = RenderStage::Stopped;
+
+        capture
+    }
+
+    /// Shows `title` on the window. Does nothing when rendering offscreen.
+    pub fn set_window_title(&self, title: &str) {
+        if let Target::Window { surface, .. } = &self.target {
+            let window = surface.object().unwrap().downcast_ref::<Window>().unwrap();
+            window.set_title(title);
+        }
+    }
+
+    /// Reads back the given attachments at the end of the next frame that
+    /// reaches `finish`.
+    pub fn request_capture(&mut self, targets: &[CaptureTarget]) {
+        for target in targets {
+            if !self.capture_targets.contains(target) {
+                self.capture_targets.push(*target);
+            }
+        }
+    }
+
+    fn capture_image(&self, capture_target: CaptureTarget) -> Arc<dyn ImageAccess> {
+        match capture_target {
+            CaptureTarget::FinalColor => match &self.target {
+                Target::Window { images, .. } => images[self.image_index as usize].clone(),
+                Target::Offscreen { image } => image.clone(),
+            },
+            CaptureTarget::HdrColor => self.hdr_buffer.image().clone(),
+            CaptureTarget::AlbedoAo => self.albedo_ao_buffer.image().clone(),
+            CaptureTarget::Surface => self.surface_buffer.image().clone(),
+            CaptureTarget::Depth => self.depth_buffer.image().clone(),
+            CaptureTarget::Emissive => self.emissive_buffer.image().clone(),
+        }
+    }
+
+    /// Records a copy of `image` into a new host-visible buffer. The buffer can
+    /// be read once the command buffer has finished executing.
+    fn record_readback(
+        &self,
+        commands: &mut AutoCommandBufferBuilder<PrimaryAutoCommandBuffer>,
+        image: Arc<dyn ImageAccess>,
+    ) -> Arc<CpuAccessibleBuffer<[u8]>> {
+        let [width, height] = image.dimensions().width_height();
+        let texel_size = image.format().block_size().unwrap();
+        let size = (width as u64 * height as u64 * texel_size) as usize;
+
+        let readback = CpuAccessibleBuffer::from_iter(
             &self.memory_allocator,
             BufferUsage {
-                index_buffer: true,
+                transfer_dst: true,
                 ..BufferUsage::empty()
             },
-            false,
-            mesh.build.indices.iter().cloned(),
+            true,
+            (0..size).map(|_| 0u8),
         )
         .unwrap();
 
+        commands
+            .copy_image_to_buffer(CopyImageToBufferInfo::image_buffer(image, readback.clone()))
+            .unwrap();
+
+        readback
+    }
+
+    fn generate_directional_buffer(
+        &self,
+        pool: &CpuBufferPool<directional_frag::ty::Directional_Light_Data>,
+        light: &DirectionalLight,
+    ) -> Arc<CpuBufferPoolSubbuffer<directional_frag::ty::Directional_Light_Data>> {
+        let uniform_data = directional_frag::ty::Directional_Light_Data {
+            position: light.position.into(),
+            color: light.color.into(),
+        };
+
+        pool.from_data(uniform_data).unwrap()
+    }
+
+    /// Waits for every asset `engine` requested to be decoded, then uploads
+    /// them like `upload_assets` and blocks until the upload is done.
+    pub fn preload_assets(&mut self, engine: &mut Engine) {
+        engine.assets.wait_until_decoded();
+        let Some(command_buffer) = self.record_uploads(engine) else {
+            return;
+        };
+
+        command_buffer
+            .execute(self.queue.clone())
+            .unwrap()
+            .then_signal_fence_and_flush()
+            .unwrap()
+            .wait(None)
+            .unwrap();
+    }
+
+    /// Forgets meshes freed by `AssetServer::free_unused`, repacking the pool
+    /// without them on the next upload.
+    pub fn free_meshes(&mut self, mesh_ids: &[usize]) {
+        if self.indirect.free_meshes(mesh_ids) {
+            self.pool_outdated = true;
+        }
+    }
+
+    /// Uploads the assets `engine` finished decoding since the last call,
+    /// ahead of the next frame on the same queue, without waiting for them.
+    pub fn upload_assets(
+        &mut self,
+        engine: &mut Engine,
+        previous_frame_end: &mut Option<Box<dyn GpuFuture>>,
+    ) {
+        let Some(command_buffer) = self.record_uploads(engine) else {
+            return;
+        };
+
+        let future = previous_frame_end
+            .take()
+            .unwrap()
+            .then_execute(self.queue.clone(), command_buffer)
+            .unwrap();
+        *previous_frame_end = Some(future.boxed());
+    }
+
+    /// Records the upload of every pending material, mesh and skybox of
+    /// `engine` and builds the per-material descriptor sets. `None` when
+    /// nothing is pending.
+    fn record_uploads(&mut self, engine: &mut Engine) -> Option<PrimaryAutoCommandBuffer> {
+        let materials = engine.assets.materials.drain_pending();
+        let meshes = engine.assets.meshes.drain_pending();
+        let skyboxes = engine.assets.skyboxes.drain_pending();
+        let storage_changed = std::mem::take(&mut self.mesh_storage_changed);
+        let pool_outdated = std::mem::take(&mut self.pool_outdated);
+        if materials.is_empty()
+            && meshes.is_empty()
+            && skyboxes.is_empty()
+            && !storage_changed
+            && !pool_outdated
+        {
+            return None;
+        }
+
+        let mut upload_builder = AutoCommandBufferBuilder::primary(
+            &self.command_buffer_allocator,
+            self.queue.queue_family_index(),
+            CommandBufferUsage::OneTimeSubmit,
+        )
+        .unwrap();
+
+        for material in materials {
+            let mut material_guard = material.write().unwrap();
+            material_guard.load(&self.memory_allocator, &mut upload_builder);
+            let (descriptor_set, shadow_set) = self.material_sets(&material_guard);
+            material_guard.descriptor_set = Some(descriptor_set);
+            material_guard.shadow_set = shadow_set;
+        }
+
+        // Meshes are drawn either from the pool or from their own buffers,
+        // never both, so only one copy of them is kept
+        if self.gpu_driven {
+            if storage_changed {
+                for (_, mesh) in engine.assets.meshes.iter() {
+                    mesh.write().unwrap().unload();
+                }
+            }
+            // The pool is repacked with every mesh still loaded
+            if !meshes.is_empty() || storage_changed || pool_outdated {
+                self.indirect.upload_meshes(
+                    &engine.assets.meshes,
+                    &self.memory_allocator,
+                    &mut upload_builder,
+                );
+            }
+        } else {
+            if storage_changed {
+                self.indirect.clear_meshes();
+            }
+            // Newly drained meshes are already listed as loaded
+            for (_, mesh) in engine.assets.meshes.iter() {
+                let mut mesh_guard = mesh.write().unwrap();
+                if mesh_guard.vertex_buffer.is_none() {
+                    mesh_guard.load(&self.memory_allocator, &mut upload_builder);
+                }
+            }
+        }
+
+        for skybox in skyboxes {
+            let mut skybox_guard = skybox.write().unwrap();
+            skybox_guard.load(&self.memory_allocator, &mut upload_builder);
+            self.ibl_baker.bake(
+                &mut skybox_guard,
+                &self.memory_allocator,
+                &self.descriptor_set_allocator,
+                &mut upload_builder,
+            );
+        }
+
+        Some(upload_builder.build().unwrap())
+    }
+
+    /// Builds the descriptor set of a loaded material for the pipeline that
+    /// draws its blend mode, and for cutouts the set of the shadow pass.
+    fn material_sets(
+        &self,
+        material: &Material,
+    ) -> (
+        Arc<PersistentDescriptorSet>,
+        Option<Arc<PersistentDescriptorSet>>,
+    ) {
+        let (albedo_ao, surface) = material.unpack();
+        let [r, g, b] = material.emissive_factor;
+        let mut writes = vec![
+            WriteDescriptorSet::image_view_sampler(1, albedo_ao, self.material_sampler.clone()),
+            WriteDescriptorSet::image_view_sampler(2, surface, self.material_sampler.clone()),
+            WriteDescriptorSet::image_view_sampler(
+                4,
+                material.unpack_emissive(),
+                self.material_sampler.clone(),
+            ),
+        ];
+        let mut shadow_set = None;
+
+        let pipeline = if material.blend_mode.is_transparent() {
+            let material_buffer = CpuAccessibleBuffer::from_data(
+                &self.memory_allocator,
+                BufferUsage {
+                    uniform_buffer: true,
+                    ..BufferUsage::empty()
+                },
+                false,
+                transparent_frag::ty::Material_Data {
+                    params: [
+                        material.opacity,
+                        (material.blend_mode == BlendMode::Additive) as u32 as f32,
+                        0.0,
+                        0.0,
+                    ],
+                    emissive: [r, g, b, 0.0],
+                },
+            )
+            .unwrap();
+            writes.push(WriteDescriptorSet::buffer(0, material_buffer));
+            &self.transparent_pipeline
+        } else {
+            // A cutoff of zero never discards
+            let material_buffer = CpuAccessibleBuffer::from_data(
+                &self.memory_allocator,
+                BufferUsage {
+                    uniform_buffer: true,
+                    ..BufferUsage::empty()
+                },
+                false,
+                deferred_frag::ty::Material_Data {
+                    params: [material.alpha_cutoff.unwrap_or(0.0), 0.0, 0.0, 0.0],
+                    emissive: [r, g, b, 0.0],
+                },
+            )
+            .unwrap();
+            let mask = material.unpack_mask();
+
+            if material.alpha_cutoff.is_some() {
+                shadow_set = Some(
+                    PersistentDescriptorSet::new(
+                        &self.descriptor_set_allocator,
+                        self.shadow_map.cutout_layout(),
+                        [
+                            WriteDescriptorSet::buffer(0, material_buffer.clone()),
+                            WriteDescriptorSet::image_view_sampler(
+                                1,
+                                mask.clone(),
+                                self.material_sampler.clone(),
+                            ),
+                        ],
+                    )
+                    .unwrap(),
+                );
+            }
+
+            writes.push(WriteDescriptorSet::buffer(0, material_buffer));
+            writes.push(WriteDescriptorSet::image_view_sampler(
+                3,
+                mask,
+                self.material_sampler.clone(),
+            ));
+            &self.deferred_pipeline
+        };
+
+        let descriptor_set = PersistentDescriptorSet::new(
+            &self.descriptor_set_allocator,
+            pipeline.layout().set_layouts().get(1).unwrap().clone(),
+            writes,
+        )
+        .unwrap();
+
+        (descriptor_set, shadow_set)
+    }
+
+    /// Fills this frame's instance buffer. `write` receives exactly `count`
+    /// instances of mapped memory, laid out as the draw batches expect.
+    pub fn write_instances(&mut self, count: usize, write: impl FnOnce(&mut [DrawInstance])) {
+        let instance_buffer = self.instance_ring.next(&self.memory_allocator, count);
+        {
+            let mut instances = instance_buffer.write().unwrap();
+            write(&mut instances[..count]);
+        }
+
+        self.instance_buffer = Some(instance_buffer);
+    }
+
+    /// Frustum culls this frame's instances on the GPU, against the camera and
+    /// every shadow cascade, and builds the indirect draw commands used by
+    /// `shadow` and `geometry_indirect`. Must be called after
+    /// `update_cascades` and `write_instances` when GPU driven.
+    pub fn cull_instances(&mut self, engine: &Engine) {
+        match self.render_stage {
+            RenderStage::Shadow => {}
+            _ => return,
+        }
+
+        let instance_buffer = self
+            .instance_buffer
+            .clone()
+            .expect("instances not written for this frame");
+        let instance_count = engine
+            .draw_list
+            .batches()
+            .iter()
+            .map(|batch| batch.instance_count as usize)
+            .sum();
+        // The camera is view 0, cascade `i` view `i + 1`
+        let frusta: Vec<Frustum> =
+            std::iter::once(Frustum::from_matrix(&(self.vp.projection * self.vp.view)))
+                .chain(self.shadow_map.light_vps.iter().map(Frustum::from_matrix))
+                .collect();
+
+        self.indirect.record_cull(
+            self.commands.as_mut().unwrap(),
+            &self.memory_allocator,
+            &self.descriptor_set_allocator,
+            engine.draw_list.batches(),
+            instance_buffer,
+            instance_count,
+            &frusta,
+        );
+    }
+
+    /// Draws everything culled by `cull_instances` into the G-buffer, one
+    /// multi-draw per material.
+    pub fn geometry_indirect(&mut self, engine: &Engine) {
+        match self.render_stage {
+            RenderStage::Geometry => {}
+            RenderStage::NeedsRedraw => {
+                self.recreate_swapchain();
+                self.render_stage = RenderStage::Stopped;
+                self.commands = None;
+                return;
+            }
+            _ => {
+                self.render_stage = RenderStage::Stopped;
+                self.commands = None;
+                return;
+            }
+        }
+
+        if !self.indirect.has_draws() {
+            return;
+        }
+
+        let commands = self.commands.as_mut().unwrap();
+        commands
+            .set_viewport(0, [self.viewport.clone()])
+            .bind_pipeline_graphics(self.deferred_pipeline.clone());
+
+        for (material_id, range) in self.indirect.material_ranges() {
+            let Some(material_data) = engine.assets.materials.get(*material_id) else {
+                continue;
+            };
+            let model_set = material_data
+                .read()
+                .unwrap()
+                .descriptor_set
+                .clone()
+                .expect("material descriptor set not built");
+
+            commands.bind_descriptor_sets(
+                PipelineBindPoint::Graphics,
+                self.deferred_pipeline.layout().clone(),
+                0,
+                (self.vp_set.clone(), model_set),
+            );
+            self.indirect.draw(commands, 0, range.clone());
+        }
+    }
+
+    pub fn geometry(
+        &mut self,
+        batch: &DrawBatch,
+        material: RwLockReadGuard<Material>,
+        mesh: RwLockReadGuard<Mesh>,
+    ) {
+        match self.render_stage {
+            RenderStage::Geometry => {}
+            RenderStage::NeedsRedraw => {
+                self.recreate_swapchain();
+                self.render_stage = RenderStage::Stopped;
+                self.commands = None;
+                return;
+            }
+            _ => {
+                self.render_stage = RenderStage::Stopped;
+                self.commands = None;
+                return;
+            }
+        }
+
+        let instance_buffer = self
+            .instance_buffer
+            .clone()
+            .expect("instances not written for this frame");
+        let model_set = material
+            .descriptor_set
+            .clone()
+            .expect("material descriptor set not built");
+        let (vertex_buffer, index_buffer) = mesh.unpack();
+        let lod = mesh.lod(batch.submesh, batch.lod);
+
         self.commands
             .as_mut()
             .unwrap()
@@ -997,28 +2086,56 @@ impl System {
                 PipelineBindPoint::Graphics,
                 self.deferred_pipeline.layout().clone(),
                 0,
-                (self.vp_set.clone(), model_set.clone()),
+                (self.vp_set.clone(), model_set),
+            )
+            .bind_vertex_buffers(0, (vertex_buffer, instance_buffer))
+            .bind_index_buffer(index_buffer)
+            .draw_indexed(
+                lod.index_count,
+                batch.instance_count,
+                lod.first_index,
+                0,
+                batch.first_instance,
             )
-            .bind_vertex_buffers(0, (vertex_buffer.clone(), instance_buffer.clone()))
-            .bind_index_buffer(index_buffer.clone())
-            .draw_indexed(index_buffer.len() as u32, instance_len as u32, 0, 0, 0)
             .unwrap();
     }
 
+    /// Ends the geometry pass, computes SSAO from the G-buffer and begins the
+    /// lighting pass.
     pub fn start_lighting(&mut self) {
         match self.render_stage {
             RenderStage::Geometry => {
                 self.render_stage = RenderStage::Lighting;
-                self.commands
-                    .as_mut()
-                    .unwrap()
-                    .next_subpass(SubpassContents::Inline)
-                    .unwrap();
             }
             _ => {
                 return;
             }
         }
+
+        let commands = self.commands.as_mut().unwrap();
+        commands.end_render_pass().unwrap();
+
+        self.ssao.record(
+            commands,
+            &self.memory_allocator,
+            &self.descriptor_set_allocator,
+            self.surface_buffer.clone(),
+            self.depth_buffer.clone(),
+            &self.vp.view,
+            &self.vp.jittered_projection,
+        );
+
+        let clear_values = vec![Some([0.0, 0.0, 0.0, 1.0].into()), None, None, None, None];
+
+        commands
+            .begin_render_pass(
+                RenderPassBeginInfo {
+                    clear_values,
+                    ..RenderPassBeginInfo::framebuffer(self.lighting_framebuffer.clone())
+                },
+                SubpassContents::Inline,
+            )
+            .unwrap();
     }
 
     #[allow(dead_code)]
@@ -1035,36 +2152,20 @@ impl System {
         .unwrap();
     }
 
+    /// View matrix of the current camera.
+    pub fn view(&self) -> &TMat4<f32> {
+        &self.vp.view
+    }
+
+    /// Unjittered projection of the current camera.
+    pub fn projection(&self) -> &TMat4<f32> {
+        &self.vp.projection
+    }
+
     pub fn set_view(&mut self, view: &TMat4<f32>) {
         self.vp.view = view.clone();
         let look = inverse(&view);
         self.vp.camera_pos = vec3(look[12], look[13], look[14]);
-        self.vp_buffer = CpuAccessibleBuffer::from_data(
-            &self.memory_allocator,
-            BufferUsage {
-                uniform_buffer: true,
-                ..BufferUsage::empty()
-            },
-            false,
-            deferred_vert::ty::VP_Data {
-                view: self.vp.view.into(),
-                projection: self.vp.projection.into(),
-            },
-        )
-        .unwrap();
-
-        let vp_layout = self
-            .deferred_pipeline
-            .layout()
-            .set_layouts()
-            .get(0)
-            .unwrap();
-        self.vp_set = PersistentDescriptorSet::new(
-            &self.descriptor_set_allocator,
-            vp_layout.clone(),
-            [WriteDescriptorSet::buffer(0, self.vp_buffer.clone())],
-        )
-        .unwrap();
 
         self.render_stage = RenderStage::Stopped;
     }
@@ -1072,7 +2173,7 @@ impl System {
     pub fn start(&mut self) {
         match self.render_stage {
             RenderStage::Stopped => {
-                self.render_stage = RenderStage::Geometry;
+                self.render_stage = RenderStage::Shadow;
             }
             RenderStage::NeedsRedraw => {
                 self.recreate_swapchain();
@@ -1087,90 +2188,185 @@ impl System {
             }
         }
 
-        let (image_index, suboptimal, acquire_future) =
-            match swapchain::acquire_next_image(self.swapchain.clone(), None) {
-                Ok(r) => r,
-                Err(AcquireError::OutOfDate) => {
+        let image_index = match &self.target {
+            Target::Window { swapchain, .. } => {
+                let (image_index, suboptimal, acquire_future) =
+                    match swapchain::acquire_next_image(swapchain.clone(), None) {
+                        Ok(r) => r,
+                        Err(AcquireError::OutOfDate) => {
+                            self.recreate_swapchain();
+                            return;
+                        }
+                        Err(err) => panic!("{:?}", err),
+                    };
+
+                if suboptimal {
                     self.recreate_swapchain();
                     return;
                 }
-                Err(err) => panic!("{:?}", err),
-            };
 
-        if suboptimal {
-            self.recreate_swapchain();
-            return;
-        }
+                if let Target::Window {
+                    acquire_future: target_future,
+                    ..
+                } = &mut self.target
+                {
+                    *target_future = Some(acquire_future);
+                }
 
-        let clear_values = vec![
-            Some([0.0, 0.0, 0.0, 1.0].into()),
-            Some([0.0, 0.0, 0.0, 1.0].into()),
-            Some([0.0, 0.0, 0.0, 1.0].into()),
-            Some([0.0, 0.0, 0.0, 1.0].into()),
-            Some(1.0.into()),
-        ];
+                image_index
+            }
+            Target::Offscreen { .. } => 0,
+        };
 
-        let mut commands = AutoCommandBufferBuilder::primary(
+        let commands = AutoCommandBufferBuilder::primary(
             &self.command_buffer_allocator,
             self.queue.queue_family_index(),
             CommandBufferUsage::OneTimeSubmit,
         )
         .unwrap();
 
+        self.commands = Some(commands);
+        self.image_index = image_index;
+        self.shadow_rendered = false;
+    }
+
+    /// Fits the shadow cascades around the current camera and returns each
+    /// one's light view projection, to cull shadow casters against.
+    pub fn update_cascades(&mut self, light: &DirectionalLight) -> [TMat4<f32>; CASCADE_COUNT] {
+        self.shadow_map
+            .update_cascades(light, &self.vp.view, &self.vp.projection);
+        self.shadow_map.light_vps
+    }
+
+    /// Renders the depth of each cascade's casters in `engine` from the
+    /// light's point of view. Must be called after `update_cascades` and
+    /// `write_instances`, before `start_geometry`, and after `cull_instances`
+    /// when GPU driven.
+    pub fn shadow(&mut self, engine: &Engine) {
+        match self.render_stage {
+            RenderStage::Shadow => {}
+            RenderStage::NeedsRedraw => {
+                self.recreate_swapchain();
+                self.commands = None;
+                self.render_stage = RenderStage::Stopped;
+                return;
+            }
+            _ => {
+                self.commands = None;
+                self.render_stage = RenderStage::Stopped;
+                return;
+            }
+        }
+
+        let instance_buffer = self
+            .instance_buffer
+            .clone()
+            .expect("instances not written for this frame");
+        let commands = self.commands.as_mut().unwrap();
+
+        for cascade in 0..CASCADE_COUNT {
+            self.shadow_map.begin(
+                commands,
+                &self.memory_allocator,
+                &self.descriptor_set_allocator,
+                cascade,
+            );
+            if self.gpu_driven {
+                for (material_id, range) in self.indirect.material_ranges() {
+                    self.shadow_map
+                        .bind_material(commands, shadow_set(engine, *material_id));
+                    self.indirect.draw(commands, cascade + 1, range.clone());
+                }
+            } else {
+                for batch in engine.shadow_draw_lists[cascade].batches() {
+                    if let Some(mesh_data) = engine.assets.meshes.get(batch.mesh_id) {
+                        let mesh = mesh_data.read().unwrap();
+                        let (vertex_buffer, index_buffer) = mesh.unpack();
+                        self.shadow_map
+                            .bind_material(commands, shadow_set(engine, batch.material_id));
+                        self.shadow_map.draw(
+                            commands,
+                            batch,
+                            mesh.lod(batch.submesh, batch.lod),
+                            vertex_buffer,
+                            index_buffer,
+                            instance_buffer.clone(),
+                        );
+                    }
+                }
+            }
+            self.shadow_map.end(commands);
+        }
+
+        self.shadow_rendered = true;
+    }
+
+    /// Begins the geometry render pass. If no shadow was rendered this frame
+    /// the cascades are cleared so nothing is shadowed.
+    pub fn start_geometry(&mut self) {
+        match self.render_stage {
+            RenderStage::Shadow => {
+                self.render_stage = RenderStage::Geometry;
+            }
+            RenderStage::NeedsRedraw => {
+                self.recreate_swapchain();
+                self.commands = None;
+                self.render_stage = RenderStage::Stopped;
+                return;
+            }
+            _ => {
+                self.commands = None;
+                self.render_stage = RenderStage::Stopped;
+                return;
+            }
+        }
+
+        self.update_vp();
+
+        let commands = self.commands.as_mut().unwrap();
+
+        if !self.shadow_rendered {
+            for cascade in 0..CASCADE_COUNT {
+                self.shadow_map.begin(
+                    commands,
+                    &self.memory_allocator,
+                    &self.descriptor_set_allocator,
+                    cascade,
+                );
+                self.shadow_map.end(commands);
+            }
+        }
+
+        // Depth stays 1.0 where no geometry is drawn
+        let clear_values = vec![
+            Some([0.0, 0.0, 0.0, 1.0].into()),
+            Some([0.0, 0.0, 0.0, 1.0].into()),
+            Some([0.0, 0.0, 0.0, 0.0].into()),
+            Some([0.0, 0.0, 0.0, 0.0].into()),
+            Some(1.0.into()),
+        ];
+
         commands
             .begin_render_pass(
                 RenderPassBeginInfo {
                     clear_values,
-                    ..RenderPassBeginInfo::framebuffer(
-                        self.framebuffers[image_index as usize].clone(),
-                    )
+                    ..RenderPassBeginInfo::framebuffer(self.geometry_framebuffer.clone())
                 },
                 SubpassContents::Inline,
             )
             .unwrap();
-
-        self.commands = Some(commands);
-        self.image_index = image_index;
-        self.acquire_future = Some(acquire_future);
     }
 
-    pub fn recreate_swapchain(&mut self) {
-        self.render_stage = RenderStage::NeedsRedraw;
-        self.commands = None;
-
-        let window = self
-            .surface
-            .object()
-            .unwrap()
-            .downcast_ref::<Window>()
-            .unwrap();
-        let image_extent: [u32; 2] = window.inner_size().into();
-
-        let aspect_ratio = image_extent[0] as f32 / image_extent[1] as f32;
-        self.vp.projection = perspective(aspect_ratio, half_pi(), 0.01, 300.0);
-
-        let (new_swapchain, new_images) = match self.swapchain.recreate(SwapchainCreateInfo {
-            image_extent,
-            ..self.swapchain.create_info()
-        }) {
-            Ok(r) => r,
-            Err(SwapchainCreationError::ImageExtentNotSupported { .. }) => return,
-            Err(e) => panic!("Failed to recreate swapchain: {:?}", e),
-        };
-
-        let (new_framebuffers, new_albedo_ao_buffer, new_surface_buffer, new_position_buffer) =
-            System::window_size_dependent_setup(
-                &self.memory_allocator,
-                &new_images,
-                self.render_pass.clone(),
-                &mut self.viewport,
-            );
+    /// Uploads this frame's camera matrices for the geometry pass, with the
+    /// projection jittered when TAA is on.
+    fn update_vp(&mut self) {
+        let jitter = self.anti_aliasing.next_jitter(self.target.extent());
+        let mut jittered_projection = self.vp.projection;
+        // Clip w is -z for this projection, so the offset is scaled by -z
+        jittered_projection[(0, 2)] -= jitter[0];
+        jittered_projection[(1, 2)] -= jitter[1];
 
-        self.swapchain = new_swapchain;
-        self.framebuffers = new_framebuffers;
-        self.albedo_ao_buffer = new_albedo_ao_buffer;
-        self.surface_buffer = new_surface_buffer;
-        self.position_buffer = new_position_buffer;
+        let view_projection = self.vp.projection * self.vp.view;
 
         self.vp_buffer = CpuAccessibleBuffer::from_data(
             &self.memory_allocator,
@@ -1181,7 +2377,9 @@ impl System {
             false,
             deferred_vert::ty::VP_Data {
                 view: self.vp.view.into(),
-                projection: self.vp.projection.into(),
+                projection: jittered_projection.into(),
+                view_projection: view_projection.into(),
+                previous_view_projection: self.vp.previous_view_projection.into(),
             },
         )
         .unwrap();
@@ -1199,84 +2397,308 @@ impl System {
         )
         .unwrap();
 
+        self.vp.jittered_projection = jittered_projection;
+        self.vp.previous_view_projection = view_projection;
+    }
+
+    /// Maps depth buffer NDC back to world space for the lighting shaders.
+    fn inverse_view_projection(&self) -> TMat4<f32> {
+        inverse(&(self.vp.jittered_projection * self.vp.view))
+    }
+
+    fn screen_size(&self) -> [f32; 4] {
+        [
+            self.viewport.dimensions[0],
+            self.viewport.dimensions[1],
+            0.0,
+            0.0,
+        ]
+    }
+
+    pub fn shadow_settings(&self) -> &ShadowSettings {
+        &self.shadow_map.settings
+    }
+
+    pub fn set_shadow_settings(&mut self, settings: ShadowSettings) {
+        self.shadow_map
+            .set_settings(&self.memory_allocator, settings);
+    }
+
+    pub fn tonemap_settings(&self) -> &TonemapSettings {
+        &self.tonemapper.settings
+    }
+
+    pub fn set_tonemap_settings(&mut self, settings: TonemapSettings) {
+        self.tonemapper.settings = settings;
+    }
+
+    pub fn ssao_settings(&self) -> &SsaoSettings {
+        &self.ssao.settings
+    }
+
+    pub fn set_ssao_settings(&mut self, settings: SsaoSettings) {
+        self.ssao.settings = settings;
+    }
+
+    pub fn anti_aliasing_settings(&self) -> &AntiAliasingSettings {
+        &self.anti_aliasing.settings
+    }
+
+    pub fn set_anti_aliasing_settings(&mut self, settings: AntiAliasingSettings) {
+        self.anti_aliasing.settings = settings;
+    }
+
+    /// Whether culling and draw submission happen on the GPU.
+    pub fn gpu_driven(&self) -> bool {
+        self.gpu_driven
+    }
+
+    /// Switches GPU driven drawing on or off. Stays off on devices that can't
+    /// offset the first instance of an indirect draw.
+    pub fn set_gpu_driven(&mut self, enabled: bool) {
+        let enabled = enabled && self.device.enabled_features().draw_indirect_first_instance;
+        if enabled != self.gpu_driven {
+            self.gpu_driven = enabled;
+            self.mesh_storage_changed = true;
+        }
+    }
+
+    pub fn bloom_settings(&self) -> &BloomSettings {
+        &self.bloom.settings
+    }
+
+    pub fn set_bloom_settings(&mut self, settings: BloomSettings) {
+        self.bloom.settings = settings;
+    }
+
+    pub fn recreate_swapchain(&mut self) {
+        self.render_stage = RenderStage::NeedsRedraw;
+        self.commands = None;
+
+        // An offscreen image never goes out of date
+        let (surface, swapchain) = match &self.target {
+            Target::Window {
+                surface, swapchain, ..
+            } => (surface.clone(), swapchain.clone()),
+            Target::Offscreen { .. } => {
+                self.render_stage = RenderStage::Stopped;
+                return;
+            }
+        };
+
+        let window = surface.object().unwrap().downcast_ref::<Window>().unwrap();
+        let image_extent: [u32; 2] = window.inner_size().into();
+
+        let aspect_ratio = image_extent[0] as f32 / image_extent[1] as f32;
+        self.vp.projection = perspective(aspect_ratio, half_pi(), 0.01, 300.0);
+
+        let (new_swapchain, new_images) = match swapchain.recreate(SwapchainCreateInfo {
+            image_extent,
+            ..swapchain.create_info()
+        }) {
+            Ok(r) => r,
+            Err(SwapchainCreationError::ImageExtentNotSupported { .. }) => return,
+            Err(e) => panic!("Failed to recreate swapchain: {:?}", e),
+        };
+
+        self.target = Target::Window {
+            surface,
+            swapchain: new_swapchain,
+            images: new_images,
+            acquire_future: None,
+        };
+
+        let (
+            new_geometry_framebuffer,
+            new_lighting_framebuffer,
+            new_hdr_buffer,
+            new_albedo_ao_buffer,
+            new_surface_buffer,
+            new_depth_buffer,
+            new_velocity_buffer,
+            new_emissive_buffer,
+        ) = System::window_size_dependent_setup(
+            &self.memory_allocator,
+            self.target.extent(),
+            self.geometry_render_pass.clone(),
+            self.lighting_render_pass.clone(),
+            &mut self.viewport,
+        );
+
+        self.geometry_framebuffer = new_geometry_framebuffer;
+        self.lighting_framebuffer = new_lighting_framebuffer;
+        self.hdr_buffer = new_hdr_buffer;
+        self.tonemapper.set_target(self.target.final_views());
+        self.bloom
+            .resize(&self.memory_allocator, self.target.extent());
+        self.ssao
+            .resize(&self.memory_allocator, self.target.extent());
+        self.albedo_ao_buffer = new_albedo_ao_buffer;
+        self.surface_buffer = new_surface_buffer;
+        self.depth_buffer = new_depth_buffer;
+        self.velocity_buffer = new_velocity_buffer;
+        self.emissive_buffer = new_emissive_buffer;
+        self.anti_aliasing
+            .resize(&self.memory_allocator, self.target.extent());
+
         self.render_stage = RenderStage::Stopped;
     }
 
     fn window_size_dependent_setup(
         allocator: &StandardMemoryAllocator,
-        images: &[Arc<SwapchainImage>],
-        render_pass: Arc<RenderPass>,
+        dimensions: [u32; 2],
+        geometry_render_pass: Arc<RenderPass>,
+        lighting_render_pass: Arc<RenderPass>,
         viewport: &mut Viewport,
     ) -> (
-        Vec<Arc<Framebuffer>>,
+        Arc<Framebuffer>,
+        Arc<Framebuffer>,
+        Arc<ImageView<AttachmentImage>>,
+        Arc<ImageView<AttachmentImage>>,
+        Arc<ImageView<AttachmentImage>>,
         Arc<ImageView<AttachmentImage>>,
         Arc<ImageView<AttachmentImage>>,
         Arc<ImageView<AttachmentImage>>,
     ) {
-        let dimensions = images[0].dimensions().width_height();
         viewport.dimensions = [dimensions[0] as f32, dimensions[1] as f32];
 
+        // Read by lighting to reconstruct positions and sampled by SSAO
         let depth_buffer = ImageView::new_default(
-            AttachmentImage::transient(allocator, dimensions, Format::D16_UNORM).unwrap(),
+            AttachmentImage::with_usage(
+                allocator,
+                dimensions,
+                DEPTH_FORMAT,
+                ImageUsage {
+                    depth_stencil_attachment: true,
+                    input_attachment: true,
+                    sampled: true,
+                    transfer_src: true,
+                    ..ImageUsage::empty()
+                },
+            )
+            .unwrap(),
+        )
+        .unwrap();
+
+        // G-buffer attachments are kept after the render pass so they can be
+        // captured, and sampled by SSAO
+        let g_buffer_usage = ImageUsage {
+            color_attachment: true,
+            input_attachment: true,
+            sampled: true,
+            transfer_src: true,
+            ..ImageUsage::empty()
+        };
+
+        // Sampled by tonemapping after the render pass
+        let hdr_buffer = ImageView::new_default(
+            AttachmentImage::with_usage(
+                allocator,
+                dimensions,
+                HDR_FORMAT,
+                ImageUsage {
+                    color_attachment: true,
+                    sampled: true,
+                    transfer_src: true,
+                    ..ImageUsage::empty()
+                },
+            )
+            .unwrap(),
         )
         .unwrap();
 
         let albedo_ao_buffer = ImageView::new_default(
-            AttachmentImage::transient_input_attachment(
+            AttachmentImage::with_usage(
                 allocator,
                 dimensions,
                 Format::R8G8B8A8_SRGB,
+                g_buffer_usage,
             )
             .unwrap(),
         )
         .unwrap();
 
         let surface_buffer = ImageView::new_default(
-            AttachmentImage::transient_input_attachment(
+            AttachmentImage::with_usage(
                 allocator,
                 dimensions,
                 Format::R16G16B16A16_SFLOAT,
+                g_buffer_usage,
             )
             .unwrap(),
         )
         .unwrap();
 
-        let position_buffer = ImageView::new_default(
-            AttachmentImage::transient_input_attachment(
+        // Sampled by TAA to find where each pixel was last frame
+        let velocity_buffer = ImageView::new_default(
+            AttachmentImage::with_usage(
                 allocator,
                 dimensions,
-                Format::R16G16B16A16_SFLOAT,
+                VELOCITY_FORMAT,
+                ImageUsage {
+                    color_attachment: true,
+                    sampled: true,
+                    ..ImageUsage::empty()
+                },
             )
             .unwrap(),
         )
         .unwrap();
 
-        let framebuffers = images
-            .iter()
-            .map(|image| {
-                let view = ImageView::new_default(image.clone()).unwrap();
-                Framebuffer::new(
-                    render_pass.clone(),
-                    FramebufferCreateInfo {
-                        attachments: vec![
-                            view,
-                            albedo_ao_buffer.clone(),
-                            surface_buffer.clone(),
-                            position_buffer.clone(),
-                            depth_buffer.clone(),
-                        ],
-                        ..Default::default()
-                    },
-                )
-                .unwrap()
-            })
-            .collect::<Vec<_>>();
+        // Light given off by materials, added unlit by the ambient pass
+        let emissive_buffer = ImageView::new_default(
+            AttachmentImage::with_usage(allocator, dimensions, HDR_FORMAT, g_buffer_usage).unwrap(),
+        )
+        .unwrap();
+
+        let geometry_framebuffer = Framebuffer::new(
+            geometry_render_pass,
+            FramebufferCreateInfo {
+                attachments: vec![
+                    albedo_ao_buffer.clone(),
+                    surface_buffer.clone(),
+                    velocity_buffer.clone(),
+                    emissive_buffer.clone(),
+                    depth_buffer.clone(),
+                ],
+                ..Default::default()
+            },
+        )
+        .unwrap();
+
+        let lighting_framebuffer = Framebuffer::new(
+            lighting_render_pass,
+            FramebufferCreateInfo {
+                attachments: vec![
+                    hdr_buffer.clone(),
+                    albedo_ao_buffer.clone(),
+                    surface_buffer.clone(),
+                    depth_buffer.clone(),
+                    emissive_buffer.clone(),
+                ],
+                ..Default::default()
+            },
+        )
+        .unwrap();
 
         (
-            framebuffers,
+            geometry_framebuffer,
+            lighting_framebuffer,
+            hdr_buffer,
             albedo_ao_buffer.clone(),
             surface_buffer.clone(),
-            position_buffer.clone(),
+            depth_buffer,
+            velocity_buffer,
+            emissive_buffer,
         )
     }
 }
+
+/// The shadow pass set of a cutout material, `None` for everything else.
+fn shadow_set(engine: &Engine, material_id: usize) -> Option<Arc<PersistentDescriptorSet>> {
+    engine
+        .assets
+        .materials
+        .get(material_id)
+        .and_then(|material| material.read().unwrap().shadow_set.clone())
+}
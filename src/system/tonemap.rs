@@ -0,0 +1,419 @@
+use std::sync::Arc;
+use std::time::Instant;
+
+use vulkano::buffer::{BufferUsage, CpuAccessibleBuffer};
+use vulkano::command_buffer::{AutoCommandBufferBuilder, PrimaryAutoCommandBuffer};
+use vulkano::descriptor_set::allocator::StandardDescriptorSetAllocator;
+use vulkano::descriptor_set::{PersistentDescriptorSet, WriteDescriptorSet};
+use vulkano::device::Device;
+use vulkano::format::Format;
+use vulkano::image::view::{ImageView, ImageViewAbstract};
+use vulkano::image::{AttachmentImage, ImageAccess};
+use vulkano::memory::allocator::StandardMemoryAllocator;
+use vulkano::pipeline::{ComputePipeline, GraphicsPipeline, Pipeline, PipelineBindPoint};
+use vulkano::render_pass::{Framebuffer, FramebufferCreateInfo, RenderPass, Subpass};
+use vulkano::sampler::{Filter, Sampler, SamplerAddressMode, SamplerCreateInfo};
+
+use crate::system::fullscreen::FullscreenPass;
+
+mod tonemap_frag {
+    vulkano_shaders::shader! {
+        ty: "fragment",
+        path: "src/system/shaders/tonemap.frag",
+        types_meta: {
+            use bytemuck::{Pod, Zeroable};
+
+            #[derive(Clone, Copy, Zeroable, Pod)]
+        },
+    }
+}
+
+mod histogram_comp {
+    vulkano_shaders::shader! {
+        ty: "compute",
+        path: "src/system/shaders/luminance_histogram.comp",
+        types_meta: {
+            use bytemuck::{Pod, Zeroable};
+
+            #[derive(Clone, Copy, Zeroable, Pod)]
+        },
+    }
+}
+
+mod average_comp {
+    vulkano_shaders::shader! {
+        ty: "compute",
+        path: "src/system/shaders/luminance_average.comp",
+        types_meta: {
+            use bytemuck::{Pod, Zeroable};
+
+            #[derive(Clone, Copy, Zeroable, Pod)]
+        },
+    }
+}
+
+/// Format the lighting subpass renders into before tonemapping.
+pub const HDR_FORMAT: Format = Format::R16G16B16A16_SFLOAT;
+
+const HISTOGRAM_BINS: usize = 256;
+// Matches the local size of `luminance_histogram.comp`
+const HISTOGRAM_GROUP_SIZE: u32 = 16;
+
+/// Curve used to map HDR colors into the displayable range.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TonemapOperator {
+    Reinhard,
+    Aces,
+    Agx,
+}
+
+impl TonemapOperator {
+    pub fn next(self) -> TonemapOperator {
+        match self {
+            TonemapOperator::Reinhard => TonemapOperator::Aces,
+            TonemapOperator::Aces => TonemapOperator::Agx,
+            TonemapOperator::Agx => TonemapOperator::Reinhard,
+        }
+    }
+
+    // Index understood by `tonemap.frag`
+    fn shader_index(self) -> f32 {
+        match self {
+            TonemapOperator::Reinhard => 0.0,
+            TonemapOperator::Aces => 1.0,
+            TonemapOperator::Agx => 2.0,
+        }
+    }
+}
+
+/// Tunables of the tonemapping pass.
+#[derive(Debug, Clone)]
+pub struct TonemapSettings {
+    pub operator: TonemapOperator,
+    /// Exposure compensation in stops, applied on top of auto exposure
+    pub exposure: f32,
+    /// Adapts exposure to the average scene luminance over time
+    pub auto_exposure: bool,
+    /// Darkest log2 luminance the histogram tells apart
+    pub min_log_luminance: f32,
+    /// Brightest log2 luminance the histogram tells apart
+    pub max_log_luminance: f32,
+    /// How quickly auto exposure follows changes, higher is faster
+    pub adaptation_rate: f32,
+}
+
+impl Default for TonemapSettings {
+    fn default() -> Self {
+        Self {
+            operator: TonemapOperator::Aces,
+            exposure: 0.0,
+            auto_exposure: false,
+            min_log_luminance: -8.0,
+            max_log_luminance: 4.0,
+            adaptation_rate: 1.5,
+        }
+    }
+}
+
+/// Maps the HDR output of the lighting subpass onto the final target,
+/// measuring the scene's average luminance first when auto exposure is on.
+pub struct Tonemapper {
+    pub settings: TonemapSettings,
+    render_pass: Arc<RenderPass>,
+    pipeline: Arc<GraphicsPipeline>,
+    histogram_pipeline: Arc<ComputePipeline>,
+    average_pipeline: Arc<ComputePipeline>,
+    framebuffers: Vec<Arc<Framebuffer>>,
+    histogram: Arc<CpuAccessibleBuffer<[u32]>>,
+    exposure: Arc<CpuAccessibleBuffer<[f32]>>,
+    sampler: Arc<Sampler>,
+    fullscreen: FullscreenPass,
+    last_frame: Option<Instant>,
+}
+
+impl Tonemapper {
+    pub fn new(
+        device: Arc<Device>,
+        allocator: &StandardMemoryAllocator,
+        final_format: Format,
+        final_views: Vec<Arc<dyn ImageViewAbstract>>,
+        settings: TonemapSettings,
+    ) -> Tonemapper {
+        let fullscreen = FullscreenPass::new(device.clone(), allocator);
+        let tonemap_frag = tonemap_frag::load(device.clone()).unwrap();
+        let histogram_comp = histogram_comp::load(device.clone()).unwrap();
+        let average_comp = average_comp::load(device.clone()).unwrap();
+
+        let render_pass = vulkano::single_pass_renderpass!(device.clone(),
+            attachments: {
+                final_color: {
+                    load: DontCare,
+                    store: Store,
+                    format: final_format,
+                    samples: 1,
+                }
+            },
+            pass: {
+                color: [final_color],
+                depth_stencil: {}
+            }
+        )
+        .unwrap();
+
+        let pipeline = fullscreen.pipeline(
+            device.clone(),
+            &tonemap_frag,
+            Subpass::from(render_pass.clone(), 0).unwrap(),
+            None,
+        );
+
+        let histogram_pipeline = ComputePipeline::new(
+            device.clone(),
+            histogram_comp.entry_point("main").unwrap(),
+            &(),
+            None,
+            |_| {},
+        )
+        .unwrap();
+
+        let average_pipeline = ComputePipeline::new(
+            device.clone(),
+            average_comp.entry_point("main").unwrap(),
+            &(),
+            None,
+            |_| {},
+        )
+        .unwrap();
+
+        let storage_usage = BufferUsage {
+            storage_buffer: true,
+            ..BufferUsage::empty()
+        };
+        let histogram =
+            CpuAccessibleBuffer::from_iter(allocator, storage_usage, false, [0u32; HISTOGRAM_BINS])
+                .unwrap();
+        // Starts out at middle grey so the first frames are not over exposed
+        let exposure =
+            CpuAccessibleBuffer::from_iter(allocator, storage_usage, false, [0.18f32]).unwrap();
+
+        // Linear so the half resolution bloom is upsampled smoothly
+        let sampler = Sampler::new(
+            device.clone(),
+            SamplerCreateInfo {
+                mag_filter: Filter::Linear,
+                min_filter: Filter::Linear,
+                address_mode: [SamplerAddressMode::ClampToEdge; 3],
+                ..Default::default()
+            },
+        )
+        .unwrap();
+
+        let framebuffers = Tonemapper::create_framebuffers(render_pass.clone(), final_views);
+
+        Tonemapper {
+            settings,
+            render_pass,
+            pipeline,
+            histogram_pipeline,
+            average_pipeline,
+            framebuffers,
+            histogram,
+            exposure,
+            sampler,
+            fullscreen,
+            last_frame: None,
+        }
+    }
+
+    /// Recreates the framebuffers after the final images changed.
+    pub fn set_target(&mut self, final_views: Vec<Arc<dyn ImageViewAbstract>>) {
+        self.framebuffers = Tonemapper::create_framebuffers(self.render_pass.clone(), final_views);
+    }
+
+    /// Records auto exposure, if enabled, and the tonemapping of `hdr_color`
+    /// into final image `image_index`. `bloom` is added with the given
+    /// intensity first. Must be recorded outside a render pass.
+    pub fn record(
+        &mut self,
+        commands: &mut AutoCommandBufferBuilder<PrimaryAutoCommandBuffer>,
+        allocator: &StandardMemoryAllocator,
+        descriptor_set_allocator: &StandardDescriptorSetAllocator,
+        hdr_color: Arc<ImageView<AttachmentImage>>,
+        bloom: Option<(Arc<ImageView<AttachmentImage>>, f32)>,
+        image_index: u32,
+    ) {
+        let now = Instant::now();
+        let delta = self
+            .last_frame
+            .map_or(0.0, |last_frame| (now - last_frame).as_secs_f32());
+        self.last_frame = Some(now);
+
+        let [width, height] = hdr_color.image().dimensions().width_height();
+
+        if self.settings.auto_exposure {
+            self.record_auto_exposure(
+                commands,
+                allocator,
+                descriptor_set_allocator,
+                hdr_color.clone(),
+                [width, height],
+                delta,
+            );
+        }
+
+        // Without bloom the HDR image is bound in its place and never read
+        let (bloom_color, bloom_intensity) = bloom.unwrap_or((hdr_color.clone(), 0.0));
+
+        let tonemap_buffer = CpuAccessibleBuffer::from_data(
+            allocator,
+            BufferUsage {
+                uniform_buffer: true,
+                ..BufferUsage::empty()
+            },
+            false,
+            tonemap_frag::ty::Tonemap_Data {
+                params: [
+                    self.settings.operator.shader_index(),
+                    self.settings.exposure.exp2(),
+                    self.settings.auto_exposure as u32 as f32,
+                    bloom_intensity,
+                ],
+            },
+        )
+        .unwrap();
+
+        let tonemap_layout = self.pipeline.layout().set_layouts().get(0).unwrap();
+        let tonemap_set = PersistentDescriptorSet::new(
+            descriptor_set_allocator,
+            tonemap_layout.clone(),
+            [
+                WriteDescriptorSet::image_view_sampler(0, hdr_color, self.sampler.clone()),
+                WriteDescriptorSet::buffer(1, self.exposure.clone()),
+                WriteDescriptorSet::buffer(2, tonemap_buffer),
+                WriteDescriptorSet::image_view_sampler(3, bloom_color, self.sampler.clone()),
+            ],
+        )
+        .unwrap();
+
+        self.fullscreen.draw(
+            commands,
+            self.framebuffers[image_index as usize].clone(),
+            &self.pipeline,
+            Some(tonemap_set),
+        );
+    }
+
+    /// Builds a log luminance histogram of `hdr_color` and moves the average
+    /// luminance read by the tonemapping pass towards its mean.
+    fn record_auto_exposure(
+        &self,
+        commands: &mut AutoCommandBufferBuilder<PrimaryAutoCommandBuffer>,
+        allocator: &StandardMemoryAllocator,
+        descriptor_set_allocator: &StandardDescriptorSetAllocator,
+        hdr_color: Arc<ImageView<AttachmentImage>>,
+        extent: [u32; 2],
+        delta: f32,
+    ) {
+        let uniform_usage = BufferUsage {
+            uniform_buffer: true,
+            ..BufferUsage::empty()
+        };
+        let log_range = self.settings.max_log_luminance - self.settings.min_log_luminance;
+
+        let histogram_buffer = CpuAccessibleBuffer::from_data(
+            allocator,
+            uniform_usage,
+            false,
+            histogram_comp::ty::Histogram_Data {
+                min_log_luminance: self.settings.min_log_luminance,
+                inverse_log_range: 1.0 / log_range,
+            },
+        )
+        .unwrap();
+
+        let histogram_layout = self
+            .histogram_pipeline
+            .layout()
+            .set_layouts()
+            .get(0)
+            .unwrap();
+        let histogram_set = PersistentDescriptorSet::new(
+            descriptor_set_allocator,
+            histogram_layout.clone(),
+            [
+                WriteDescriptorSet::image_view_sampler(0, hdr_color, self.sampler.clone()),
+                WriteDescriptorSet::buffer(1, self.histogram.clone()),
+                WriteDescriptorSet::buffer(2, histogram_buffer),
+            ],
+        )
+        .unwrap();
+
+        let average_buffer = CpuAccessibleBuffer::from_data(
+            allocator,
+            uniform_usage,
+            false,
+            average_comp::ty::Average_Data {
+                min_log_luminance: self.settings.min_log_luminance,
+                log_range,
+                time_coefficient: 1.0 - (-delta * self.settings.adaptation_rate).exp(),
+                pixel_count: (extent[0] * extent[1]) as f32,
+            },
+        )
+        .unwrap();
+
+        let average_layout = self.average_pipeline.layout().set_layouts().get(0).unwrap();
+        let average_set = PersistentDescriptorSet::new(
+            descriptor_set_allocator,
+            average_layout.clone(),
+            [
+                WriteDescriptorSet::buffer(0, self.histogram.clone()),
+                WriteDescriptorSet::buffer(1, self.exposure.clone()),
+                WriteDescriptorSet::buffer(2, average_buffer),
+            ],
+        )
+        .unwrap();
+
+        commands
+            .bind_pipeline_compute(self.histogram_pipeline.clone())
+            .bind_descriptor_sets(
+                PipelineBindPoint::Compute,
+                self.histogram_pipeline.layout().clone(),
+                0,
+                histogram_set,
+            )
+            .dispatch([
+                extent[0].div_ceil(HISTOGRAM_GROUP_SIZE),
+                extent[1].div_ceil(HISTOGRAM_GROUP_SIZE),
+                1,
+            ])
+            .unwrap()
+            .bind_pipeline_compute(self.average_pipeline.clone())
+            .bind_descriptor_sets(
+                PipelineBindPoint::Compute,
+                self.average_pipeline.layout().clone(),
+                0,
+                average_set,
+            )
+            .dispatch([1, 1, 1])
+            .unwrap();
+    }
+
+    fn create_framebuffers(
+        render_pass: Arc<RenderPass>,
+        final_views: Vec<Arc<dyn ImageViewAbstract>>,
+    ) -> Vec<Arc<Framebuffer>> {
+        final_views
+            .into_iter()
+            .map(|view| {
+                Framebuffer::new(
+                    render_pass.clone(),
+                    FramebufferCreateInfo {
+                        attachments: vec![view],
+                        ..Default::default()
+                    },
+                )
+                .unwrap()
+            })
+            .collect()
+    }
+}
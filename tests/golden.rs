@@ -0,0 +1,20 @@
+//! Renders the scenes in `src/golden.rs` headlessly and compares them with the
+//! references in `assets/golden`. Needs a Vulkan device, lavapipe will do, so
+//! it only runs with `cargo test -- --ignored`.
+
+use std::process::Command;
+
+#[test]
+#[ignore = "needs a Vulkan device"]
+fn golden_images_match() {
+    let status = Command::new(env!("CARGO_BIN_EXE_rust-game"))
+        .arg("--golden")
+        .current_dir(env!("CARGO_MANIFEST_DIR"))
+        .status()
+        .expect("Failed to run the renderer");
+
+    assert!(
+        status.success(),
+        "Golden images differ, renders and diffs are in target/golden"
+    );
+}